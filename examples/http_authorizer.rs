@@ -0,0 +1,96 @@
+//! Runs notiflux with an authorizer that asks a policy service on localhost instead of checking
+//! JWTs. Every subscribe, broadcast, presence query and direct message is POSTed to
+//! `POLICY_URL` as
+//!
+//! ```json
+//! {"credential": {"token": "..."}, "operation": "subscribe", "topic": "build:123"}
+//! ```
+//!
+//! and the service answers with `{"allow": true, "sub": "alice", "presence": false}` or
+//! `{"allow": false, "reason": "..."}`. Run it with the usual env vars, such as
+//! `JWT_PUBLIC_KEY_B64`, and `cargo run --example http_authorizer`.
+use actix_web::{middleware::Logger, App, HttpServer};
+use futures_util::future::BoxFuture;
+use notiflux::{
+    Authorizer, Config, Credential, Decision, Grant, NotifluxBuilder, NotifluxError, Operation,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+
+const DEFAULT_POLICY_URL: &str = "http://127.0.0.1:8181/authorize";
+
+struct HttpAuthorizer {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct PolicyResponse {
+    allow: bool,
+    reason: Option<String>,
+    #[serde(default)]
+    sub: String,
+    #[serde(default)]
+    presence: bool,
+}
+
+impl Authorizer for HttpAuthorizer {
+    fn authorize(
+        &self,
+        credential: &Credential,
+        operation: Operation,
+        topic: &str,
+    ) -> BoxFuture<'static, Decision> {
+        let credential = match credential {
+            Credential::Token(token) => json!({ "token": token }),
+            Credential::Certificate(names) => json!({ "certificate": names }),
+        };
+        let request = self.client.post(&self.url).json(&json!({
+            "credential": credential,
+            "operation": operation,
+            "topic": topic,
+        }));
+
+        Box::pin(async move {
+            let response = match request.send().await.and_then(|r| r.error_for_status()) {
+                Ok(response) => response,
+                Err(e) => return Decision::Deny(format!("Policy service unavailable: {}", e)),
+            };
+            match response.json::<PolicyResponse>().await {
+                Ok(policy) if policy.allow => Decision::Allow(Grant {
+                    sub: policy.sub,
+                    presence: policy.presence,
+                }),
+                Ok(policy) => Decision::Deny(policy.reason.unwrap_or_else(|| "Denied".to_owned())),
+                Err(e) => Decision::Deny(format!("Invalid policy response: {}", e)),
+            }
+        })
+    }
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), NotifluxError> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
+    let config = Config::load(None, HashMap::new())?;
+    let bind_tuple = (config.host.clone(), config.port);
+    let authorizer = HttpAuthorizer {
+        url: std::env::var("POLICY_URL").unwrap_or_else(|_| DEFAULT_POLICY_URL.to_owned()),
+        client: reqwest::Client::new(),
+    };
+    let notiflux = NotifluxBuilder::new(config)
+        .authorizer(authorizer)
+        .build()?;
+
+    HttpServer::new(move || {
+        App::new()
+            .service(notiflux.scope(""))
+            .wrap(Logger::default())
+    })
+    .bind(bind_tuple)?
+    .run()
+    .await?;
+
+    Ok(())
+}
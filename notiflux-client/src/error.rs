@@ -0,0 +1,45 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request couldn't be sent, or the response couldn't be read
+    Http(reqwest::Error),
+    /// The server responded with an error, such as a 403 when the token doesn't allow it
+    Rejected { status: u16, message: String },
+    /// The token couldn't be signed, usually because of an invalid private key
+    Token(jsonwebtoken::errors::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "Request failed: {}", e),
+            Error::Rejected { status, message } => {
+                write!(f, "Rejected with {}: {}", status, message)
+            }
+            Error::Token(e) => write!(f, "Unable to sign token: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            Error::Rejected { .. } => None,
+            Error::Token(e) => Some(e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::Http(error)
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for Error {
+    fn from(error: jsonwebtoken::errors::Error) -> Self {
+        Error::Token(error)
+    }
+}
@@ -0,0 +1,52 @@
+//! Client for [notiflux](https://github.com/ikornaselur/notiflux), with a [`Subscriber`] that
+//! stays subscribed across reconnects, a [`Publisher`] for broadcasting and a [`Token`] helper
+//! for minting tokens.
+mod error;
+mod publisher;
+mod subscriber;
+mod token;
+
+pub use error::Error;
+pub use publisher::Publisher;
+pub use subscriber::{Event, Subscriber, Subscription};
+pub use token::{Scope, Token};
+
+/// An HTTP API URL, for a base URL given as either `http(s)://` or `ws(s)://`
+fn http_url(base: &str, path: &str) -> String {
+    let base = base.trim_end_matches('/');
+    match base.strip_prefix("ws") {
+        Some(rest) => format!("http{}{}", rest, path),
+        None => format!("{}{}", base, path),
+    }
+}
+
+/// A WebSocket URL, for a base URL given as either `http(s)://` or `ws(s)://`
+fn ws_url(base: &str, path: &str) -> String {
+    let base = base.trim_end_matches('/');
+    match base.strip_prefix("http") {
+        Some(rest) => format!("ws{}{}", rest, path),
+        None => format!("{}{}", base, path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_urls() {
+        assert_eq!(
+            http_url("ws://localhost:8080/", "/broadcast"),
+            "http://localhost:8080/broadcast"
+        );
+        assert_eq!(
+            http_url("https://notiflux.example.com", "/broadcast"),
+            "https://notiflux.example.com/broadcast"
+        );
+        assert_eq!(
+            ws_url("https://notiflux.example.com/", "/ws"),
+            "wss://notiflux.example.com/ws"
+        );
+        assert_eq!(ws_url("ws://localhost", "/ws"), "ws://localhost/ws");
+    }
+}
@@ -0,0 +1,137 @@
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{http_url, Error};
+
+/// What can't appear as is in a path segment, topics being able to hold anything else
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// The path of a route taking the topic as its last segment
+fn topic_path(route: &str, topic: &str) -> String {
+    format!("{}/{}", route, utf8_percent_encode(topic, PATH_SEGMENT))
+}
+
+/// Broadcasts messages through the HTTP API, with a token that has the broadcast scope
+#[derive(Clone)]
+pub struct Publisher {
+    url: String,
+    token: String,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct BroadcastPayload<'a> {
+    topic: &'a str,
+    message: &'a str,
+    token: &'a str,
+    retain: bool,
+    retain_ttl: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl Publisher {
+    pub fn new(url: &str, token: &str) -> Publisher {
+        Publisher {
+            url: url.to_owned(),
+            token: token.to_owned(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn publish(&self, topic: &str, message: &str) -> Result<(), Error> {
+        self.broadcast(topic, message, false, None).await
+    }
+
+    /// Publish a binary message, delivered to the subscribers that asked for binary messages
+    pub async fn publish_binary(&self, topic: &str, message: &[u8]) -> Result<(), Error> {
+        let response = self
+            .client
+            .post(http_url(&self.url, &topic_path("/broadcast", topic)))
+            .bearer_auth(&self.token)
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .body(message.to_vec())
+            .send()
+            .await?;
+
+        check(response).await
+    }
+
+    /// Publish a message that is also kept as the last value of the topic, for the given time or
+    /// until it's replaced
+    pub async fn publish_retained(
+        &self,
+        topic: &str,
+        message: &str,
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        self.broadcast(topic, message, true, ttl).await
+    }
+
+    pub async fn clear_retained(&self, topic: &str) -> Result<(), Error> {
+        let response = self
+            .client
+            .delete(http_url(&self.url, &topic_path("/retained", topic)))
+            .bearer_auth(&self.token)
+            .send()
+            .await?;
+
+        check(response).await
+    }
+
+    async fn broadcast(
+        &self,
+        topic: &str,
+        message: &str,
+        retain: bool,
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        let response = self
+            .client
+            .post(http_url(&self.url, "/broadcast"))
+            .json(&BroadcastPayload {
+                topic,
+                message,
+                token: &self.token,
+                retain,
+                retain_ttl: ttl.map(|ttl| ttl.as_secs()),
+            })
+            .send()
+            .await?;
+
+        check(response).await
+    }
+}
+
+/// Turn an error response into an [`Error::Rejected`] with the message the server gave
+async fn check(response: reqwest::Response) -> Result<(), Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    let message = match serde_json::from_str::<ErrorResponse>(&body) {
+        Ok(error) => error.error,
+        Err(_) => body,
+    };
+    Err(Error::Rejected {
+        status: status.as_u16(),
+        message,
+    })
+}
@@ -0,0 +1,382 @@
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+
+use crate::ws_url;
+
+/// How often to ping, until the server says otherwise, matching the default `HEARTBEAT_INTERVAL`
+/// of the server
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// How long without hearing from the server before the connection is considered dead, matching
+/// the default `CLIENT_TIMEOUT` of the server
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+const HEARTBEAT_INTERVAL_HEADER: &str = "x-notiflux-heartbeat-interval";
+const CLIENT_TIMEOUT_HEADER: &str = "x-notiflux-client-timeout";
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+type Stream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Why a connection dropped, with the delay the server suggested before reconnecting when it
+/// shut down
+struct Dropped {
+    error: String,
+    retry_in: Option<Duration>,
+}
+
+impl From<String> for Dropped {
+    fn from(error: String) -> Self {
+        Dropped {
+            error,
+            retry_in: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GoingAway {
+    reconnect_after_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Connected and subscribed to the topics, sent after every reconnect as well
+    Connected,
+    /// The connection was lost, and another attempt is made after `retry_in`
+    Disconnected { error: String, retry_in: Duration },
+    /// A message delivered to one of the topics, a direct message or a reply from the server
+    Message(String),
+    /// A binary message, only delivered to subscribers that asked for them
+    Binary(Vec<u8>),
+}
+
+enum Command {
+    Subscribe { topic: String, token: String },
+    Unsubscribe { topic: String },
+}
+
+/// Connects to notiflux and keeps the topics subscribed, reconnecting with an exponential
+/// backoff whenever the connection drops
+///
+/// ```no_run
+/// # async fn run() {
+/// # use notiflux_client::{Event, Subscriber};
+/// let mut subscription = Subscriber::new("https://notiflux.example.com")
+///     .topic("build:123", "<token>")
+///     .start();
+///
+/// while let Some(event) = subscription.next().await {
+///     if let Event::Message(message) = event {
+///         println!("{}", message);
+///     }
+/// }
+/// # }
+/// ```
+pub struct Subscriber {
+    url: String,
+    topics: Vec<(String, String)>,
+    envelope: bool,
+    binary: bool,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    heartbeat_interval: Duration,
+    client_timeout: Duration,
+    /// Whether to ask the server for the heartbeat, rather than going with its default
+    negotiate_heartbeat: bool,
+}
+
+impl Subscriber {
+    /// A subscriber for the server at the given base URL, as `http(s)://` or `ws(s)://`
+    pub fn new(url: &str) -> Subscriber {
+        Subscriber {
+            url: url.to_owned(),
+            topics: Vec::new(),
+            envelope: false,
+            binary: false,
+            initial_backoff: INITIAL_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            client_timeout: CLIENT_TIMEOUT,
+            negotiate_heartbeat: false,
+        }
+    }
+
+    /// Subscribe to a topic with a token that has the subscribe scope for it
+    pub fn topic(mut self, topic: &str, token: &str) -> Self {
+        self.topics.push((topic.to_owned(), token.to_owned()));
+        self
+    }
+
+    /// Receive every message in a JSON envelope with its topic, id and timestamp
+    pub fn envelope(mut self, envelope: bool) -> Self {
+        self.envelope = envelope;
+        self
+    }
+
+    /// Receive binary messages as [`Event::Binary`], or in base64 in the envelope
+    pub fn binary(mut self, binary: bool) -> Self {
+        self.binary = binary;
+        self
+    }
+
+    /// The delay before the first reconnect attempt, which doubles up to `max` while the server
+    /// can't be reached
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// How often to ping the server, and how long to wait for anything from it before
+    /// reconnecting. The server is asked for the same, in whole seconds, and what it settles on
+    /// within its bounds is used instead.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self.client_timeout = timeout;
+        self.negotiate_heartbeat = true;
+        self
+    }
+
+    /// Connect in a background task, which runs until the subscription is dropped. Needs to be
+    /// called from within a tokio runtime.
+    pub fn start(self) -> Subscription {
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        tokio::spawn(self.run(events, commands_rx));
+
+        Subscription {
+            events: events_rx,
+            commands,
+        }
+    }
+
+    async fn run(
+        mut self,
+        events: mpsc::UnboundedSender<Event>,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+    ) {
+        let mut query = Vec::new();
+        if self.envelope {
+            query.push("envelope=true".to_owned());
+        }
+        if self.binary {
+            query.push("binary=true".to_owned());
+        }
+        if self.negotiate_heartbeat {
+            let secs = |duration: Duration| duration.as_secs().max(1);
+            query.push(format!("heartbeat={}", secs(self.heartbeat_interval)));
+            query.push(format!("timeout={}", secs(self.client_timeout)));
+        }
+        let path = match query.is_empty() {
+            true => "/ws".to_owned(),
+            false => format!("/ws?{}", query.join("&")),
+        };
+        let url = ws_url(&self.url, &path);
+        let mut backoff = self.initial_backoff;
+
+        loop {
+            let dropped = match tokio_tungstenite::connect_async(url.as_str()).await {
+                Ok((stream, response)) => {
+                    backoff = self.initial_backoff;
+                    let heartbeat = (
+                        header_secs(&response, HEARTBEAT_INTERVAL_HEADER)
+                            .unwrap_or(self.heartbeat_interval),
+                        header_secs(&response, CLIENT_TIMEOUT_HEADER)
+                            .unwrap_or(self.client_timeout),
+                    );
+                    match self
+                        .session(stream, heartbeat, &events, &mut commands)
+                        .await
+                    {
+                        Ok(()) => return,
+                        Err(dropped) => dropped,
+                    }
+                }
+                Err(e) => Dropped::from(e.to_string()),
+            };
+            let error = dropped.error;
+            let delay = dropped.retry_in.unwrap_or(backoff);
+
+            log::warn!(
+                "Disconnected from {}, retrying in {:?}: {}",
+                url,
+                delay,
+                error
+            );
+            let event = Event::Disconnected {
+                error,
+                retry_in: delay,
+            };
+            if events.send(event).is_err() {
+                return;
+            }
+
+            // Keep track of subscription changes while waiting to reconnect
+            let sleep = tokio::time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    command = commands.recv() => match command {
+                        Some(command) => self.apply(&command),
+                        None => return,
+                    },
+                }
+            }
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+
+    /// Run a single connection with the negotiated heartbeat interval and timeout until it
+    /// drops, which is an error, or until the subscription is dropped
+    async fn session(
+        &mut self,
+        stream: Stream,
+        (heartbeat_interval, client_timeout): (Duration, Duration),
+        events: &mpsc::UnboundedSender<Event>,
+        commands: &mut mpsc::UnboundedReceiver<Command>,
+    ) -> Result<(), Dropped> {
+        let (mut sink, mut stream) = stream.split();
+        let ws_error = |e: tungstenite::Error| e.to_string();
+
+        for (topic, token) in &self.topics {
+            sink.send(subscribe_command(topic, token))
+                .await
+                .map_err(ws_error)?;
+        }
+        if events.send(Event::Connected).is_err() {
+            return Ok(());
+        }
+
+        let mut heartbeat = tokio::time::interval(heartbeat_interval);
+        let mut last_seen = Instant::now();
+        loop {
+            tokio::select! {
+                message = stream.next() => {
+                    last_seen = Instant::now();
+                    match message {
+                        Some(Ok(tungstenite::Message::Text(text))) => {
+                            if events.send(Event::Message(text.to_string())).is_err() {
+                                return Ok(());
+                            }
+                        }
+                        Some(Ok(tungstenite::Message::Binary(bytes))) => {
+                            if events.send(Event::Binary(bytes.to_vec())).is_err() {
+                                return Ok(());
+                            }
+                        }
+                        Some(Ok(tungstenite::Message::Close(frame))) => {
+                            return Err(closed(frame));
+                        }
+                        // Pings are answered by tungstenite itself
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => return Err(ws_error(e).into()),
+                        None => return Err("Connection closed".to_owned().into()),
+                    }
+                }
+                command = commands.recv() => {
+                    let Some(command) = command else {
+                        let _ = sink.send(tungstenite::Message::Close(None)).await;
+                        return Ok(());
+                    };
+                    self.apply(&command);
+                    let message = match &command {
+                        Command::Subscribe { topic, token } => subscribe_command(topic, token),
+                        Command::Unsubscribe { topic } => {
+                            tungstenite::Message::text(format!("/unsubscribe {}", topic))
+                        }
+                    };
+                    sink.send(message).await.map_err(ws_error)?;
+                }
+                _ = heartbeat.tick() => {
+                    if last_seen.elapsed() > client_timeout {
+                        return Err("Heartbeat timed out".to_owned().into());
+                    }
+                    sink.send(tungstenite::Message::Ping(Default::default()))
+                        .await
+                        .map_err(ws_error)?;
+                }
+            }
+        }
+    }
+
+    /// Record a subscription change, so that it's applied again on reconnect
+    fn apply(&mut self, command: &Command) {
+        match command {
+            Command::Subscribe { topic, token } => {
+                self.topics.retain(|(t, _)| t != topic);
+                self.topics.push((topic.clone(), token.clone()));
+            }
+            Command::Unsubscribe { topic } => self.topics.retain(|(t, _)| t != topic),
+        }
+    }
+}
+
+/// A heartbeat setting the server responded with, in seconds
+fn header_secs(
+    response: &tungstenite::handshake::client::Response,
+    name: &str,
+) -> Option<Duration> {
+    response
+        .headers()
+        .get(name)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// A close frame from the server, which suggests when to reconnect if it's shutting down
+fn closed(frame: Option<CloseFrame>) -> Dropped {
+    let Some(frame) = frame else {
+        return "Closed by the server".to_owned().into();
+    };
+    let retry_in = (frame.code == CloseCode::Away)
+        .then(|| serde_json::from_str::<GoingAway>(&frame.reason).ok())
+        .flatten()
+        .map(|going_away| Duration::from_millis(going_away.reconnect_after_ms));
+
+    Dropped {
+        error: format!("Closed by the server: {}", frame),
+        retry_in,
+    }
+}
+
+fn subscribe_command(topic: &str, token: &str) -> tungstenite::Message {
+    tungstenite::Message::text(format!("/subscribe {} {}", topic, token))
+}
+
+/// A running subscriber, which disconnects when dropped
+pub struct Subscription {
+    events: mpsc::UnboundedReceiver<Event>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Subscription {
+    /// The next event, `None` once the subscriber has stopped
+    pub async fn next(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+
+    /// Subscribe to another topic, which is also resubscribed on every reconnect. Subscribing to
+    /// a topic again replaces its token.
+    pub fn subscribe(&self, topic: &str, token: &str) {
+        let _ = self.commands.send(Command::Subscribe {
+            topic: topic.to_owned(),
+            token: token.to_owned(),
+        });
+    }
+
+    pub fn unsubscribe(&self, topic: &str) {
+        let _ = self.commands.send(Command::Unsubscribe {
+            topic: topic.to_owned(),
+        });
+    }
+}
@@ -0,0 +1,167 @@
+use serde::Serialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Subscribe,
+    Broadcast,
+    Send,
+}
+
+/// A token to mint, signed with the ES256 private key whose public key notiflux is deployed with
+///
+/// ```no_run
+/// # use notiflux_client::Token;
+/// # let private_key = b"";
+/// let token = Token::subscribe(&["build:123"])
+///     .subject("alice")
+///     .sign(private_key)?;
+/// # Ok::<(), notiflux_client::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Token {
+    scope: Scope,
+    topics: Vec<String>,
+    targets: Vec<String>,
+    sub: String,
+    presence: bool,
+    expires_in: Duration,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    sub: &'a str,
+    exp: u64,
+    topics: &'a [String],
+    scope: Scope,
+    presence: bool,
+    targets: &'a [String],
+}
+
+impl Token {
+    fn new(scope: Scope) -> Token {
+        Token {
+            scope,
+            topics: Vec::new(),
+            targets: Vec::new(),
+            sub: "notiflux".to_owned(),
+            presence: false,
+            expires_in: Duration::from_secs(3600),
+        }
+    }
+
+    pub fn subscribe(topics: &[&str]) -> Token {
+        Token {
+            topics: topics.iter().map(|t| t.to_string()).collect(),
+            ..Token::new(Scope::Subscribe)
+        }
+    }
+
+    pub fn broadcast(topics: &[&str]) -> Token {
+        Token {
+            topics: topics.iter().map(|t| t.to_string()).collect(),
+            ..Token::new(Scope::Broadcast)
+        }
+    }
+
+    /// A token for direct messages to the given subjects or session ids
+    pub fn send(targets: &[&str]) -> Token {
+        Token {
+            targets: targets.iter().map(|t| t.to_string()).collect(),
+            ..Token::new(Scope::Send)
+        }
+    }
+
+    /// The subject, `notiflux` by default
+    pub fn subject(mut self, sub: &str) -> Self {
+        self.sub = sub.to_owned();
+        self
+    }
+
+    /// Allow seeing who else is subscribed to the topics
+    pub fn presence(mut self, presence: bool) -> Self {
+        self.presence = presence;
+        self
+    }
+
+    /// How long the token is valid for, an hour by default
+    pub fn expires_in(mut self, expires_in: Duration) -> Self {
+        self.expires_in = expires_in;
+        self
+    }
+
+    /// Sign the token with a private key in PKCS8 PEM format
+    pub fn sign(&self, private_key_pem: &[u8]) -> Result<String, Error> {
+        let exp = (SystemTime::now() + self.expires_in)
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let key = jsonwebtoken::EncodingKey::from_ec_pem(private_key_pem)?;
+
+        Ok(jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::ES256),
+            &Claims {
+                sub: &self.sub,
+                exp,
+                topics: &self.topics,
+                scope: self.scope,
+                presence: self.presence,
+                targets: &self.targets,
+            },
+            &key,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn decode(token: &str) -> Value {
+        let key =
+            jsonwebtoken::DecodingKey::from_ec_pem(include_bytes!("../../scripts/public_key.pem"))
+                .unwrap();
+        let validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::ES256);
+        jsonwebtoken::decode::<Value>(token, &key, &validation)
+            .unwrap()
+            .claims
+    }
+
+    #[test]
+    fn test_sign() {
+        let token = Token::subscribe(&["foo", "bar"])
+            .subject("alice")
+            .presence(true)
+            .sign(include_bytes!("../../scripts/private_key_pkcs8.pem"))
+            .unwrap();
+
+        let claims = decode(&token);
+        assert_eq!(claims["sub"], "alice");
+        assert_eq!(claims["scope"], "subscribe");
+        assert_eq!(claims["topics"], serde_json::json!(["foo", "bar"]));
+        assert_eq!(claims["presence"], true);
+    }
+
+    #[test]
+    fn test_sign_send() {
+        let token = Token::send(&["bob"])
+            .sign(include_bytes!("../../scripts/private_key_pkcs8.pem"))
+            .unwrap();
+
+        let claims = decode(&token);
+        assert_eq!(claims["scope"], "send");
+        assert_eq!(claims["targets"], serde_json::json!(["bob"]));
+    }
+
+    #[test]
+    fn test_sign_invalid_key() {
+        assert!(matches!(
+            Token::broadcast(&["foo"]).sign(b"not a key"),
+            Err(Error::Token(_))
+        ));
+    }
+}
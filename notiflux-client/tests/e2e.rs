@@ -0,0 +1,234 @@
+//! Runs the client against an in-process notiflux server
+use actix_web::{dev::ServerHandle, App, HttpServer};
+use notiflux::{Config, NotifluxBuilder, NotifluxHandle};
+use notiflux_client::{Error, Event, Publisher, Subscriber, Subscription, Token};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const PRIVATE_KEY: &[u8] = include_bytes!("../../scripts/private_key_pkcs8.pem");
+
+fn config() -> Config {
+    Config::new(include_bytes!("../../scripts/public_key.pem"))
+}
+
+/// Serve notiflux on the given address, port 0 for any free port
+fn start_server(addr: SocketAddr) -> (SocketAddr, ServerHandle, NotifluxHandle) {
+    start_server_with(addr, config())
+}
+
+fn start_server_with(
+    addr: SocketAddr,
+    config: Config,
+) -> (SocketAddr, ServerHandle, NotifluxHandle) {
+    let notiflux = NotifluxBuilder::new(config).build().unwrap();
+    let handle = notiflux.handle();
+
+    let server = HttpServer::new(move || App::new().service(notiflux.scope("")))
+        .workers(1)
+        .shutdown_timeout(0)
+        .bind(addr)
+        .unwrap();
+    let addr = server.addrs()[0];
+    let server = server.run();
+    let server_handle = server.handle();
+    actix::spawn(server);
+
+    (addr, server_handle, handle)
+}
+
+fn url(addr: SocketAddr) -> String {
+    format!("http://{}", addr)
+}
+
+fn token(scope: Token) -> String {
+    scope.sign(PRIVATE_KEY).unwrap()
+}
+
+async fn next(subscription: &mut Subscription) -> Event {
+    tokio::time::timeout(Duration::from_secs(5), subscription.next())
+        .await
+        .expect("Timed out waiting for an event")
+        .expect("Subscriber stopped")
+}
+
+/// Wait until the subscribe commands have been handled by the server
+async fn settle() {
+    tokio::time::sleep(Duration::from_millis(100)).await;
+}
+
+#[actix::test]
+async fn test_subscribe_and_publish() {
+    let (addr, _, _) = start_server("127.0.0.1:0".parse().unwrap());
+    let mut subscription = Subscriber::new(&url(addr))
+        .topic("foo", &token(Token::subscribe(&["foo"])))
+        .start();
+    assert_eq!(next(&mut subscription).await, Event::Connected);
+    settle().await;
+
+    let publisher = Publisher::new(&url(addr), &token(Token::broadcast(&["foo"])));
+    publisher.publish("foo", "hello").await.unwrap();
+
+    assert_eq!(
+        next(&mut subscription).await,
+        Event::Message("hello".to_owned())
+    );
+}
+
+#[actix::test]
+async fn test_publish_binary() {
+    let (addr, _, _) = start_server("127.0.0.1:0".parse().unwrap());
+    let mut subscription = Subscriber::new(&url(addr))
+        .topic("foo", &token(Token::subscribe(&["foo"])))
+        .binary(true)
+        .start();
+    assert_eq!(next(&mut subscription).await, Event::Connected);
+    settle().await;
+
+    let publisher = Publisher::new(&url(addr), &token(Token::broadcast(&["foo"])));
+    publisher
+        .publish_binary("foo", &[0, 159, 146, 150])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        next(&mut subscription).await,
+        Event::Binary(vec![0, 159, 146, 150])
+    );
+}
+
+#[actix::test]
+async fn test_topic_in_path_encoded() {
+    let (addr, _, _) = start_server("127.0.0.1:0".parse().unwrap());
+    for topic in ["a/b", "a?b", "foo#1", "50%"] {
+        let mut subscription = Subscriber::new(&url(addr))
+            .topic(topic, &token(Token::subscribe(&[topic])))
+            .binary(true)
+            .start();
+        assert_eq!(next(&mut subscription).await, Event::Connected);
+        settle().await;
+
+        let publisher = Publisher::new(&url(addr), &token(Token::broadcast(&[topic])));
+        publisher.publish_binary(topic, &[0, 159]).await.unwrap();
+        assert_eq!(next(&mut subscription).await, Event::Binary(vec![0, 159]));
+
+        publisher
+            .publish_retained(topic, "hello", None)
+            .await
+            .unwrap();
+        publisher.clear_retained(topic).await.unwrap();
+    }
+}
+
+#[actix::test]
+async fn test_publish_rejected() {
+    let (addr, _, _) = start_server("127.0.0.1:0".parse().unwrap());
+    let publisher = Publisher::new(&url(addr), &token(Token::broadcast(&["foo"])));
+
+    match publisher.publish("bar", "hello").await {
+        Err(Error::Rejected { status, .. }) => assert_eq!(status, 403),
+        other => panic!("Expected a rejection, got {:?}", other),
+    }
+}
+
+#[actix::test]
+async fn test_subscribe_later_and_unsubscribe() {
+    let (addr, _, handle) = start_server("127.0.0.1:0".parse().unwrap());
+    let mut subscription = Subscriber::new(&url(addr)).start();
+    assert_eq!(next(&mut subscription).await, Event::Connected);
+
+    subscription.subscribe("foo", &token(Token::subscribe(&["foo"])));
+    settle().await;
+    handle.publish("foo", "first");
+    assert_eq!(
+        next(&mut subscription).await,
+        Event::Message("first".to_owned())
+    );
+
+    subscription.unsubscribe("foo");
+    settle().await;
+    handle.publish("foo", "second");
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), subscription.next())
+            .await
+            .is_err()
+    );
+}
+
+#[actix::test]
+async fn test_reconnects_and_resubscribes() {
+    let (addr, server, _) = start_server("127.0.0.1:0".parse().unwrap());
+    let mut subscription = Subscriber::new(&url(addr))
+        .topic("foo", &token(Token::subscribe(&["foo"])))
+        .backoff(Duration::from_millis(50), Duration::from_millis(200))
+        .start();
+    assert_eq!(next(&mut subscription).await, Event::Connected);
+
+    server.stop(false).await;
+    assert!(matches!(
+        next(&mut subscription).await,
+        Event::Disconnected { .. }
+    ));
+
+    let (_, _, handle) = start_server(addr);
+    loop {
+        match next(&mut subscription).await {
+            Event::Connected => break,
+            Event::Disconnected { .. } => continue,
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+    settle().await;
+
+    handle.publish("foo", "back");
+    assert_eq!(
+        next(&mut subscription).await,
+        Event::Message("back".to_owned())
+    );
+}
+
+#[actix::test]
+async fn test_heartbeat_timeout() {
+    // A server that accepts the connection but never says anything
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    actix::spawn(async move {
+        let mut streams = Vec::new();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(stream) = tokio_tungstenite::accept_async(stream).await {
+                streams.push(stream);
+            }
+        }
+    });
+
+    let mut subscription = Subscriber::new(&url(addr))
+        .heartbeat(Duration::from_millis(50), Duration::from_millis(200))
+        .start();
+    assert_eq!(next(&mut subscription).await, Event::Connected);
+
+    match next(&mut subscription).await {
+        Event::Disconnected { error, .. } => assert_eq!(error, "Heartbeat timed out"),
+        event => panic!("Unexpected event {:?}", event),
+    }
+}
+
+#[actix::test]
+async fn test_reconnect_delay_from_shutdown() {
+    let mut config = config();
+    config.shutdown.reconnect_delay = Duration::from_millis(200);
+    let (addr, _, handle) = start_server_with("127.0.0.1:0".parse().unwrap(), config);
+    let mut subscription = Subscriber::new(&url(addr))
+        .backoff(Duration::from_secs(60), Duration::from_secs(60))
+        .start();
+    assert_eq!(next(&mut subscription).await, Event::Connected);
+
+    handle.shutdown().await;
+
+    match next(&mut subscription).await {
+        Event::Disconnected { error, retry_in } => {
+            assert!(error.starts_with("Closed by the server"), "{}", error);
+            assert!(retry_in <= Duration::from_millis(200));
+        }
+        event => panic!("Unexpected event {:?}", event),
+    }
+}
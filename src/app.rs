@@ -1,75 +1,747 @@
 use actix::*;
-use actix_web::{middleware::Logger, web, App, Error, HttpRequest, HttpResponse, HttpServer};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::{
+    middleware::Logger, web, App, Error, HttpRequest, HttpResponse, HttpServer, Scope,
+};
 use actix_web_actors::ws;
-use serde::Deserialize;
-use std::time::Instant;
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use ulid::Ulid;
 
-use crate::{config, message, server, session, NotifluxError};
+use crate::auth::{Authorizer, Credential, Decision, Grant, Operation};
+use crate::builder::{Notiflux, NotifluxBuilder};
+use crate::limits::{Limiter, RateKey};
+use crate::{config, message, server, session, tls, NotifluxError, NotifluxErrorType};
+
+/// Set once the server starts shutting down, after which new WebSocket connections and HTTP
+/// broadcasts are refused
+#[derive(Default)]
+pub(crate) struct Draining {
+    draining: AtomicBool,
+    /// HTTP broadcasts accepted but not yet handed to the server
+    in_flight: AtomicUsize,
+}
+
+impl Draining {
+    pub(crate) fn start(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Counts a broadcast as in flight until the guard is dropped, or refuses it once draining.
+    /// The count goes up before the check, so that shutdown either sees it or it sees shutdown.
+    fn track(&self) -> Result<InFlight<'_>, NotifluxError> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlight(&self.in_flight);
+        if self.is_draining() {
+            return Err(NotifluxError {
+                message: Some("Shutting down".to_owned()),
+                error_type: NotifluxErrorType::Unavailable,
+            });
+        }
+        Ok(guard)
+    }
+}
+
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Room for the topic, token and other fields of a JSON body besides the message
+const JSON_OVERHEAD: usize = 64 * 1024;
+const HEARTBEAT_INTERVAL_HEADER: &str = "x-notiflux-heartbeat-interval";
+const CLIENT_TIMEOUT_HEADER: &str = "x-notiflux-client-timeout";
+
+#[derive(Deserialize)]
+struct WsQuery {
+    #[serde(default)]
+    envelope: bool,
+    /// Deliver binary messages, which sessions that only expect text are spared
+    #[serde(default)]
+    binary: bool,
+    /// Seconds between pings the client asks for
+    heartbeat: Option<u64>,
+    /// Seconds of silence after which the client asks to be disconnected
+    timeout: Option<u64>,
+}
 
 async fn ws_route(
     req: HttpRequest,
     stream: web::Payload,
+    query: web::Query<WsQuery>,
+    draining: web::Data<Draining>,
+    heartbeat: web::Data<config::HeartbeatConfig>,
+    limiter: web::Data<Limiter>,
     srv: web::Data<Addr<server::Server>>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(
+    if draining.is_draining() {
+        return Err(NotifluxError {
+            message: Some("Shutting down".to_owned()),
+            error_type: NotifluxErrorType::Unavailable,
+        }
+        .into());
+    }
+    let ip = client_ip(&req, &limiter);
+    let connection = limiter.into_inner().connect(ip)?;
+
+    let (heartbeat_interval, client_timeout) = heartbeat.negotiate(query.heartbeat, query.timeout);
+    let protocols = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|protocols| protocols.to_str().ok());
+    let codec = session::Codec::negotiate(protocols, query.envelope);
+    let max_frame_size = connection.limiter().config().max_frame_size;
+    let mut builder = ws::WsResponseBuilder::new(
         session::WSSession {
             id: Ulid::new(),
-            topics: Vec::new(),
             heartbeat: Instant::now(),
+            heartbeat_interval,
+            client_timeout,
             addr: srv.get_ref().clone(),
+            codec,
+            binary: query.binary || codec.is_binary(),
+            connection,
         },
         &req,
         stream,
     )
+    .protocols(codec.protocols());
+    if let Some(max_frame_size) = max_frame_size {
+        builder = builder.frame_size(max_frame_size);
+    }
+    let mut response = builder.start()?;
+
+    // Let the client know what it got, which may differ from what it asked for
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_static(HEARTBEAT_INTERVAL_HEADER),
+        HeaderValue::from(heartbeat_interval.as_secs()),
+    );
+    headers.insert(
+        HeaderName::from_static(CLIENT_TIMEOUT_HEADER),
+        HeaderValue::from(client_timeout.as_secs()),
+    );
+    Ok(response)
+}
+
+/// The token of an `Authorization: Bearer` header, if there's one. Tokens aren't taken from the
+/// query string, which ends up in access logs.
+fn bearer(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_owned())
+}
+
+/// The address the per address limits count the request against, which is the peer's unless
+/// the proxy in front is trusted to forward the client's
+fn client_ip(req: &HttpRequest, limiter: &Limiter) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    if !limiter.config().trust_proxy {
+        return peer;
+    }
+    forwarded_ip(req).or(peer)
+}
+
+/// The address the trusted proxy forwarded, which is the rightmost one. Those on its left come
+/// from the client, which can send anything.
+fn forwarded_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let header = |name| {
+        req.headers()
+            .get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .last()
+    };
+    if let Some(forwarded) = header(header::FORWARDED) {
+        let element = forwarded.rsplit(',').next()?;
+        let node = element.split(';').find_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            name.eq_ignore_ascii_case("for").then_some(value)
+        })?;
+        return parse_node(node);
+    }
+    parse_node(
+        header(HeaderName::from_static("x-forwarded-for"))?
+            .rsplit(',')
+            .next()?,
+    )
+}
+
+/// An address with an optional port, quoted or in brackets as the `Forwarded` header has them
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+fn bearer_token(req: &HttpRequest) -> Result<String, NotifluxError> {
+    bearer(req).ok_or_else(|| NotifluxError {
+        message: Some("An Authorization header with a bearer token is required".to_owned()),
+        error_type: NotifluxErrorType::ValidationError,
+    })
+}
+
+/// Ask the authorizer, turning a denial into a 403 with its reason
+async fn authorize(
+    authorizer: &dyn Authorizer,
+    credential: &Credential,
+    operation: Operation,
+    topic: &str,
+) -> Result<Grant, NotifluxError> {
+    match authorizer.authorize(credential, operation, topic).await {
+        Decision::Allow(grant) => Ok(grant),
+        Decision::Deny(reason) => Err(NotifluxError {
+            message: Some(reason),
+            error_type: NotifluxErrorType::AuthorizationError,
+        }),
+    }
 }
 
 #[derive(Deserialize)]
 struct BroadcastPayload {
     topic: String,
     message: String,
-    token: String,
+    /// Set to `base64` for a binary message
+    encoding: Option<message::Encoding>,
+    /// Can be left out by clients that authenticate with a certificate
+    token: Option<String>,
+    #[serde(default)]
+    retain: bool,
+    /// Seconds to keep the retained message for
+    retain_ttl: Option<u64>,
+}
+
+/// The options of a binary broadcast, whose body is the message and path the topic. The token
+/// is in the Authorization header.
+#[derive(Deserialize)]
+struct BinaryBroadcastQuery {
+    #[serde(default)]
+    retain: bool,
+    retain_ttl: Option<u64>,
+}
+
+/// A message to publish, from either kind of broadcast request
+struct Broadcast {
+    topic: String,
+    payload: message::Payload,
+    token: Option<String>,
+    retain: Option<message::Retain>,
+}
+
+fn retain(retain: bool, ttl: Option<u64>) -> Option<message::Retain> {
+    retain.then(|| message::Retain {
+        ttl: ttl.map(Duration::from_secs),
+    })
 }
 
 async fn broadcast(
+    http_req: HttpRequest,
     req: web::Json<BroadcastPayload>,
+    authorizer: web::Data<dyn Authorizer>,
+    limiter: web::Data<Limiter>,
     srv: web::Data<Addr<server::Server>>,
-) -> HttpResponse {
-    srv.get_ref().do_send(message::Broadcast {
-        msg: req.message.to_owned(),
-        topic: req.topic.to_owned(),
-        token: req.token.to_owned(),
+) -> Result<HttpResponse, NotifluxError> {
+    let req = req.into_inner();
+    let payload = match req.encoding {
+        None => message::Payload::Text(req.message),
+        Some(message::Encoding::Base64) => {
+            message::Payload::Binary(BASE64_STANDARD.decode(req.message).map_err(|e| {
+                NotifluxError {
+                    message: Some(format!("Invalid base64 message: {}", e)),
+                    error_type: NotifluxErrorType::ValidationError,
+                }
+            })?)
+        }
+    };
+
+    let broadcast = Broadcast {
+        topic: req.topic,
+        payload,
+        token: req.token,
+        retain: retain(req.retain, req.retain_ttl),
+    };
+    publish(&http_req, broadcast, &**authorizer, &limiter, &srv).await
+}
+
+async fn broadcast_binary(
+    http_req: HttpRequest,
+    topic: web::Path<String>,
+    query: web::Query<BinaryBroadcastQuery>,
+    body: web::Bytes,
+    authorizer: web::Data<dyn Authorizer>,
+    limiter: web::Data<Limiter>,
+    srv: web::Data<Addr<server::Server>>,
+) -> Result<HttpResponse, NotifluxError> {
+    let query = query.into_inner();
+    let broadcast = Broadcast {
+        topic: topic.into_inner(),
+        payload: message::Payload::Binary(body.to_vec()),
+        token: bearer(&http_req),
+        retain: retain(query.retain, query.retain_ttl),
+    };
+    publish(&http_req, broadcast, &**authorizer, &limiter, &srv).await
+}
+
+async fn publish(
+    http_req: &HttpRequest,
+    req: Broadcast,
+    authorizer: &dyn Authorizer,
+    limiter: &Limiter,
+    srv: &Addr<server::Server>,
+) -> Result<HttpResponse, NotifluxError> {
+    let draining = http_req
+        .app_data::<web::Data<Draining>>()
+        .expect("Draining is registered by the scope");
+    let _in_flight = draining.track()?;
+    // Checked before the token, so that a flood doesn't cost a verification each
+    if let Some(ip) = client_ip(http_req, limiter) {
+        limiter.check_rate(RateKey::Ip(ip))?;
+    }
+    limiter.validate_topic(&req.topic)?;
+    limiter.validate_message(&req.payload)?;
+
+    let credential = match (req.token, http_req.conn_data::<tls::ClientIdentity>()) {
+        (Some(token), _) => Credential::Token(token),
+        (None, Some(identity)) => Credential::Certificate(identity.names.clone()),
+        (None, None) => {
+            return Err(NotifluxError {
+                message: Some("A token or client certificate is required".to_owned()),
+                error_type: NotifluxErrorType::ValidationError,
+            })
+        }
+    };
+
+    let grant = authorize(authorizer, &credential, Operation::Broadcast, &req.topic).await?;
+    limiter.check_rate(RateKey::Sub(&grant.sub))?;
+    limiter.check_rate(RateKey::Topic(&req.topic))?;
+
+    srv.do_send(message::Publish {
+        payload: req.payload,
+        topic: req.topic,
+        retain: req.retain,
     });
 
-    HttpResponse::Ok().finish()
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn clear_retained(
+    req: HttpRequest,
+    topic: web::Path<String>,
+    authorizer: web::Data<dyn Authorizer>,
+    limiter: web::Data<Limiter>,
+    srv: web::Data<Addr<server::Server>>,
+) -> Result<HttpResponse, NotifluxError> {
+    let topic = topic.into_inner();
+    limiter.validate_topic(&topic)?;
+    let credential = Credential::Token(bearer_token(&req)?);
+    authorize(&**authorizer, &credential, Operation::ClearRetained, &topic).await?;
+
+    srv.get_ref().do_send(message::ClearRetained { topic });
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Serialize)]
+struct PresenceResponse {
+    topic: String,
+    members: Vec<message::PresenceEntry>,
+}
+
+async fn presence(
+    req: HttpRequest,
+    topic: web::Path<String>,
+    authorizer: web::Data<dyn Authorizer>,
+    limiter: web::Data<Limiter>,
+    srv: web::Data<Addr<server::Server>>,
+) -> Result<HttpResponse, Error> {
+    let topic = topic.into_inner();
+    limiter.validate_topic(&topic)?;
+    let credential = Credential::Token(bearer_token(&req)?);
+    authorize(&**authorizer, &credential, Operation::Presence, &topic).await?;
+
+    let members = srv
+        .get_ref()
+        .send(message::GetPresence {
+            topic: topic.clone(),
+            auth: message::PresenceAuth::Authorized,
+        })
+        .await
+        .map_err(|e| {
+            log::error!("Unable to query presence: {}", e);
+            actix_web::error::ErrorInternalServerError(e)
+        })??;
+
+    Ok(HttpResponse::Ok().json(PresenceResponse { topic, members }))
+}
+
+#[derive(Deserialize)]
+struct SendPayload {
+    sub: Option<String>,
+    session: Option<String>,
+    message: String,
+    token: String,
+}
+
+async fn send(
+    req: web::Json<SendPayload>,
+    limiter: web::Data<Limiter>,
+    srv: web::Data<Addr<server::Server>>,
+) -> Result<HttpResponse, NotifluxError> {
+    let SendPayload {
+        sub,
+        session,
+        message,
+        token,
+    } = req.into_inner();
+    limiter.validate_message(&message)?;
+
+    let target = match (sub, session) {
+        (Some(sub), None) => message::SendTarget::Subject(sub),
+        (None, Some(session)) => {
+            let id = Ulid::from_string(&session).map_err(|_| NotifluxError {
+                message: Some(format!("Invalid session id: {}", session)),
+                error_type: NotifluxErrorType::ValidationError,
+            })?;
+            message::SendTarget::Session(id)
+        }
+        _ => {
+            return Err(NotifluxError {
+                message: Some("Exactly one of sub or session must be provided".to_owned()),
+                error_type: NotifluxErrorType::ValidationError,
+            })
+        }
+    };
+
+    srv.get_ref()
+        .send(message::DirectMessage {
+            msg: message,
+            target,
+            credential: Credential::Token(token),
+        })
+        .await
+        .map_err(|e| NotifluxError {
+            message: Some(format!("Unable to send message: {}", e)),
+            error_type: NotifluxErrorType::Error,
+        })??;
+
+    Ok(HttpResponse::Ok().finish())
 }
 
 async fn health_check() -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
-pub async fn run() -> Result<(), NotifluxError> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
+async fn metrics(limiter: web::Data<Limiter>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(limiter.metrics())
+}
 
-    let config = config::get_config();
+/// JSON bodies are limited to the message size, with room for the rest of the payload, and
+/// invalid ones are reported like any other validation error
+fn json_config(limiter: &Limiter) -> web::JsonConfig {
+    let config = web::JsonConfig::default().error_handler(|err, _| {
+        NotifluxError {
+            message: Some(err.to_string()),
+            error_type: NotifluxErrorType::ValidationError,
+        }
+        .into()
+    });
+    match limiter.config().max_message_size {
+        Some(max) => config.limit(max + JSON_OVERHEAD),
+        None => config,
+    }
+}
 
-    let server = server::Server::new(&config.jwt_public_key).start();
+/// Binary bodies are limited to the message size
+fn payload_config(limiter: &Limiter) -> web::PayloadConfig {
+    match limiter.config().max_message_size {
+        Some(max) => web::PayloadConfig::new(max),
+        None => web::PayloadConfig::default(),
+    }
+}
+
+/// Mount the routes on a scope, with the server and the state shared by the workers as app data
+pub(crate) fn scope(path: &str, notiflux: &Notiflux) -> Scope {
+    web::scope(path)
+        .app_data(json_config(&notiflux.limiter))
+        .app_data(payload_config(&notiflux.limiter))
+        .app_data(web::Data::new(notiflux.server.clone()))
+        .app_data(notiflux.authorizer.clone())
+        .app_data(notiflux.draining.clone())
+        .app_data(notiflux.heartbeat.clone())
+        .app_data(notiflux.limiter.clone())
+        .route("/broadcast", web::post().to(broadcast))
+        .route("/broadcast/{topic}", web::post().to(broadcast_binary))
+        .route("/send", web::post().to(send))
+        .route("/retained/{topic}", web::delete().to(clear_retained))
+        .route("/ws", web::get().to(ws_route))
+        .route("/presence/{topic}", web::get().to(presence))
+        .route("/health", web::get().to(health_check))
+        .route("/metrics", web::get().to(metrics))
+}
+
+/// Resolves on SIGTERM, or ctrl-c
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                let terminate = Box::pin(terminate.recv());
+                let interrupt = Box::pin(tokio::signal::ctrl_c());
+                futures_util::future::select(terminate, interrupt).await;
+                return;
+            }
+            Err(e) => log::error!("Unable to listen for SIGTERM: {}", e),
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
 
-    log::info!("Starting server on {}:{}", config.host, config.port);
+pub async fn run(config: config::Config) -> Result<(), NotifluxError> {
+    let notiflux = NotifluxBuilder::new(config.clone()).build()?;
+    let handle = notiflux.handle();
+
+    log::info!(
+        "Starting server on {}://{}:{}",
+        if config.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        },
+        config.host,
+        config.port
+    );
     let bind_tuple = (config.host.clone(), config.port);
 
-    HttpServer::new(move || {
+    let http_server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(server.clone()))
-            .route("/broadcast", web::post().to(broadcast))
-            .route("/ws", web::get().to(ws_route))
-            .route("/health", web::get().to(health_check))
+            .service(notiflux.scope(""))
             .wrap(Logger::default())
     })
+    .on_connect(tls::on_connect)
     .workers(config.worker_count)
-    .bind(bind_tuple)?
-    .run()
-    .await?;
+    .disable_signals();
+
+    let http_server = match &config.tls {
+        Some(tls_config) => {
+            let resolver = Arc::new(tls::CertResolver::new(tls_config.clone())?);
+            let server_config = tls::server_config(resolver.clone())?;
+            tls::CertReloader::new(resolver).start();
+            http_server.bind_rustls_0_23(bind_tuple, server_config)?
+        }
+        None => http_server.bind(bind_tuple)?,
+    };
+    let http_server = http_server.run();
+
+    let server_handle = http_server.handle();
+    actix::spawn(async move {
+        shutdown_signal().await;
+        log::info!("Shutting down");
+        handle.shutdown().await;
+        server_handle.stop(true).await;
+    });
+    http_server.await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::test_utils::{sign_send_token, sign_subject_token, sign_token};
+    use crate::builder::test_utils::*;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite;
+
+    #[test]
+    fn test_forwarded_ip() {
+        for (name, value, expected) in [
+            ("x-forwarded-for", "10.0.0.1", "10.0.0.1"),
+            ("x-forwarded-for", "spoofed, 10.0.0.1, 10.0.0.2", "10.0.0.2"),
+            ("x-forwarded-for", "10.0.0.1:8080", "10.0.0.1"),
+            (
+                "forwarded",
+                "for=10.0.0.1;proto=https, for=10.0.0.2",
+                "10.0.0.2",
+            ),
+            ("forwarded", r#"for="[2001:db8::1]:4711""#, "2001:db8::1"),
+            ("forwarded", r#"for="[2001:db8::1]""#, "2001:db8::1"),
+        ] {
+            let req = actix_web::test::TestRequest::default()
+                .insert_header((name, value))
+                .to_http_request();
+            assert_eq!(
+                super::forwarded_ip(&req),
+                Some(expected.parse().unwrap()),
+                "{}: {}",
+                name,
+                value
+            );
+        }
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("x-forwarded-for", "10.0.0.1, unknown"))
+            .to_http_request();
+        assert_eq!(super::forwarded_ip(&req), None);
+    }
+
+    #[actix::test]
+    async fn test_validation() {
+        let mut config = config();
+        config.limits.max_message_size = Some(8);
+        let (addr, _) = serve(config);
+
+        let broadcast = |topic: &str, message: &str| {
+            reqwest::Client::new()
+                .post(url(addr, "/broadcast"))
+                .json(&serde_json::json!({
+                    "topic": topic,
+                    "message": message,
+                    "token": sign_token("broadcast", &[topic]),
+                }))
+                .send()
+        };
+        // Topics can have anything but control characters, as before there was validation
+        for topic in ["a/b", "foo bar", "büild"] {
+            let response = broadcast(topic, "hello").await.unwrap();
+            assert!(response.status().is_success(), "{}", topic);
+        }
+        for (topic, message, expected) in [
+            ("foo\nbar", "hello", "Topic can't contain '\\n'"),
+            ("foo", "hello world", "Message is larger than 8 bytes"),
+        ] {
+            let response = broadcast(topic, message).await.unwrap();
+            assert_eq!(response.status(), 400);
+            assert_eq!(
+                response.json::<serde_json::Value>().await.unwrap()["error"],
+                expected
+            );
+        }
+        let response = reqwest::Client::new()
+            .post(url(addr, "/broadcast"))
+            .body("not json")
+            .header("Content-Type", "application/json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix::test]
+    async fn test_binary_broadcast() {
+        let (addr, handle) = serve(config());
+        let mut text = connect(addr, "").await;
+        let mut binary = connect(addr, "?binary=true").await;
+        let mut envelope = connect(addr, "?binary=true&envelope=true").await;
+        for stream in [&mut text, &mut binary, &mut envelope] {
+            subscribe(stream, "foo").await;
+        }
+
+        let token = sign_token("broadcast", &["foo"]);
+        let broadcast = |request: reqwest::RequestBuilder| {
+            request
+                .header("Content-Type", "application/octet-stream")
+                .body(vec![0u8, 159, 146, 150])
+                .send()
+        };
+        // The token isn't taken from the query string
+        let response = broadcast(
+            reqwest::Client::new()
+                .post(url(addr, "/broadcast/foo"))
+                .query(&[("token", &token)]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 400);
+        let response = broadcast(
+            reqwest::Client::new()
+                .post(url(addr, "/broadcast/foo"))
+                .bearer_auth(&token),
+        )
+        .await
+        .unwrap();
+        assert!(response.status().is_success());
+        let response = reqwest::Client::new()
+            .post(url(addr, "/broadcast"))
+            .json(&serde_json::json!({
+                "topic": "foo",
+                "message": "AQI=",
+                "encoding": "base64",
+                "token": token,
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        handle.publish("foo", "hello");
+
+        assert_eq!(
+            next(&mut binary).await,
+            tungstenite::Message::binary(vec![0u8, 159, 146, 150])
+        );
+        assert_eq!(
+            next(&mut binary).await,
+            tungstenite::Message::binary(vec![1u8, 2])
+        );
+        assert_eq!(next(&mut binary).await, tungstenite::Message::text("hello"));
+
+        let message = next(&mut envelope).await.into_text().unwrap();
+        let delivery: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(delivery["payload"], "AJ+Slg==");
+        assert_eq!(delivery["encoding"], "base64");
+
+        // Sessions that didn't ask for binary messages only get the text one
+        assert_eq!(next(&mut text).await, tungstenite::Message::text("hello"));
+    }
+
+    #[actix::test]
+    async fn test_send_to_subject() {
+        let (addr, _) = serve(config());
+        let mut phone = connect(addr, "").await;
+        let mut laptop = connect(addr, "").await;
+        for (stream, topic) in [(&mut phone, "foo"), (&mut laptop, "bar")] {
+            let token = sign_subject_token("alice", "subscribe", &[topic]);
+            send(stream, &format!("/subscribe {} {}", topic, token)).await;
+        }
+        actix::clock::sleep(Duration::from_millis(50)).await;
+
+        let send = |sub: &str| {
+            reqwest::Client::new()
+                .post(url(addr, "/send"))
+                .json(&serde_json::json!({
+                    "sub": sub,
+                    "message": "hello",
+                    "token": sign_send_token(&["alice"]),
+                }))
+                .send()
+        };
+        let response = send("bob").await.unwrap();
+        assert_eq!(response.status(), 403);
+        let response = send("alice").await.unwrap();
+        assert!(response.status().is_success());
+
+        assert_eq!(next(&mut phone).await, tungstenite::Message::text("hello"));
+        assert_eq!(next(&mut laptop).await, tungstenite::Message::text("hello"));
+    }
+}
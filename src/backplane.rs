@@ -0,0 +1,45 @@
+//! Backplanes connect notiflux instances to each other, so that a message broadcast on one
+//! instance reaches the subscribers of every instance.
+//!
+//! The server tells its backplane about every message broadcast locally, and about the topics
+//! that local sessions are interested in. Messages received from other instances are handed
+//! back to the server as a [`message::Relay`](crate::message::Relay), which delivers them to
+//! local subscribers without any further authorisation. Direct messages are relayed the same
+//! way, to every instance, and delivered to the local sessions of their target.
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::message::{Delivery, SendTarget};
+
+pub mod cluster;
+#[cfg(feature = "redis")]
+pub mod redis;
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub enum Outbound {
+    /// A message that was broadcast on this instance
+    Publish(Delivery),
+    /// The first local session subscribed to the topic
+    Subscribe(String),
+    /// The last local session left the topic
+    Unsubscribe(String),
+    /// A direct message that was sent on this instance, for the sessions of the target on
+    /// other instances
+    Send {
+        target: SendTarget,
+        delivery: Delivery,
+    },
+}
+
+/// A message as it is sent between instances
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+pub struct Envelope {
+    /// The instance the message was broadcast on, which has already delivered it locally
+    pub origin: String,
+    pub delivery: Delivery,
+    /// Set for direct messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<SendTarget>,
+}
@@ -0,0 +1,923 @@
+//! Native clustering, where notiflux instances connect directly to each other without an
+//! external broker.
+//!
+//! Every node listens on a cluster address and keeps a persistent TCP connection to every other
+//! member, exchanging newline delimited JSON frames. Members are found from a static list of
+//! seeds, optionally resolved from DNS, and from the member lists that nodes gossip to each
+//! other. Each node tells its peers which topics it has local subscribers for, so that a
+//! broadcast is only forwarded to the nodes that need it.
+//!
+//! Nodes prove that they know the shared secret without sending it: the node accepting a
+//! connection sends a random nonce, and the dialing node answers with a nonce of its own in its
+//! hello. Each node signs both nonces, its role and its id with an HMAC of the secret, so a
+//! proof made for one connection or direction can't be replayed for another. The accepting node
+//! only answers with its hello once the dialer's has been verified, and nothing else is
+//! exchanged until both have been. A connection that isn't authenticated within a few seconds,
+//! or sends a frame larger than a hello before then, is closed.
+use actix::io::{FramedWrite, WriteHandler};
+use actix::prelude::*;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::{split, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, FramedRead, LinesCodec, LinesCodecError};
+use ulid::Ulid;
+
+use super::Outbound;
+use crate::config::ClusterConfig;
+use crate::message::{self, Delivery, SendTarget};
+
+/// How often seeds are resolved and members that aren't connected are dialed
+const DIAL_INTERVAL: Duration = Duration::from_secs(5);
+/// Upper bound on a single frame, which mostly consists of a forwarded message
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+/// Upper bound on a frame before the other node has proven the secret, which fits a hello
+const MAX_HANDSHAKE_FRAME_LENGTH: usize = 4 * 1024;
+/// How long the other node has to prove the secret before the connection is closed
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Signed into proofs, so that a dialing node's proof can't pass for an accepting node's
+const DIALER: &str = "dialer";
+const ACCEPTER: &str = "accepter";
+
+/// Frames exchanged between nodes
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Frame {
+    /// The first frame on every connection, sent by the node that accepted it
+    Challenge {
+        nonce: String,
+    },
+    /// Sent by the dialing node in response to the challenge, then by the accepting node once
+    /// it has verified it. The members and topics follow once both have been verified.
+    Hello {
+        node: String,
+        /// The address other nodes can reach this node on
+        advertise: String,
+        /// HMAC of the role, both nonces and this node's id, keyed with the secret
+        proof: String,
+        /// The dialing node's nonce, only sent by the dialing node
+        nonce: Option<String>,
+    },
+    Members {
+        members: Vec<String>,
+    },
+    Subscribe {
+        topic: String,
+    },
+    Unsubscribe {
+        topic: String,
+    },
+    Forward {
+        delivery: Delivery,
+    },
+    /// A direct message, sent to every peer
+    Send {
+        target: SendTarget,
+        delivery: Delivery,
+    },
+}
+
+struct Peer {
+    conn: Addr<Connection>,
+    advertise: String,
+    /// The node that opened the connection, used to settle which connection to keep when two
+    /// nodes dial each other at the same time
+    dialer: String,
+    topics: HashSet<String>,
+}
+
+pub struct ClusterBackplane {
+    node_id: String,
+    config: ClusterConfig,
+    server: Recipient<message::Relay>,
+    /// Topics with local subscribers
+    topics: HashSet<String>,
+    /// Advertised addresses of every member learned through gossip
+    members: HashSet<String>,
+    /// Connected peers by node id
+    peers: HashMap<String, Peer>,
+    /// Node ids of the addresses that have been dialed, so a node known under several addresses
+    /// is only dialed again if it isn't connected
+    dialed: HashMap<String, String>,
+    /// Addresses with a connection attempt in progress
+    dialing: HashSet<String>,
+    handshake_timeout: Duration,
+    /// Listener bound ahead of time, used instead of binding `config.bind`
+    listener: Option<std::net::TcpListener>,
+}
+
+impl ClusterBackplane {
+    pub fn new(config: ClusterConfig, server: Recipient<message::Relay>) -> Self {
+        ClusterBackplane {
+            node_id: Ulid::new().to_string(),
+            config,
+            server,
+            topics: HashSet::new(),
+            members: HashSet::new(),
+            peers: HashMap::new(),
+            dialed: HashMap::new(),
+            dialing: HashSet::new(),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            listener: None,
+        }
+    }
+
+    fn listen(&mut self, ctx: &mut Context<Self>) {
+        let bind = self.config.bind.clone();
+        let listener = self.listener.take();
+
+        async move {
+            match listener {
+                Some(listener) => {
+                    listener.set_nonblocking(true)?;
+                    TcpListener::from_std(listener)
+                }
+                None => TcpListener::bind(bind).await,
+            }
+        }
+        .into_actor(self)
+        .map(|res, act, ctx| match res {
+            Ok(listener) => {
+                log::info!("Cluster listening on {}", act.config.bind);
+                let addr = ctx.address();
+                actix::spawn(async move {
+                    loop {
+                        match listener.accept().await {
+                            Ok((stream, _)) => addr.do_send(Accepted(stream)),
+                            Err(e) => log::error!("Unable to accept cluster connection: {}", e),
+                        }
+                    }
+                });
+            }
+            Err(e) => log::error!("Unable to listen on {}: {}", act.config.bind, e),
+        })
+        .wait(ctx);
+    }
+
+    /// Resolve the seeds and dial every member that isn't connected yet
+    fn dial_members(&mut self, ctx: &mut Context<Self>) {
+        let dns = self.config.dns.clone();
+
+        async move {
+            let Some(dns) = dns else {
+                return Vec::new();
+            };
+            let resolved = match tokio::net::lookup_host(&dns).await {
+                Ok(addrs) => addrs.map(|addr| addr.to_string()).collect(),
+                Err(e) => {
+                    log::error!("Unable to resolve cluster DNS {}: {}", dns, e);
+                    Vec::new()
+                }
+            };
+            resolved
+        }
+        .into_actor(self)
+        .map(|resolved, act, ctx| {
+            let candidates: Vec<String> = act
+                .config
+                .peers
+                .iter()
+                .cloned()
+                .chain(resolved)
+                .chain(act.members.iter().cloned())
+                .collect();
+            for addr in candidates {
+                act.dial(addr, ctx);
+            }
+        })
+        .spawn(ctx);
+    }
+
+    fn dial(&mut self, addr: String, ctx: &mut Context<Self>) {
+        let connected = self.peers.values().any(|peer| peer.advertise == addr)
+            || self
+                .dialed
+                .get(&addr)
+                .is_some_and(|node| *node == self.node_id || self.peers.contains_key(node));
+        if addr == self.config.advertise || connected || !self.dialing.insert(addr.clone()) {
+            return;
+        }
+
+        let target = addr.clone();
+        async move { TcpStream::connect(target).await }
+            .into_actor(self)
+            .map(move |res, act, ctx| {
+                act.dialing.remove(&addr);
+                match res {
+                    Ok(stream) => act.open(stream, Some(addr), ctx),
+                    Err(e) => log::debug!("Unable to connect to cluster member {}: {}", addr, e),
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// Start a connection actor for the stream, which proves the secret to the other node
+    fn open(&mut self, stream: TcpStream, dialed: Option<String>, ctx: &mut Context<Self>) {
+        let cluster = ctx.address();
+        let handshake = Handshake {
+            secret: self.config.secret.clone(),
+            node: self.node_id.clone(),
+            advertise: self.config.advertise.clone(),
+            challenge: None,
+            response: None,
+        };
+        let timeout = self.handshake_timeout;
+        Connection::create(move |conn_ctx| {
+            let (read, write) = split(stream);
+            let authenticated = Rc::new(Cell::new(false));
+            conn_ctx.add_stream(FramedRead::new(
+                read,
+                FrameCodec {
+                    lines: LinesCodec::new_with_max_length(MAX_FRAME_LENGTH),
+                    authenticated: authenticated.clone(),
+                },
+            ));
+            Connection {
+                cluster,
+                writer: FramedWrite::new(write, LinesCodec::new(), conn_ctx),
+                dialed,
+                handshake,
+                authenticated,
+                timeout,
+            }
+        });
+    }
+
+    fn member_addresses(&self) -> Vec<String> {
+        self.peers
+            .values()
+            .map(|peer| peer.advertise.clone())
+            .collect()
+    }
+
+    /// Add the node as a peer, once the connection has verified its hello
+    fn handle_hello(&mut self, conn: Addr<Connection>, dialed: Option<String>, hello: Frame) {
+        let Frame::Hello {
+            node, advertise, ..
+        } = hello
+        else {
+            return;
+        };
+
+        if let Some(addr) = dialed.as_ref() {
+            self.dialed.insert(addr.clone(), node.clone());
+        }
+        if node == self.node_id {
+            // One of the seeds resolved to ourselves, the dialing side closes the connection
+            // once it learns that from the hello
+            if dialed.is_some() {
+                conn.do_send(Close);
+            }
+            return;
+        }
+
+        let dialer = match dialed {
+            Some(_) => self.node_id.clone(),
+            None => node.clone(),
+        };
+        if let Some(existing) = self.peers.get(&node) {
+            // Both nodes dialed each other, keep the connection opened by the lowest node id
+            let keep_new =
+                dialer != existing.dialer && dialer == *std::cmp::min(&self.node_id, &node);
+            if !keep_new {
+                conn.do_send(Close);
+                return;
+            }
+            existing.conn.do_send(Close);
+        }
+
+        log::info!("Cluster member {} ({}) connected", advertise, node);
+        send(
+            &conn,
+            &Frame::Members {
+                members: self.member_addresses(),
+            },
+        );
+        for topic in &self.topics {
+            send(
+                &conn,
+                &Frame::Subscribe {
+                    topic: topic.clone(),
+                },
+            );
+        }
+        let gossip = Frame::Members {
+            members: vec![advertise.clone()],
+        };
+        for (_, peer) in self.peers.iter().filter(|(id, _)| **id != node) {
+            send(&peer.conn, &gossip);
+        }
+        self.members.insert(advertise.clone());
+        self.peers.insert(
+            node,
+            Peer {
+                conn,
+                advertise,
+                dialer,
+                topics: HashSet::new(),
+            },
+        );
+    }
+
+    fn broadcast_frame(&self, frame: &Frame) {
+        for peer in self.peers.values() {
+            send(&peer.conn, frame);
+        }
+    }
+}
+
+fn nonce() -> String {
+    hex::encode(rand::rng().random::<[u8; 32]>())
+}
+
+fn mac(secret: &str, role: &str, challenge: &str, response: &str, node: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    for part in [role, challenge, response, node] {
+        mac.update(part.as_bytes());
+        mac.update(b":");
+    }
+    mac
+}
+
+fn prove(secret: &str, role: &str, challenge: &str, response: &str, node: &str) -> String {
+    hex::encode(
+        mac(secret, role, challenge, response, node)
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+/// Whether the proof was made with the secret, compared in constant time
+fn verify(
+    secret: &str,
+    role: &str,
+    challenge: &str,
+    response: &str,
+    node: &str,
+    proof: &str,
+) -> bool {
+    hex::decode(proof).is_ok_and(|proof| {
+        mac(secret, role, challenge, response, node)
+            .verify_slice(&proof)
+            .is_ok()
+    })
+}
+
+fn send(conn: &Addr<Connection>, frame: &Frame) {
+    match serde_json::to_string(frame) {
+        Ok(line) => conn.do_send(Line(line)),
+        Err(e) => log::error!("Unable to serialize cluster frame: {}", e),
+    }
+}
+
+impl Actor for ClusterBackplane {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.listen(ctx);
+        self.dial_members(ctx);
+        ctx.run_interval(DIAL_INTERVAL, |act, ctx| act.dial_members(ctx));
+    }
+}
+
+impl Handler<Outbound> for ClusterBackplane {
+    type Result = ();
+
+    fn handle(&mut self, msg: Outbound, _: &mut Context<Self>) {
+        match msg {
+            Outbound::Publish(delivery) => {
+                let Some(topic) = delivery.topic.clone() else {
+                    return;
+                };
+                let line = match serde_json::to_string(&Frame::Forward { delivery }) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        log::error!("Unable to serialize cluster frame: {}", e);
+                        return;
+                    }
+                };
+                for peer in self.peers.values() {
+                    if peer.topics.contains(&topic) {
+                        peer.conn.do_send(Line(line.clone()));
+                    }
+                }
+            }
+            Outbound::Subscribe(topic) => {
+                self.topics.insert(topic.clone());
+                self.broadcast_frame(&Frame::Subscribe { topic });
+            }
+            Outbound::Unsubscribe(topic) => {
+                self.topics.remove(&topic);
+                self.broadcast_frame(&Frame::Unsubscribe { topic });
+            }
+            Outbound::Send { target, delivery } => {
+                self.broadcast_frame(&Frame::Send { target, delivery });
+            }
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Accepted(TcpStream);
+
+impl Handler<Accepted> for ClusterBackplane {
+    type Result = ();
+
+    fn handle(&mut self, msg: Accepted, ctx: &mut Context<Self>) {
+        self.open(msg.0, None, ctx);
+    }
+}
+
+/// A frame received on a connection
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Incoming {
+    conn: Addr<Connection>,
+    /// The address the connection was dialed on, if this node opened it
+    dialed: Option<String>,
+    frame: Frame,
+}
+
+impl Handler<Incoming> for ClusterBackplane {
+    type Result = ();
+
+    fn handle(&mut self, msg: Incoming, ctx: &mut Context<Self>) {
+        if let Frame::Hello { .. } = msg.frame {
+            self.handle_hello(msg.conn, msg.dialed, msg.frame);
+            return;
+        }
+
+        let Some(peer) = self.peers.values_mut().find(|peer| peer.conn == msg.conn) else {
+            log::error!("Cluster frame received before hello, closing connection");
+            msg.conn.do_send(Close);
+            return;
+        };
+        match msg.frame {
+            Frame::Subscribe { topic } => {
+                peer.topics.insert(topic);
+            }
+            Frame::Unsubscribe { topic } => {
+                peer.topics.remove(&topic);
+            }
+            Frame::Forward { delivery } => {
+                self.server.do_send(message::Relay {
+                    delivery,
+                    target: None,
+                });
+            }
+            Frame::Send { target, delivery } => {
+                self.server.do_send(message::Relay {
+                    delivery,
+                    target: Some(target),
+                });
+            }
+            Frame::Members { members } => {
+                for addr in members {
+                    if self.members.insert(addr.clone()) {
+                        self.dial(addr, ctx);
+                    }
+                }
+            }
+            // Only passed on by the connection during the handshake
+            Frame::Hello { .. } | Frame::Challenge { .. } => {}
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Closed(Addr<Connection>);
+
+impl Handler<Closed> for ClusterBackplane {
+    type Result = ();
+
+    fn handle(&mut self, msg: Closed, _: &mut Context<Self>) {
+        self.peers.retain(|node, peer| {
+            let closed = peer.conn == msg.0;
+            if closed {
+                log::info!("Cluster member {} ({}) disconnected", peer.advertise, node);
+            }
+            !closed
+        });
+    }
+}
+
+/// Lines of up to `MAX_HANDSHAKE_FRAME_LENGTH` bytes until the other node has proven the secret,
+/// so that it can't make us buffer large frames before then
+struct FrameCodec {
+    lines: LinesCodec,
+    authenticated: Rc<Cell<bool>>,
+}
+
+impl FrameCodec {
+    fn check_length(&self, src: &BytesMut) -> Result<(), LinesCodecError> {
+        if self.authenticated.get() {
+            return Ok(());
+        }
+        let length = src.iter().position(|b| *b == b'\n').unwrap_or(src.len());
+        if length > MAX_HANDSHAKE_FRAME_LENGTH {
+            return Err(LinesCodecError::MaxLineLengthExceeded);
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = String;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        self.check_length(src)?;
+        self.lines.decode(src)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        self.check_length(src)?;
+        self.lines.decode_eof(src)
+    }
+}
+
+/// What a connection proves the secret with, and checks the other node's proof against
+struct Handshake {
+    secret: String,
+    node: String,
+    advertise: String,
+    /// The accepting node's nonce
+    challenge: Option<String>,
+    /// The dialing node's nonce
+    response: Option<String>,
+}
+
+/// A single connection to another node
+struct Connection {
+    cluster: Addr<ClusterBackplane>,
+    writer: FramedWrite<String, WriteHalf<TcpStream>, LinesCodec>,
+    dialed: Option<String>,
+    handshake: Handshake,
+    /// Set once the other node has proven the secret, shared with the codec
+    authenticated: Rc<Cell<bool>>,
+    timeout: Duration,
+}
+
+impl Connection {
+    fn send(&mut self, frame: &Frame) {
+        match serde_json::to_string(frame) {
+            Ok(line) => self.writer.write(line),
+            Err(e) => log::error!("Unable to serialize cluster frame: {}", e),
+        }
+    }
+
+    fn hello(&self, role: &str, nonce: Option<String>) -> Frame {
+        let handshake = &self.handshake;
+        Frame::Hello {
+            node: handshake.node.clone(),
+            advertise: handshake.advertise.clone(),
+            proof: prove(
+                &handshake.secret,
+                role,
+                handshake.challenge.as_deref().unwrap_or_default(),
+                handshake.response.as_deref().unwrap_or_default(),
+                &handshake.node,
+            ),
+            nonce,
+        }
+    }
+
+    /// Answer the challenge if we dialed, or verify the other node's hello, which is only
+    /// passed on to the cluster once verified
+    fn handshake(&mut self, frame: Frame, ctx: &mut Context<Self>) -> Result<(), String> {
+        match frame {
+            Frame::Challenge { nonce } if self.dialed.is_some() => {
+                if self.handshake.challenge.is_some() {
+                    return Err("Challenged twice".to_owned());
+                }
+                let response = self::nonce();
+                self.handshake.challenge = Some(nonce);
+                self.handshake.response = Some(response.clone());
+                let hello = self.hello(DIALER, Some(response));
+                self.send(&hello);
+                Ok(())
+            }
+            Frame::Hello {
+                node,
+                advertise,
+                proof,
+                nonce,
+            } => {
+                let (role, response) = match self.dialed {
+                    Some(_) => (ACCEPTER, self.handshake.response.clone()),
+                    None => (DIALER, nonce),
+                };
+                let (Some(challenge), Some(response)) =
+                    (self.handshake.challenge.as_deref(), response.as_deref())
+                else {
+                    return Err(format!("{} sent a hello out of turn", advertise));
+                };
+                if !verify(
+                    &self.handshake.secret,
+                    role,
+                    challenge,
+                    response,
+                    &node,
+                    &proof,
+                ) {
+                    return Err(format!("{} failed to prove the secret", advertise));
+                }
+                if self.dialed.is_none() {
+                    self.handshake.response = Some(response.to_owned());
+                    let hello = self.hello(ACCEPTER, None);
+                    self.send(&hello);
+                }
+                self.authenticated.set(true);
+
+                self.cluster.do_send(Incoming {
+                    conn: ctx.address(),
+                    dialed: self.dialed.clone(),
+                    frame: Frame::Hello {
+                        node,
+                        advertise,
+                        proof,
+                        nonce: None,
+                    },
+                });
+                Ok(())
+            }
+            _ => Err("Cluster frame received before the handshake".to_owned()),
+        }
+    }
+}
+
+impl Actor for Connection {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.dialed.is_none() {
+            let challenge = nonce();
+            self.handshake.challenge = Some(challenge.clone());
+            self.send(&Frame::Challenge { nonce: challenge });
+        }
+        ctx.run_later(self.timeout, |act, ctx| {
+            if !act.authenticated.get() {
+                log::error!("Cluster handshake timed out, closing connection");
+                ctx.stop();
+            }
+        });
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.cluster.do_send(Closed(ctx.address()));
+    }
+}
+
+impl WriteHandler<LinesCodecError> for Connection {}
+
+impl StreamHandler<Result<String, LinesCodecError>> for Connection {
+    fn handle(&mut self, line: Result<String, LinesCodecError>, ctx: &mut Self::Context) {
+        let frame = match line
+            .map_err(|e| e.to_string())
+            .and_then(|line| serde_json::from_str::<Frame>(&line).map_err(|e| e.to_string()))
+        {
+            Ok(frame) => frame,
+            Err(e) => {
+                log::error!("Invalid cluster frame, closing connection: {}", e);
+                ctx.stop();
+                return;
+            }
+        };
+
+        if !self.authenticated.get() {
+            if let Err(e) = self.handshake(frame, ctx) {
+                log::error!("Cluster handshake failed, closing connection: {}", e);
+                ctx.stop();
+            }
+            return;
+        }
+        if matches!(frame, Frame::Hello { .. } | Frame::Challenge { .. }) {
+            log::error!("Unexpected cluster handshake frame, closing connection");
+            ctx.stop();
+            return;
+        }
+        self.cluster.do_send(Incoming {
+            conn: ctx.address(),
+            dialed: self.dialed.clone(),
+            frame,
+        });
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Line(String);
+
+impl Handler<Line> for Connection {
+    type Result = ();
+
+    fn handle(&mut self, msg: Line, _: &mut Context<Self>) {
+        self.writer.write(msg.0);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Close;
+
+impl Handler<Close> for Connection {
+    type Result = ();
+
+    fn handle(&mut self, _: Close, ctx: &mut Context<Self>) {
+        ctx.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct Collector(Arc<Mutex<Vec<Delivery>>>);
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<message::Relay> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: message::Relay, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(msg.delivery);
+        }
+    }
+
+    /// A node listening on a port picked by the OS, and its address
+    fn start_node(
+        peers: &[&str],
+        secret: &str,
+    ) -> (Addr<ClusterBackplane>, String, Arc<Mutex<Vec<Delivery>>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let config = ClusterConfig {
+            bind: addr.clone(),
+            advertise: addr.clone(),
+            peers: peers.iter().map(|peer| peer.to_string()).collect(),
+            dns: None,
+            secret: secret.to_owned(),
+        };
+        let mut node =
+            ClusterBackplane::new(config, Collector(received.clone()).start().recipient());
+        node.listener = Some(listener);
+        node.handshake_timeout = Duration::from_millis(200);
+
+        (node.start(), addr, received)
+    }
+
+    #[actix::test]
+    async fn test_forward_between_nodes() {
+        // The second and third node only know about the first one, and find each other through
+        // gossip
+        let (_first, first_addr, first_received) = start_node(&[], "secret");
+        actix::clock::sleep(Duration::from_millis(50)).await;
+        let (second, _, _) = start_node(&[&first_addr], "secret");
+        let (third, _, third_received) = start_node(&[&first_addr], "secret");
+        actix::clock::sleep(Duration::from_millis(300)).await;
+
+        third
+            .send(Outbound::Subscribe("foo".to_owned()))
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(100)).await;
+        for topic in ["foo", "bar"] {
+            second
+                .send(Outbound::Publish(Delivery::new(Some(topic), "hello")))
+                .await
+                .unwrap();
+        }
+        actix::clock::sleep(Duration::from_millis(100)).await;
+
+        {
+            let received = third_received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0].topic.as_deref(), Some("foo"));
+            assert_eq!(received[0].payload, message::Payload::from("hello"));
+            // The first node has no subscribers, so nothing is forwarded to it
+            assert!(first_received.lock().unwrap().is_empty());
+        }
+
+        // Direct messages go to every node, whatever it subscribed to
+        second
+            .send(Outbound::Send {
+                target: message::SendTarget::Subject("alice".to_owned()),
+                delivery: Delivery::new(None, "direct"),
+            })
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(100)).await;
+        for received in [&first_received, &third_received] {
+            let received = received.lock().unwrap();
+            assert_eq!(
+                received.last().unwrap().payload,
+                message::Payload::from("direct")
+            );
+        }
+    }
+
+    #[actix::test]
+    async fn test_secret_proven_before_hello() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (node, addr, _) = start_node(&[], "secret");
+        let (intruder, _, intruder_received) = start_node(&[&addr], "wrong");
+        actix::clock::sleep(Duration::from_millis(200)).await;
+        node.send(Outbound::Publish(Delivery::new(Some("foo"), "hello")))
+            .await
+            .unwrap();
+        intruder
+            .send(Outbound::Subscribe("foo".to_owned()))
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(100)).await;
+        assert!(intruder_received.lock().unwrap().is_empty());
+
+        // Nothing but the challenge is sent to a node that hasn't proven the secret, and the
+        // secret itself is never sent
+        let stream = TcpStream::connect(&addr).await.unwrap();
+        let (read, mut write) = split(stream);
+        let mut lines = BufReader::new(read).lines();
+        let challenge = lines.next_line().await.unwrap().unwrap();
+        let Frame::Challenge { nonce } = serde_json::from_str(&challenge).unwrap() else {
+            panic!("Expected a challenge, got {}", challenge);
+        };
+        let hello = Frame::Hello {
+            node: "intruder".to_owned(),
+            advertise: "127.0.0.1:1".to_owned(),
+            proof: prove("wrong", DIALER, &nonce, "nonce", "intruder"),
+            nonce: Some("nonce".to_owned()),
+        };
+        let line = serde_json::to_string(&hello).unwrap() + "\n";
+        write.write_all(line.as_bytes()).await.unwrap();
+        assert_eq!(lines.next_line().await.unwrap(), None);
+    }
+
+    #[actix::test]
+    async fn test_unauthenticated_connections_closed() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (_node, addr, _) = start_node(&[], "secret");
+        actix::clock::sleep(Duration::from_millis(50)).await;
+        let connect = || async {
+            let stream = TcpStream::connect(&addr).await.unwrap();
+            let (read, write) = split(stream);
+            let mut lines = BufReader::new(read).lines();
+            let challenge = lines.next_line().await.unwrap().unwrap();
+            assert!(challenge.contains("challenge"));
+            (lines, write)
+        };
+
+        // A node that never proves the secret is dropped once the handshake times out
+        let (mut lines, _write) = connect().await;
+        let closed = actix::clock::timeout(Duration::from_secs(2), lines.next_line())
+            .await
+            .unwrap();
+        assert!(matches!(closed, Ok(None) | Err(_)));
+
+        // So is one sending more than a hello's worth before proving it
+        let (mut lines, mut write) = connect().await;
+        let garbage = vec![b'x'; MAX_HANDSHAKE_FRAME_LENGTH + 1];
+        let _ = write.write_all(&garbage).await;
+        let closed = actix::clock::timeout(Duration::from_millis(100), lines.next_line())
+            .await
+            .unwrap();
+        assert!(matches!(closed, Ok(None) | Err(_)));
+    }
+
+    #[test]
+    fn test_proof_bound_to_role_and_nonces() {
+        let proof = prove("secret", DIALER, "challenge", "response", "node");
+        assert!(verify(
+            "secret",
+            DIALER,
+            "challenge",
+            "response",
+            "node",
+            &proof
+        ));
+        for (secret, role, challenge, response, node) in [
+            ("wrong", DIALER, "challenge", "response", "node"),
+            ("secret", ACCEPTER, "challenge", "response", "node"),
+            ("secret", DIALER, "response", "challenge", "node"),
+            ("secret", DIALER, "challenge", "other", "node"),
+            ("secret", DIALER, "challenge", "response", "other"),
+        ] {
+            assert!(!verify(secret, role, challenge, response, node, &proof));
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Redis pub/sub backplane, where every topic maps to a Redis channel. An instance only
+//! subscribes to the channels of topics that it has local subscribers for.
+use actix::prelude::*;
+use redis::aio::{MultiplexedConnection, PubSubSink};
+use redis::AsyncCommands;
+use std::collections::HashSet;
+use std::time::Duration;
+use ulid::Ulid;
+
+use super::{Envelope, Outbound};
+use crate::message;
+
+const CHANNEL_PREFIX: &str = "notiflux:";
+/// Channel of direct messages, which every instance subscribes to. It doesn't have the prefix
+/// of topics so that it can't clash with one.
+const DIRECT_CHANNEL: &str = "notiflux-direct";
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+pub struct RedisBackplane {
+    client: redis::Client,
+    /// Identifies this instance, to skip its own messages when they come back from Redis
+    node_id: String,
+    server: Recipient<message::Relay>,
+    publisher: Option<MultiplexedConnection>,
+    subscriber: Option<PubSubSink>,
+    /// Topics with local subscribers, resubscribed to when reconnecting
+    topics: HashSet<String>,
+}
+
+impl RedisBackplane {
+    pub fn new(url: &str, server: Recipient<message::Relay>) -> redis::RedisResult<Self> {
+        Ok(RedisBackplane {
+            client: redis::Client::open(url)?,
+            node_id: Ulid::new().to_string(),
+            server,
+            publisher: None,
+            subscriber: None,
+            topics: HashSet::new(),
+        })
+    }
+
+    fn connect(&mut self, ctx: &mut Context<Self>) {
+        let client = self.client.clone();
+        let channels: Vec<String> = self
+            .topics
+            .iter()
+            .map(|t| channel(t))
+            .chain([DIRECT_CHANNEL.to_owned()])
+            .collect();
+
+        async move {
+            let publisher = client.get_multiplexed_async_connection().await?;
+            let (mut subscriber, stream) = client.get_async_pubsub().await?.split();
+            subscriber.subscribe(channels).await?;
+            Ok::<_, redis::RedisError>((publisher, subscriber, stream))
+        }
+        .into_actor(self)
+        .map(|res, act, ctx| match res {
+            Ok((publisher, subscriber, stream)) => {
+                log::info!("Connected to Redis backplane");
+                act.publisher = Some(publisher);
+                act.subscriber = Some(subscriber);
+                ctx.add_stream(stream);
+            }
+            Err(e) => {
+                log::error!("Unable to connect to Redis backplane: {}", e);
+                ctx.run_later(RECONNECT_DELAY, |act, ctx| act.connect(ctx));
+            }
+        })
+        // Hold back outbound messages until connected, so that no subscriptions are lost
+        .wait(ctx);
+    }
+
+    fn publish(
+        &mut self,
+        delivery: message::Delivery,
+        target: Option<message::SendTarget>,
+        ctx: &mut Context<Self>,
+    ) {
+        let Some(publisher) = self.publisher.clone() else {
+            log::warn!("Not connected to Redis backplane, message not published");
+            return;
+        };
+        let channel = match (&target, &delivery.topic) {
+            (Some(_), _) => DIRECT_CHANNEL.to_owned(),
+            (None, Some(topic)) => channel(topic),
+            (None, None) => return,
+        };
+        let envelope = Envelope {
+            origin: self.node_id.clone(),
+            delivery,
+            target,
+        };
+        let payload = match serde_json::to_string(&envelope) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::error!("Unable to serialize message for Redis backplane: {}", e);
+                return;
+            }
+        };
+
+        ctx.spawn(
+            async move {
+                let mut publisher = publisher;
+                publisher.publish::<_, _, ()>(channel, payload).await
+            }
+            .into_actor(self)
+            .map(|res, _, _| {
+                if let Err(e) = res {
+                    log::error!("Unable to publish to Redis backplane: {}", e);
+                }
+            }),
+        );
+    }
+
+    fn update_subscription(&mut self, topic: String, subscribe: bool, ctx: &mut Context<Self>) {
+        if subscribe {
+            self.topics.insert(topic.clone());
+        } else {
+            self.topics.remove(&topic);
+        }
+        let Some(mut subscriber) = self.subscriber.clone() else {
+            // Picked up from `topics` once connected
+            return;
+        };
+
+        ctx.spawn(
+            async move {
+                if subscribe {
+                    subscriber.subscribe(channel(&topic)).await
+                } else {
+                    subscriber.unsubscribe(channel(&topic)).await
+                }
+            }
+            .into_actor(self)
+            .map(|res, _, _| {
+                if let Err(e) = res {
+                    log::error!("Unable to update Redis backplane subscription: {}", e);
+                }
+            }),
+        );
+    }
+}
+
+fn channel(topic: &str) -> String {
+    format!("{}{}", CHANNEL_PREFIX, topic)
+}
+
+impl Actor for RedisBackplane {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connect(ctx);
+    }
+}
+
+impl Handler<Outbound> for RedisBackplane {
+    type Result = ();
+
+    fn handle(&mut self, msg: Outbound, ctx: &mut Context<Self>) {
+        match msg {
+            Outbound::Publish(delivery) => self.publish(delivery, None, ctx),
+            Outbound::Subscribe(topic) => self.update_subscription(topic, true, ctx),
+            Outbound::Unsubscribe(topic) => self.update_subscription(topic, false, ctx),
+            Outbound::Send { target, delivery } => self.publish(delivery, Some(target), ctx),
+        }
+    }
+}
+
+impl StreamHandler<redis::Msg> for RedisBackplane {
+    fn handle(&mut self, msg: redis::Msg, _: &mut Context<Self>) {
+        let envelope = match serde_json::from_slice::<Envelope>(msg.get_payload_bytes()) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                log::error!(
+                    "Invalid message on Redis channel {}: {}",
+                    msg.get_channel_name(),
+                    e
+                );
+                return;
+            }
+        };
+        if envelope.origin == self.node_id {
+            return;
+        }
+
+        self.server.do_send(message::Relay {
+            delivery: envelope.delivery,
+            target: envelope.target,
+        });
+    }
+
+    fn finished(&mut self, ctx: &mut Context<Self>) {
+        log::error!("Lost connection to Redis backplane, reconnecting");
+        self.publisher = None;
+        self.subscriber = None;
+        ctx.run_later(RECONNECT_DELAY, |act, ctx| act.connect(ctx));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct Collector(Arc<Mutex<Vec<message::Delivery>>>);
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<message::Relay> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: message::Relay, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(msg.delivery);
+        }
+    }
+
+    #[actix::test]
+    #[ignore = "needs a Redis server at REDIS_TEST_URL, run by CI with --ignored"]
+    async fn test_relay_between_instances() {
+        let url =
+            std::env::var("REDIS_TEST_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_owned());
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let relayed_to = Collector(received.clone()).start();
+        let subscriber = RedisBackplane::new(&url, relayed_to.recipient())
+            .unwrap()
+            .start();
+        let publisher =
+            RedisBackplane::new(&url, Collector(Default::default()).start().recipient())
+                .unwrap()
+                .start();
+
+        subscriber
+            .send(Outbound::Subscribe("foo".to_owned()))
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(100)).await;
+        for topic in ["foo", "bar"] {
+            publisher
+                .send(Outbound::Publish(message::Delivery::new(
+                    Some(topic),
+                    "hello",
+                )))
+                .await
+                .unwrap();
+        }
+        actix::clock::sleep(Duration::from_millis(100)).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].topic.as_deref(), Some("foo"));
+        assert_eq!(received[0].payload, message::Payload::from("hello"));
+    }
+}
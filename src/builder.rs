@@ -0,0 +1,525 @@
+//! Embedding notiflux in another actix-web app, with its routes mounted under a scope of the
+//! host app and a handle to publish messages from it.
+use actix::prelude::*;
+use actix_web::{web, Scope};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::app::Draining;
+use crate::auth::{self, Authorizer, JwtAuthorizer};
+#[cfg(feature = "redis")]
+use crate::backplane;
+use crate::backplane::{cluster::ClusterBackplane, Outbound};
+use crate::config::{self, Config};
+#[cfg(any(feature = "nats", feature = "kafka"))]
+use crate::ingest;
+use crate::limits::Limiter;
+use crate::{app, message, server, webhook, NotifluxError};
+
+pub struct NotifluxBuilder {
+    config: Config,
+    authorizer: Option<Arc<dyn Authorizer>>,
+}
+
+impl NotifluxBuilder {
+    pub fn new(config: Config) -> NotifluxBuilder {
+        NotifluxBuilder {
+            config,
+            authorizer: None,
+        }
+    }
+
+    /// Authorise clients with something other than the JWTs signed by the configured public
+    /// key and the client certificate topics
+    pub fn authorizer(mut self, authorizer: impl Authorizer + 'static) -> Self {
+        self.authorizer = Some(Arc::new(authorizer));
+        self
+    }
+
+    /// Start the server, with the backplane, ingest connectors and webhook of the config. Needs
+    /// to be called from a running actix system. The host, port, worker count and TLS
+    /// certificates are left to the host app.
+    pub fn build(self) -> Result<Notiflux, NotifluxError> {
+        let config = self.config;
+
+        let authorizer: Arc<dyn Authorizer> = match self.authorizer {
+            Some(authorizer) => authorizer,
+            None => {
+                let certificate_topics =
+                    match config.tls.as_ref().and_then(|tls| tls.client_auth.as_ref()) {
+                        Some(client_auth) => {
+                            auth::CertificateTopics::load(&client_auth.topics_path)?
+                        }
+                        None => auth::CertificateTopics::default(),
+                    };
+                Arc::new(
+                    JwtAuthorizer::new(&config.jwt_public_key)
+                        .with_certificate_topics(certificate_topics),
+                )
+            }
+        };
+
+        let limiter = Arc::new(Limiter::new(
+            config.limits.clone(),
+            config.rate_limits.clone(),
+        ));
+
+        let server = server::Server::create(|ctx| {
+            let mut server = server::Server::new(&config.jwt_public_key);
+            server.set_authorizer(authorizer.clone());
+            server.set_limiter(limiter.clone());
+            if let Some(cluster) = &config.cluster {
+                log::info!(
+                    "Using native clustering, advertised as {}",
+                    cluster.advertise
+                );
+                let backplane = ClusterBackplane::new(cluster.clone(), ctx.address().recipient());
+                server.set_backplane(backplane.start().recipient());
+            } else if let Some(backplane) = config
+                .redis_url
+                .as_deref()
+                .and_then(|url| redis_backplane(url, ctx.address()))
+            {
+                server.set_backplane(backplane);
+            }
+            if let Some(webhook) = &config.webhook {
+                log::info!("Sending subscription events to webhook {}", webhook.url);
+                server.set_webhook(webhook::Webhook::new(webhook.clone()).start().recipient());
+            }
+            if let Some(nats) = &config.nats {
+                start_nats_ingest(nats, ctx.address());
+            }
+            if let Some(kafka) = &config.kafka {
+                start_kafka_ingest(kafka, ctx.address());
+            }
+            server
+        });
+
+        Ok(Notiflux {
+            server,
+            authorizer: web::Data::from(authorizer),
+            draining: web::Data::new(Draining::default()),
+            shutdown: config.shutdown,
+            heartbeat: web::Data::new(config.heartbeat),
+            limiter: web::Data::from(limiter),
+        })
+    }
+}
+
+/// A running notiflux server, which is cheap to clone into every worker of the host app
+#[derive(Clone)]
+pub struct Notiflux {
+    pub(crate) server: Addr<server::Server>,
+    pub(crate) authorizer: web::Data<dyn Authorizer>,
+    pub(crate) draining: web::Data<Draining>,
+    pub(crate) heartbeat: web::Data<config::HeartbeatConfig>,
+    pub(crate) limiter: web::Data<Limiter>,
+    shutdown: config::ShutdownConfig,
+}
+
+impl Notiflux {
+    /// The notiflux routes, such as `/ws` and `/broadcast`, under the given path
+    pub fn scope(&self, path: &str) -> Scope {
+        app::scope(path, self)
+    }
+
+    pub fn handle(&self) -> NotifluxHandle {
+        NotifluxHandle {
+            server: self.server.clone(),
+            draining: self.draining.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+}
+
+/// Publishes messages from the host app, which is trusted and doesn't need a token
+#[derive(Clone)]
+pub struct NotifluxHandle {
+    server: Addr<server::Server>,
+    draining: web::Data<Draining>,
+    shutdown: config::ShutdownConfig,
+}
+
+impl NotifluxHandle {
+    /// Broadcast a message to the subscribers of a topic, including those on other instances
+    /// when there's a backplane
+    pub fn publish(&self, topic: &str, message: &str) {
+        self.server.do_send(message::Publish {
+            payload: message.into(),
+            topic: topic.to_owned(),
+            retain: None,
+        });
+    }
+
+    /// Broadcast a binary message, delivered only to the sessions that asked for them
+    pub fn publish_binary(&self, topic: &str, message: &[u8]) {
+        self.server.do_send(message::Publish {
+            payload: message::Payload::Binary(message.to_vec()),
+            topic: topic.to_owned(),
+            retain: None,
+        });
+    }
+
+    /// Refuse new WebSocket connections and broadcasts, and close the open connections with a
+    /// going away frame once the broadcasts in flight are delivered. The frame suggests a
+    /// jittered delay before reconnecting. Waits for the sessions to close, up to the drain
+    /// period, before the host app stops its HTTP server.
+    pub async fn shutdown(&self) {
+        self.draining.start();
+        let deadline = Instant::now() + self.shutdown.drain;
+        // HTTP broadcasts already authorised reach the server before the shutdown does
+        while self.draining.in_flight() > 0 && Instant::now() < deadline {
+            actix::clock::sleep(Duration::from_millis(10)).await;
+        }
+        let sessions = self
+            .server
+            .send(message::Shutdown {
+                reconnect_delay: self.shutdown.reconnect_delay,
+            })
+            .await
+            .unwrap_or_default();
+        log::info!("Closing {} session(s)", sessions);
+
+        while Instant::now() < deadline {
+            match self.server.send(message::SessionCount).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => actix::clock::sleep(Duration::from_millis(100)).await,
+            }
+        }
+        log::warn!("Sessions still open after the drain period");
+    }
+}
+
+#[cfg(feature = "redis")]
+fn redis_backplane(url: &str, addr: Addr<server::Server>) -> Option<Recipient<Outbound>> {
+    match backplane::redis::RedisBackplane::new(url, addr.recipient()) {
+        Ok(backplane) => {
+            log::info!("Using Redis backplane");
+            Some(backplane.start().recipient())
+        }
+        Err(e) => {
+            log::error!("Invalid REDIS_URL, not using the Redis backplane: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+fn redis_backplane(_: &str, _: Addr<server::Server>) -> Option<Recipient<Outbound>> {
+    log::warn!("REDIS_URL is set, but notiflux was built without the redis feature");
+    None
+}
+
+#[cfg(feature = "nats")]
+fn start_nats_ingest(config: &config::NatsConfig, addr: Addr<server::Server>) {
+    ingest::nats::NatsIngest::new(&config.url, config.rules.clone(), addr.recipient()).start();
+}
+
+#[cfg(not(feature = "nats"))]
+fn start_nats_ingest(_: &config::NatsConfig, _: Addr<server::Server>) {
+    log::warn!("NATS_URL is set, but notiflux was built without the nats feature");
+}
+
+#[cfg(feature = "kafka")]
+fn start_kafka_ingest(config: &config::KafkaConfig, addr: Addr<server::Server>) {
+    if let Err(e) = ingest::kafka::start(
+        &config.brokers,
+        &config.group_id,
+        config.rules.clone(),
+        addr.recipient(),
+    ) {
+        log::error!("Unable to start Kafka ingest: {}", e);
+    }
+}
+
+#[cfg(not(feature = "kafka"))]
+fn start_kafka_ingest(_: &config::KafkaConfig, _: Addr<server::Server>) {
+    log::warn!("KAFKA_BROKERS is set, but notiflux was built without the kafka feature");
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    //! Serving notiflux in a host app, and talking to it over WebSockets, for end-to-end tests
+    use super::*;
+    use crate::auth::test_utils::sign_token;
+    use actix_web::{App, HttpResponse, HttpServer};
+    use futures_util::{SinkExt, StreamExt};
+    use std::net::SocketAddr;
+    use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+
+    pub type WsStream = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    /// A config with the test key pair from ./scripts
+    pub fn config() -> Config {
+        Config::new(include_bytes!("../scripts/public_key.pem"))
+    }
+
+    /// Serve notiflux under `/notiflux` of a host app, returning its address
+    pub fn start_host(notiflux: Notiflux) -> SocketAddr {
+        let server = HttpServer::new(move || {
+            App::new()
+                .service(notiflux.scope("/notiflux"))
+                .route("/", web::get().to(HttpResponse::Ok))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix::spawn(server.run());
+        addr
+    }
+
+    /// Build notiflux with the config and serve it, returning its address and handle
+    pub fn serve(config: Config) -> (SocketAddr, NotifluxHandle) {
+        let notiflux = NotifluxBuilder::new(config).build().unwrap();
+        let handle = notiflux.handle();
+        (start_host(notiflux), handle)
+    }
+
+    /// The HTTP URL of a notiflux route
+    pub fn url(addr: SocketAddr, path: &str) -> String {
+        format!("http://{}/notiflux{}", addr, path)
+    }
+
+    /// Open a WebSocket connection, with the query string of the upgrade
+    pub async fn connect(addr: SocketAddr, query: &str) -> WsStream {
+        let url = format!("ws://{}/notiflux/ws{}", addr, query);
+        let (stream, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        stream
+    }
+
+    pub async fn send(stream: &mut WsStream, text: &str) {
+        stream.send(tungstenite::Message::text(text)).await.unwrap();
+    }
+
+    /// Subscribe to a topic with a valid token, and give the server time to handle it
+    pub async fn subscribe(stream: &mut WsStream, topic: &str) {
+        let token = sign_token("subscribe", &[topic]);
+        send(stream, &format!("/subscribe {} {}", topic, token)).await;
+        actix::clock::sleep(Duration::from_millis(50)).await;
+    }
+
+    /// The next frame, failing the test if none arrives in time
+    pub async fn next(stream: &mut WsStream) -> tungstenite::Message {
+        actix::clock::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("Timed out waiting for a frame")
+            .unwrap()
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_utils::*;
+    use super::*;
+    use crate::auth::{Credential, Decision, Grant, Operation};
+    use futures_util::future::{self, BoxFuture};
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite;
+
+    #[actix::test]
+    async fn test_scope_mounted_under_path() {
+        let (addr, _) = serve(config());
+
+        let get = |path: &str| reqwest::get(format!("http://{}{}", addr, path));
+        assert!(get("/").await.unwrap().status().is_success());
+        assert!(get("/notiflux/health").await.unwrap().status().is_success());
+        assert_eq!(get("/health").await.unwrap().status(), 404);
+    }
+
+    #[actix::test]
+    async fn test_handle_publishes_without_token() {
+        let (addr, handle) = serve(config());
+        let mut stream = connect(addr, "").await;
+        subscribe(&mut stream, "foo").await;
+
+        handle.publish("foo", "hello");
+
+        assert_eq!(next(&mut stream).await, tungstenite::Message::text("hello"));
+    }
+
+    #[actix::test]
+    async fn test_built_from_exported_config() {
+        // Only the types exported at the crate root, as an embedding app would
+        use crate::{
+            Config, HeartbeatConfig, LimitsConfig, Rate, RateLimitsConfig, ShutdownConfig,
+        };
+
+        let mut config = Config::new(include_bytes!("../scripts/public_key.pem"));
+        config.shutdown = ShutdownConfig {
+            drain: Duration::from_secs(1),
+            ..config.shutdown
+        };
+        config.heartbeat = HeartbeatConfig {
+            interval: Duration::from_secs(10),
+            ..config.heartbeat
+        };
+        config.limits = LimitsConfig {
+            max_connections: Some(100),
+            max_topics_per_session: Some(10),
+            ..LimitsConfig::default()
+        };
+        config.rate_limits = RateLimitsConfig {
+            per_ip: Some("20/s".parse().unwrap()),
+            per_topic: Some(Rate {
+                requests: 100,
+                period: Duration::from_secs(60),
+            }),
+            ..RateLimitsConfig::default()
+        };
+        let (addr, handle) = serve(config);
+        let mut stream = connect(addr, "").await;
+        subscribe(&mut stream, "foo").await;
+
+        handle.publish("foo", "hello");
+
+        assert_eq!(next(&mut stream).await, tungstenite::Message::text("hello"));
+    }
+
+    /// Allows anything on a single topic, whatever the credential
+    struct OpenTopic(&'static str);
+
+    impl Authorizer for OpenTopic {
+        fn authorize(
+            &self,
+            _: &Credential,
+            _: Operation,
+            topic: &str,
+        ) -> BoxFuture<'static, Decision> {
+            let decision = if topic == self.0 {
+                Decision::Allow(Grant::default())
+            } else {
+                Decision::Deny(format!("{} is closed", topic))
+            };
+            Box::pin(future::ready(decision))
+        }
+    }
+
+    #[actix::test]
+    async fn test_custom_authorizer() {
+        let notiflux = NotifluxBuilder::new(config())
+            .authorizer(OpenTopic("open"))
+            .build()
+            .unwrap();
+        let addr = start_host(notiflux);
+
+        let mut stream = connect(addr, "").await;
+        send(&mut stream, "/subscribe open anything").await;
+        actix::clock::sleep(Duration::from_millis(50)).await;
+
+        let broadcast = |topic: &str| {
+            reqwest::Client::new()
+                .post(url(addr, "/broadcast"))
+                .json(&serde_json::json!({"topic": topic, "message": "hello", "token": "anything"}))
+                .send()
+        };
+        let response = broadcast("closed").await.unwrap();
+        assert_eq!(response.status(), 403);
+        assert_eq!(
+            response.json::<serde_json::Value>().await.unwrap()["error"],
+            "closed is closed"
+        );
+        assert!(broadcast("open").await.unwrap().status().is_success());
+
+        assert_eq!(next(&mut stream).await, tungstenite::Message::text("hello"));
+    }
+
+    #[actix::test]
+    async fn test_shutdown_closes_sessions() {
+        let mut config = config();
+        config.shutdown.reconnect_delay = Duration::from_millis(500);
+        let (addr, handle) = serve(config);
+
+        let mut stream = connect(addr, "").await;
+        actix::clock::timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .unwrap();
+
+        let frame = match stream.next().await {
+            Some(Ok(tungstenite::Message::Close(Some(frame)))) => frame,
+            message => panic!("Expected a close frame, got {:?}", message),
+        };
+        assert_eq!(
+            frame.code,
+            tungstenite::protocol::frame::coding::CloseCode::Away
+        );
+        let reason: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert!(reason["reconnect_after_ms"].as_u64().unwrap() <= 500);
+
+        match tokio_tungstenite::connect_async(format!("ws://{}/notiflux/ws", addr)).await {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 503),
+            result => panic!(
+                "Expected the upgrade to be refused, got {:?}",
+                result.map(|_| ())
+            ),
+        }
+    }
+
+    /// Allows anything, taking a while to authorise broadcasts
+    struct SlowBroadcasts;
+
+    impl Authorizer for SlowBroadcasts {
+        fn authorize(
+            &self,
+            _: &Credential,
+            operation: Operation,
+            _: &str,
+        ) -> BoxFuture<'static, Decision> {
+            Box::pin(async move {
+                if operation == Operation::Broadcast {
+                    actix::clock::sleep(Duration::from_millis(200)).await;
+                }
+                Decision::Allow(Grant::default())
+            })
+        }
+    }
+
+    #[actix::test]
+    async fn test_shutdown_delivers_broadcasts_in_flight() {
+        let notiflux = NotifluxBuilder::new(config())
+            .authorizer(SlowBroadcasts)
+            .build()
+            .unwrap();
+        let handle = notiflux.handle();
+        let addr = start_host(notiflux);
+
+        let mut subscriber = connect(addr, "").await;
+        send(&mut subscriber, "/subscribe news anything").await;
+        let mut publisher = connect(addr, "").await;
+        actix::clock::sleep(Duration::from_millis(50)).await;
+
+        let broadcast = |message: &str| {
+            reqwest::Client::new()
+                .post(url(addr, "/broadcast"))
+                .json(
+                    &serde_json::json!({"topic": "news", "message": message, "token": "anything"}),
+                )
+                .send()
+        };
+        // Still being authorised when the shutdown starts, the session's one finishing last
+        let http = actix::spawn(broadcast("from http"));
+        actix::clock::sleep(Duration::from_millis(100)).await;
+        send(&mut publisher, "/publish news anything from ws").await;
+        handle.publish("news", "from handle");
+        actix::clock::sleep(Duration::from_millis(20)).await;
+
+        actix::clock::timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .unwrap();
+        assert!(http.await.unwrap().unwrap().status().is_success());
+
+        let mut delivered = Vec::new();
+        loop {
+            match next(&mut subscriber).await {
+                tungstenite::Message::Text(text) => delivered.push(text.to_string()),
+                tungstenite::Message::Close(_) => break,
+                message => panic!("Unexpected frame {:?}", message),
+            }
+        }
+        delivered.sort();
+        assert_eq!(delivered, ["from handle", "from http", "from ws"]);
+
+        assert_eq!(broadcast("too late").await.unwrap().status(), 503);
+    }
+}
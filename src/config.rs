@@ -1,7 +1,13 @@
 use base64::prelude::*;
+use serde_json::Value;
+use std::collections::HashMap;
 use std::env;
-use std::sync::OnceLock;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
+use crate::ingest::{parse_rules, Rule};
 use crate::{NotifluxError, NotifluxErrorType};
 
 #[derive(Debug, Clone)]
@@ -10,97 +16,1128 @@ pub struct Config {
     pub host: String,
     pub port: u16,
     pub worker_count: usize,
+    /// Serve HTTPS and WSS directly, enabled by setting `TLS_CERT_PATH` and `TLS_KEY_PATH`
+    pub tls: Option<TlsConfig>,
+    /// Connect instances through a Redis backplane, requires the `redis` feature
+    pub redis_url: Option<String>,
+    /// Connect instances directly to each other, enabled by setting `CLUSTER_BIND`
+    pub cluster: Option<ClusterConfig>,
+    /// Ingest messages from NATS, requires the `nats` feature
+    pub nats: Option<NatsConfig>,
+    /// Ingest messages from Kafka, requires the `kafka` feature
+    pub kafka: Option<KafkaConfig>,
+    /// Call a webhook on subscription lifecycle events, enabled by setting `WEBHOOK_URL`
+    pub webhook: Option<WebhookConfig>,
+    pub shutdown: ShutdownConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub limits: LimitsConfig,
+    pub rate_limits: RateLimitsConfig,
+}
+
+/// How sessions are closed when the server shuts down
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownConfig {
+    /// How long to wait for sessions to close before exiting
+    pub drain: Duration,
+    /// Clients are told to wait a random delay up to this long before reconnecting, so they
+    /// don't all reconnect at once
+    pub reconnect_delay: Duration,
+}
+
+/// How often sessions are pinged, and how long they can stay silent before being disconnected.
+/// Clients can ask for their own values when connecting, within the bounds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub client_timeout: Duration,
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+    pub max_client_timeout: Duration,
+}
+
+impl HeartbeatConfig {
+    /// The interval and timeout for a session, from the seconds the client asked for. The
+    /// interval is kept within the bounds, and the timeout between twice the interval and the
+    /// maximum timeout, with twice the interval being the default when only that is given.
+    pub fn negotiate(&self, interval: Option<u64>, timeout: Option<u64>) -> (Duration, Duration) {
+        if interval.is_none() && timeout.is_none() {
+            return (self.interval, self.client_timeout);
+        }
+
+        let interval = interval
+            .map(Duration::from_secs)
+            .unwrap_or(self.interval)
+            .max(self.min_interval)
+            .min(self.max_interval);
+        let timeout = timeout
+            .map(Duration::from_secs)
+            .unwrap_or(interval * 2)
+            .min(self.max_client_timeout)
+            .max(interval * 2);
+
+        (interval, timeout)
+    }
+}
+
+/// Bounds on connections, subscriptions and what clients send, each unlimited when not set.
+/// The sizes have defaults when loaded from settings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LimitsConfig {
+    /// Take the client address for the per address limits from the `Forwarded` or
+    /// `X-Forwarded-For` header, which is only safe behind a proxy that sets it
+    pub trust_proxy: bool,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// Counts the sessions that have subscribed with a token for the subject
+    pub max_connections_per_sub: Option<usize>,
+    pub max_topics_per_session: Option<usize>,
+    pub max_subscribers_per_topic: Option<usize>,
+    /// Bytes of a broadcast or direct message
+    pub max_message_size: Option<usize>,
+    /// Bytes of a WebSocket frame from a client, the default of actix-web when not set
+    pub max_frame_size: Option<usize>,
+    /// Bytes of a topic name, which can't have control characters either way. Topics with
+    /// whitespace can only be subscribed to over the structured subprotocols.
+    pub max_topic_length: Option<usize>,
+    /// Only allow ASCII letters, digits and `-_.:@` in topic names
+    pub strict_topics: bool,
+}
+
+/// Token bucket rate limits, each unlimited when not set
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimitsConfig {
+    /// Broadcasts over HTTP and commands over WebSocket from one address
+    pub per_ip: Option<Rate>,
+    /// Broadcasts and subscriptions with tokens for one subject
+    pub per_sub: Option<Rate>,
+    /// Broadcasts to one topic
+    pub per_topic: Option<Rate>,
+}
+
+/// A number of requests per period, such as `20/s` or `600/m`, which can all be made at once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("expected a rate such as 20/s, 600/m or 1000/h, not {}", s);
+        let (requests, period) = s.trim().split_once('/').ok_or_else(error)?;
+        let requests = requests.trim().parse().map_err(|_| error())?;
+        let period = match period.trim() {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(3600),
+            _ => return Err(error()),
+        };
+        if requests == 0 {
+            return Err("the number of requests needs to be at least 1".to_string());
+        }
+
+        Ok(Rate { requests, period })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, starting with the server's certificate
+    pub cert_path: PathBuf,
+    /// PEM file with the private key
+    pub key_path: PathBuf,
+    /// Accept client certificates, enabled by setting `TLS_CLIENT_CA_PATH`
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientAuthConfig {
+    /// PEM file with the CA certificates that client certificates must be signed by
+    pub ca_path: PathBuf,
+    /// TOML file mapping the subject alternative names of client certificates to the topic
+    /// patterns they may broadcast to
+    pub topics_path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterConfig {
+    /// Address to listen for other cluster members on
+    pub bind: String,
+    /// Address other members can reach this instance on, defaults to `bind`
+    pub advertise: String,
+    /// Static list of members to connect to
+    pub peers: Vec<String>,
+    /// A `host:port` that resolves to the addresses of members
+    pub dns: Option<String>,
+    /// Shared secret that members need to prove they know to join the cluster
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NatsConfig {
+    pub url: String,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaConfig {
+    pub brokers: String,
+    pub group_id: String,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Secret used to sign the requests, they're sent unsigned without one
+    pub secret: Option<String>,
+    /// Whether to also send events when sessions connect and disconnect
+    pub session_events: bool,
+    /// How many times to try delivering an event before giving up on it
+    pub max_attempts: u32,
 }
 
 const DEFAULT_PORT: u16 = 8080;
 const DEFAULT_HOST: &str = "127.0.0.1";
 const DEFAULT_WORKER_COUNT: usize = 4;
+const DEFAULT_KAFKA_GROUP_ID: &str = "notiflux";
+const DEFAULT_WEBHOOK_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_SHUTDOWN_DRAIN_SECS: u64 = 10;
+const DEFAULT_SHUTDOWN_RECONNECT_DELAY_SECS: u64 = 5;
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 15;
+const DEFAULT_CLIENT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_HEARTBEAT_INTERVAL_MIN_SECS: u64 = 5;
+const DEFAULT_HEARTBEAT_INTERVAL_MAX_SECS: u64 = 120;
+const DEFAULT_CLIENT_TIMEOUT_MAX_SECS: u64 = 300;
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_TOPIC_LENGTH: usize = 256;
 
-static CONFIG: OnceLock<Config> = OnceLock::new();
+/// Env var with the path of the config file, when it isn't passed with `--config`
+pub const CONFIG_PATH_VAR: &str = "NOTIFLUX_CONFIG";
+
+/// Every setting, named by its env var, with a short description. In a config file they're
+/// written in lower case, and can be grouped in a table by their prefix, so `TLS_CERT_PATH` is
+/// `cert_path` in the `[tls]` table.
+pub const SETTINGS: &[(&str, &str)] = &[
+    (
+        "JWT_PUBLIC_KEY_B64",
+        "Base64 encoded ES256 public key that tokens are verified with",
+    ),
+    ("HOST", "Address to listen on, defaults to 127.0.0.1"),
+    ("PORT", "Port to listen on, defaults to 8080"),
+    ("WORKER_COUNT", "Number of HTTP workers, defaults to 4"),
+    ("TLS_CERT_PATH", "PEM file with the TLS certificate chain"),
+    ("TLS_KEY_PATH", "PEM file with the TLS private key"),
+    (
+        "TLS_CLIENT_CA_PATH",
+        "PEM file with the CA that client certificates are signed by",
+    ),
+    (
+        "TLS_CLIENT_TOPICS_PATH",
+        "TOML file mapping client certificate names to topics",
+    ),
+    ("REDIS_URL", "Redis server to use as a backplane"),
+    ("CLUSTER_BIND", "Address to listen for cluster members on"),
+    (
+        "CLUSTER_ADVERTISE",
+        "Address other cluster members reach this instance on",
+    ),
+    ("CLUSTER_PEERS", "Comma separated list of cluster members"),
+    (
+        "CLUSTER_DNS",
+        "A host:port resolving to the addresses of cluster members",
+    ),
+    (
+        "CLUSTER_SECRET",
+        "Shared secret that cluster members need to join, required with CLUSTER_BIND",
+    ),
+    ("NATS_URL", "NATS server to ingest messages from"),
+    ("NATS_RULES", "Rules mapping NATS subjects to topics"),
+    (
+        "KAFKA_BROKERS",
+        "Comma separated list of Kafka brokers to ingest messages from",
+    ),
+    (
+        "KAFKA_GROUP_ID",
+        "Kafka consumer group, defaults to notiflux",
+    ),
+    (
+        "KAFKA_RULES",
+        "Rules mapping Kafka topics to notiflux topics",
+    ),
+    (
+        "WEBHOOK_URL",
+        "URL to post subscription lifecycle events to",
+    ),
+    ("WEBHOOK_SECRET", "Secret to sign webhook requests with"),
+    (
+        "WEBHOOK_SESSION_EVENTS",
+        "Also post events when sessions connect and disconnect",
+    ),
+    (
+        "WEBHOOK_MAX_ATTEMPTS",
+        "Attempts to deliver each webhook event, defaults to 5",
+    ),
+    (
+        "SHUTDOWN_DRAIN",
+        "Seconds to wait for sessions to close on shutdown, defaults to 10",
+    ),
+    (
+        "SHUTDOWN_RECONNECT_DELAY",
+        "Up to how many seconds clients wait to reconnect after a shutdown, defaults to 5",
+    ),
+    (
+        "HEARTBEAT_INTERVAL",
+        "Seconds between pings to clients, defaults to 15",
+    ),
+    (
+        "CLIENT_TIMEOUT",
+        "Seconds a client can stay silent before it's disconnected, defaults to 30",
+    ),
+    (
+        "HEARTBEAT_INTERVAL_MIN",
+        "Shortest heartbeat interval clients can ask for, defaults to 5",
+    ),
+    (
+        "HEARTBEAT_INTERVAL_MAX",
+        "Longest heartbeat interval clients can ask for, defaults to 120",
+    ),
+    (
+        "CLIENT_TIMEOUT_MAX",
+        "Longest timeout clients can ask for, defaults to 300",
+    ),
+    (
+        "TRUST_PROXY",
+        "Take client addresses from the Forwarded or X-Forwarded-For header, defaults to false",
+    ),
+    ("MAX_CONNECTIONS", "Open WebSocket connections to accept"),
+    (
+        "MAX_CONNECTIONS_PER_IP",
+        "Open WebSocket connections to accept from one address",
+    ),
+    (
+        "MAX_CONNECTIONS_PER_SUB",
+        "Sessions that can subscribe with tokens for one subject",
+    ),
+    (
+        "MAX_TOPICS_PER_SESSION",
+        "Topics a session can subscribe to",
+    ),
+    (
+        "MAX_SUBSCRIBERS_PER_TOPIC",
+        "Sessions that can subscribe to a topic",
+    ),
+    (
+        "RATE_LIMIT_IP",
+        "Broadcasts and commands per address, such as 20/s",
+    ),
+    (
+        "RATE_LIMIT_SUB",
+        "Broadcasts and subscriptions per token subject, such as 600/m",
+    ),
+    ("RATE_LIMIT_TOPIC", "Broadcasts per topic, such as 100/s"),
+    (
+        "MAX_MESSAGE_SIZE",
+        "Bytes of a broadcast or direct message, defaults to 1048576",
+    ),
+    (
+        "MAX_FRAME_SIZE",
+        "Bytes of a WebSocket frame from a client, defaults to 65536",
+    ),
+    ("MAX_TOPIC_LENGTH", "Bytes of a topic name, defaults to 256"),
+    (
+        "STRICT_TOPICS",
+        "Only allow ASCII letters, digits and -_.:@ in topic names, defaults to false",
+    ),
+];
 
 impl Config {
-    pub fn init_from_env() -> Result<Self, NotifluxError> {
-        let jwt_public_key_b64 = env::var("JWT_PUBLIC_KEY_B64").map_err(|_| NotifluxError {
-            message: Some("JWT_PUBLIC_KEY_B64 is not set in env".to_string()),
-            error_type: NotifluxErrorType::EnvError,
-        })?;
-
-        let jwt_public_key = BASE64_STANDARD
-            .decode(jwt_public_key_b64.as_bytes())
-            .map_err(|_| NotifluxError {
-                message: Some("Unable to Base64 decode JWT_PUBLIC_KEY_B64".to_string()),
-                error_type: NotifluxErrorType::Base64DecodeError,
-            })?;
-        let port = env::var("PORT")
-            .unwrap_or_else(|_| DEFAULT_PORT.to_string())
-            .parse::<u16>()?;
-        let host = env::var("HOST").unwrap_or_else(|_| DEFAULT_HOST.to_string());
-        let worker_count = env::var("WORKER_COUNT")
-            .unwrap_or_else(|_| DEFAULT_WORKER_COUNT.to_string())
-            .parse::<usize>()?;
-
-        Ok(Config {
+    /// The defaults with the given public key in PEM format, for embedding notiflux
+    pub fn new(jwt_public_key: &[u8]) -> Self {
+        Config {
+            jwt_public_key: jwt_public_key.to_vec(),
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            worker_count: DEFAULT_WORKER_COUNT,
+            tls: None,
+            redis_url: None,
+            cluster: None,
+            nats: None,
+            kafka: None,
+            webhook: None,
+            shutdown: ShutdownConfig {
+                drain: Duration::from_secs(DEFAULT_SHUTDOWN_DRAIN_SECS),
+                reconnect_delay: Duration::from_secs(DEFAULT_SHUTDOWN_RECONNECT_DELAY_SECS),
+            },
+            heartbeat: HeartbeatConfig {
+                interval: Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL_SECS),
+                client_timeout: Duration::from_secs(DEFAULT_CLIENT_TIMEOUT_SECS),
+                min_interval: Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL_MIN_SECS),
+                max_interval: Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL_MAX_SECS),
+                max_client_timeout: Duration::from_secs(DEFAULT_CLIENT_TIMEOUT_MAX_SECS),
+            },
+            limits: LimitsConfig {
+                max_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+                max_frame_size: Some(DEFAULT_MAX_FRAME_SIZE),
+                max_topic_length: Some(DEFAULT_MAX_TOPIC_LENGTH),
+                ..LimitsConfig::default()
+            },
+            rate_limits: RateLimitsConfig::default(),
+        }
+    }
+
+    /// Load the config file, if there is one, with env vars overriding its values and the given
+    /// overrides, such as command line flags, overriding both. The path defaults to the
+    /// `NOTIFLUX_CONFIG` env var.
+    pub fn load(
+        path: Option<&Path>,
+        overrides: HashMap<String, String>,
+    ) -> Result<Self, NotifluxError> {
+        let path = path
+            .map(Path::to_path_buf)
+            .or_else(|| env::var_os(CONFIG_PATH_VAR).map(PathBuf::from));
+        let file = match path {
+            Some(path) => read_file(&path)?,
+            None => HashMap::new(),
+        };
+
+        Self::from_layers(vec![file, env_layer(), overrides])
+    }
+
+    /// Build the config from layers of settings, where later layers override earlier ones. Every
+    /// problem is reported at once, in a single [`NotifluxErrorType::ConfigError`].
+    pub fn from_layers(layers: Vec<HashMap<String, String>>) -> Result<Self, NotifluxError> {
+        let mut settings = Settings {
+            values: HashMap::new(),
+            errors: Vec::new(),
+        };
+        for layer in layers {
+            for (key, value) in layer {
+                if SETTINGS.iter().any(|(name, _)| *name == key) {
+                    settings.values.insert(key, value);
+                } else {
+                    settings.errors.push(format!("Unknown setting {}", key));
+                }
+            }
+        }
+
+        let config = settings.config();
+        if !settings.errors.is_empty() {
+            return Err(NotifluxError {
+                message: Some(format!(
+                    "Invalid configuration:\n  - {}",
+                    settings.errors.join("\n  - ")
+                )),
+                error_type: NotifluxErrorType::ConfigError,
+            });
+        }
+
+        Ok(config)
+    }
+}
+
+/// The settings that are set in the environment
+fn env_layer() -> HashMap<String, String> {
+    SETTINGS
+        .iter()
+        .filter_map(|(key, _)| env::var(key).ok().map(|value| (key.to_string(), value)))
+        .collect()
+}
+
+/// Read a TOML or YAML config file, going by its extension, into settings
+fn read_file(path: &Path) -> Result<HashMap<String, String>, NotifluxError> {
+    let error = |e: &dyn Display| NotifluxError {
+        message: Some(format!("Unable to read {}: {}", path.display(), e)),
+        error_type: NotifluxErrorType::ConfigError,
+    };
+    let contents = std::fs::read_to_string(path).map_err(|e| error(&e))?;
+
+    let value: Value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&contents).map_err(|e| error(&e))?,
+        Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(|e| error(&e))?,
+        _ => return Err(error(&"expected a .toml, .yaml or .yml file")),
+    };
+
+    let mut settings = HashMap::new();
+    flatten("", &value, &mut settings).map_err(|e| error(&e))?;
+    Ok(settings)
+}
+
+/// Flatten the tables of a config file into settings named like their env vars, with lists
+/// joined by commas
+fn flatten(
+    prefix: &str,
+    value: &Value,
+    settings: &mut HashMap<String, String>,
+) -> Result<(), String> {
+    let key = prefix.to_uppercase();
+    let scalar = |value: &Value| match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(_) | Value::Bool(_) => Ok(value.to_string()),
+        _ => Err(format!("{} should be a string, number or boolean", prefix)),
+    };
+
+    match value {
+        Value::Object(table) => {
+            for (name, value) in table {
+                let name = match prefix {
+                    "" => name.clone(),
+                    _ => format!("{}_{}", prefix, name),
+                };
+                flatten(&name, value, settings)?;
+            }
+        }
+        Value::Array(items) => {
+            let items = items.iter().map(scalar).collect::<Result<Vec<_>, _>>()?;
+            settings.insert(key, items.join(","));
+        }
+        Value::Null => {}
+        _ if prefix.is_empty() => return Err("expected a table of settings".to_string()),
+        value => {
+            settings.insert(key, scalar(value)?);
+        }
+    }
+    Ok(())
+}
+
+/// The merged settings, and the problems found while turning them into a [`Config`]
+struct Settings {
+    values: HashMap<String, String>,
+    errors: Vec<String>,
+}
+
+impl Settings {
+    fn get(&self, key: &str) -> Option<String> {
+        self.values.get(key).cloned()
+    }
+
+    fn is_set(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    /// Parse a setting, recording an error and using the default if it's invalid
+    fn parse<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.values.get(key).map(|value| value.parse::<T>()) {
+            Some(Ok(value)) => value,
+            Some(Err(e)) => {
+                self.errors.push(format!("{} is invalid: {}", key, e));
+                default
+            }
+            None => default,
+        }
+    }
+
+    fn parse_bool(&mut self, key: &str) -> bool {
+        match self
+            .values
+            .get(key)
+            .map(|value| value.to_lowercase())
+            .as_deref()
+        {
+            None | Some("false" | "0") => false,
+            Some("true" | "1") => true,
+            Some(value) => {
+                self.errors
+                    .push(format!("{} should be true or false, not {}", key, value));
+                false
+            }
+        }
+    }
+
+    /// An optional limit, which needs to be at least 1
+    fn limit(&mut self, key: &str) -> Option<usize> {
+        if !self.is_set(key) {
+            return None;
+        }
+        match self.parse(key, 0) {
+            0 => {
+                self.errors.push(format!("{} needs to be at least 1", key));
+                None
+            }
+            limit => Some(limit),
+        }
+    }
+
+    fn rate(&mut self, key: &str) -> Option<Rate> {
+        match self.get(key)?.parse() {
+            Ok(rate) => Some(rate),
+            Err(e) => {
+                self.errors.push(format!("{} is invalid: {}", key, e));
+                None
+            }
+        }
+    }
+
+    /// A file that needs to exist for the server to start
+    fn path(&mut self, key: &str) -> PathBuf {
+        let path = PathBuf::from(self.get(key).unwrap_or_default());
+        if !path.is_file() {
+            self.errors
+                .push(format!("{} {} does not exist", key, path.display()));
+        }
+        path
+    }
+
+    /// Record an error for each of the settings that is set without the one enabling them
+    fn requires(&mut self, keys: &[&str], enabled_by: &str) {
+        for key in keys {
+            if self.is_set(key) && !self.is_set(enabled_by) {
+                self.errors
+                    .push(format!("{} requires {} to be set", key, enabled_by));
+            }
+        }
+    }
+
+    /// Parse ingest rules, of which there needs to be at least one
+    fn rules(&mut self, key: &str) -> Vec<Rule> {
+        match parse_rules(&self.get(key).unwrap_or_default()) {
+            Ok(rules) if rules.is_empty() => {
+                self.errors.push(format!("{} needs at least one rule", key));
+                rules
+            }
+            Ok(rules) => rules,
+            Err(e) => {
+                self.errors
+                    .push(format!("{} is invalid: {}", key, e.message()));
+                Vec::new()
+            }
+        }
+    }
+
+    fn config(&mut self) -> Config {
+        let jwt_public_key = self.jwt_public_key();
+        let host = self.get("HOST").unwrap_or_else(|| DEFAULT_HOST.to_string());
+        let port = self.parse("PORT", DEFAULT_PORT);
+        let worker_count = self.parse("WORKER_COUNT", DEFAULT_WORKER_COUNT);
+        if worker_count == 0 {
+            self.errors
+                .push("WORKER_COUNT needs to be at least 1".to_string());
+        }
+
+        let tls = self.tls();
+        let redis_url = self.get("REDIS_URL");
+        let cluster = self.cluster();
+        if redis_url.is_some() && cluster.is_some() {
+            self.errors
+                .push("REDIS_URL and CLUSTER_BIND can't both be set".to_string());
+        }
+
+        self.requires(&["NATS_RULES"], "NATS_URL");
+        let nats = self.get("NATS_URL").map(|url| NatsConfig {
+            url,
+            rules: self.rules("NATS_RULES"),
+        });
+
+        self.requires(&["KAFKA_GROUP_ID", "KAFKA_RULES"], "KAFKA_BROKERS");
+        let kafka = self.get("KAFKA_BROKERS").map(|brokers| KafkaConfig {
+            brokers,
+            group_id: self
+                .get("KAFKA_GROUP_ID")
+                .unwrap_or_else(|| DEFAULT_KAFKA_GROUP_ID.to_string()),
+            rules: self.rules("KAFKA_RULES"),
+        });
+
+        let webhook = self.webhook();
+        let shutdown = ShutdownConfig {
+            drain: Duration::from_secs(self.parse("SHUTDOWN_DRAIN", DEFAULT_SHUTDOWN_DRAIN_SECS)),
+            reconnect_delay: Duration::from_secs(self.parse(
+                "SHUTDOWN_RECONNECT_DELAY",
+                DEFAULT_SHUTDOWN_RECONNECT_DELAY_SECS,
+            )),
+        };
+        let heartbeat = self.heartbeat();
+        let limits = LimitsConfig {
+            trust_proxy: self.parse_bool("TRUST_PROXY"),
+            max_connections: self.limit("MAX_CONNECTIONS"),
+            max_connections_per_ip: self.limit("MAX_CONNECTIONS_PER_IP"),
+            max_connections_per_sub: self.limit("MAX_CONNECTIONS_PER_SUB"),
+            max_topics_per_session: self.limit("MAX_TOPICS_PER_SESSION"),
+            max_subscribers_per_topic: self.limit("MAX_SUBSCRIBERS_PER_TOPIC"),
+            max_message_size: self
+                .limit("MAX_MESSAGE_SIZE")
+                .or(Some(DEFAULT_MAX_MESSAGE_SIZE)),
+            max_frame_size: self
+                .limit("MAX_FRAME_SIZE")
+                .or(Some(DEFAULT_MAX_FRAME_SIZE)),
+            max_topic_length: self
+                .limit("MAX_TOPIC_LENGTH")
+                .or(Some(DEFAULT_MAX_TOPIC_LENGTH)),
+            strict_topics: self.parse_bool("STRICT_TOPICS"),
+        };
+        let rate_limits = RateLimitsConfig {
+            per_ip: self.rate("RATE_LIMIT_IP"),
+            per_sub: self.rate("RATE_LIMIT_SUB"),
+            per_topic: self.rate("RATE_LIMIT_TOPIC"),
+        };
+
+        Config {
             jwt_public_key,
             host,
             port,
             worker_count,
+            tls,
+            redis_url,
+            cluster,
+            nats,
+            kafka,
+            webhook,
+            shutdown,
+            heartbeat,
+            limits,
+            rate_limits,
+        }
+    }
+
+    fn jwt_public_key(&mut self) -> Vec<u8> {
+        let Some(jwt_public_key_b64) = self.get("JWT_PUBLIC_KEY_B64") else {
+            self.errors
+                .push("JWT_PUBLIC_KEY_B64 is not set".to_string());
+            return Vec::new();
+        };
+
+        match BASE64_STANDARD.decode(jwt_public_key_b64.as_bytes()) {
+            Ok(key) if jsonwebtoken::DecodingKey::from_ec_pem(&key).is_ok() => key,
+            Ok(_) => {
+                self.errors.push(
+                    "JWT_PUBLIC_KEY_B64 is not a base64 encoded EC public key in PEM format"
+                        .to_string(),
+                );
+                Vec::new()
+            }
+            Err(_) => {
+                self.errors
+                    .push("Unable to Base64 decode JWT_PUBLIC_KEY_B64".to_string());
+                Vec::new()
+            }
+        }
+    }
+
+    fn tls(&mut self) -> Option<TlsConfig> {
+        match (self.is_set("TLS_CERT_PATH"), self.is_set("TLS_KEY_PATH")) {
+            (true, true) => Some(TlsConfig {
+                cert_path: self.path("TLS_CERT_PATH"),
+                key_path: self.path("TLS_KEY_PATH"),
+                client_auth: self.client_auth(),
+            }),
+            (false, false) => {
+                self.requires(
+                    &["TLS_CLIENT_CA_PATH", "TLS_CLIENT_TOPICS_PATH"],
+                    "TLS_CERT_PATH",
+                );
+                None
+            }
+            _ => {
+                self.errors
+                    .push("TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_string());
+                None
+            }
+        }
+    }
+
+    fn client_auth(&mut self) -> Option<ClientAuthConfig> {
+        match (
+            self.is_set("TLS_CLIENT_CA_PATH"),
+            self.is_set("TLS_CLIENT_TOPICS_PATH"),
+        ) {
+            (true, true) => Some(ClientAuthConfig {
+                ca_path: self.path("TLS_CLIENT_CA_PATH"),
+                topics_path: self.path("TLS_CLIENT_TOPICS_PATH"),
+            }),
+            (false, false) => None,
+            _ => {
+                self.errors.push(
+                    "TLS_CLIENT_CA_PATH and TLS_CLIENT_TOPICS_PATH must be set together"
+                        .to_string(),
+                );
+                None
+            }
+        }
+    }
+
+    fn cluster(&mut self) -> Option<ClusterConfig> {
+        self.requires(
+            &[
+                "CLUSTER_ADVERTISE",
+                "CLUSTER_PEERS",
+                "CLUSTER_DNS",
+                "CLUSTER_SECRET",
+            ],
+            "CLUSTER_BIND",
+        );
+        let bind = self.get("CLUSTER_BIND")?;
+        let Some(secret) = self.get("CLUSTER_SECRET") else {
+            self.errors
+                .push("CLUSTER_BIND requires CLUSTER_SECRET to be set".to_string());
+            return None;
+        };
+
+        Some(ClusterConfig {
+            advertise: self
+                .get("CLUSTER_ADVERTISE")
+                .unwrap_or_else(|| bind.clone()),
+            bind,
+            peers: self
+                .get("CLUSTER_PEERS")
+                .map(|peers| split_list(&peers))
+                .unwrap_or_default(),
+            dns: self.get("CLUSTER_DNS"),
+            secret,
         })
     }
-}
 
-pub fn get_config() -> &'static Config {
-    CONFIG.get_or_init(|| match Config::init_from_env() {
-        Ok(config) => config,
-        Err(e) => {
-            log::error!("Error initializing config: {}", e);
-            std::process::exit(1);
+    fn heartbeat(&mut self) -> HeartbeatConfig {
+        let mut seconds = |key: &str, default: u64| match self.parse(key, default) {
+            0 => {
+                self.errors.push(format!("{} needs to be at least 1", key));
+                Duration::from_secs(default)
+            }
+            secs => Duration::from_secs(secs),
+        };
+        let heartbeat = HeartbeatConfig {
+            interval: seconds("HEARTBEAT_INTERVAL", DEFAULT_HEARTBEAT_INTERVAL_SECS),
+            client_timeout: seconds("CLIENT_TIMEOUT", DEFAULT_CLIENT_TIMEOUT_SECS),
+            min_interval: seconds(
+                "HEARTBEAT_INTERVAL_MIN",
+                DEFAULT_HEARTBEAT_INTERVAL_MIN_SECS,
+            ),
+            max_interval: seconds(
+                "HEARTBEAT_INTERVAL_MAX",
+                DEFAULT_HEARTBEAT_INTERVAL_MAX_SECS,
+            ),
+            max_client_timeout: seconds("CLIENT_TIMEOUT_MAX", DEFAULT_CLIENT_TIMEOUT_MAX_SECS),
+        };
+
+        if heartbeat.interval < heartbeat.min_interval
+            || heartbeat.interval > heartbeat.max_interval
+        {
+            self.errors.push(
+                "HEARTBEAT_INTERVAL needs to be between HEARTBEAT_INTERVAL_MIN and HEARTBEAT_INTERVAL_MAX"
+                    .to_string(),
+            );
+        }
+        if heartbeat.client_timeout <= heartbeat.interval {
+            self.errors
+                .push("CLIENT_TIMEOUT needs to be longer than HEARTBEAT_INTERVAL".to_string());
+        }
+        if heartbeat.client_timeout > heartbeat.max_client_timeout {
+            self.errors
+                .push("CLIENT_TIMEOUT can't be longer than CLIENT_TIMEOUT_MAX".to_string());
+        }
+        if heartbeat.max_client_timeout < heartbeat.max_interval * 2 {
+            self.errors.push(
+                "CLIENT_TIMEOUT_MAX needs to be at least twice HEARTBEAT_INTERVAL_MAX".to_string(),
+            );
+        }
+
+        heartbeat
+    }
+
+    fn webhook(&mut self) -> Option<WebhookConfig> {
+        self.requires(
+            &[
+                "WEBHOOK_SECRET",
+                "WEBHOOK_SESSION_EVENTS",
+                "WEBHOOK_MAX_ATTEMPTS",
+            ],
+            "WEBHOOK_URL",
+        );
+        let url = self.get("WEBHOOK_URL")?;
+
+        let max_attempts = self.parse("WEBHOOK_MAX_ATTEMPTS", DEFAULT_WEBHOOK_MAX_ATTEMPTS);
+        if max_attempts == 0 {
+            self.errors
+                .push("WEBHOOK_MAX_ATTEMPTS needs to be at least 1".to_string());
         }
-    })
+
+        Some(WebhookConfig {
+            url,
+            secret: self.get("WEBHOOK_SECRET"),
+            session_events: self.parse_bool("WEBHOOK_SESSION_EVENTS"),
+            max_attempts,
+        })
+    }
+}
+
+/// Split a comma separated list, ignoring empty items
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn public_key_b64() -> String {
+        BASE64_STANDARD.encode(include_bytes!("../scripts/public_key.pem"))
+    }
+
+    fn layer(settings: &[(&str, &str)]) -> HashMap<String, String> {
+        settings
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn write_config(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("notiflux-{}-{}", ulid::Ulid::new(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
     #[test]
     fn test_init_from_env() {
-        env::set_var("JWT_PUBLIC_KEY_B64", "SGVsbG8hCg==");
+        env::set_var("JWT_PUBLIC_KEY_B64", public_key_b64());
         env::set_var("PORT", "1234");
         env::set_var("HOST", "10.11.12.13");
         env::set_var("WORKER_COUNT", "4");
 
-        let config = Config::init_from_env().unwrap();
+        let config = Config::load(None, HashMap::new()).unwrap();
 
         assert_eq!(config.port, 1234);
         assert_eq!(config.host, "10.11.12.13");
         assert_eq!(config.worker_count, 4);
-        assert_eq!(config.jwt_public_key, b"Hello!\n".to_vec());
+        assert_eq!(
+            config.jwt_public_key,
+            include_bytes!("../scripts/public_key.pem").to_vec()
+        );
     }
 
     #[test]
     fn test_init_defaults() {
-        env::set_var("JWT_PUBLIC_KEY_B64", "SGVsbG8hCg==");
-
-        let config = Config::init_from_env().unwrap();
+        let config =
+            Config::from_layers(vec![layer(&[("JWT_PUBLIC_KEY_B64", &public_key_b64())])]).unwrap();
 
         assert_eq!(config.port, DEFAULT_PORT);
         assert_eq!(config.host, DEFAULT_HOST);
         assert_eq!(config.worker_count, DEFAULT_WORKER_COUNT);
-        assert_eq!(config.jwt_public_key, b"Hello!\n".to_vec());
+        assert_eq!(
+            config.jwt_public_key,
+            include_bytes!("../scripts/public_key.pem").to_vec()
+        );
     }
 
     #[test]
     fn test_init_requires_jwt_public_key() {
-        env::remove_var("JWT_PUBLIC_KEY_B64");
-
-        let config = Config::init_from_env();
+        let config = Config::from_layers(vec![HashMap::new()]);
 
         assert!(config.is_err());
         let err = config.unwrap_err();
-        assert_eq!(err.error_type, NotifluxErrorType::EnvError);
+        assert_eq!(err.error_type, NotifluxErrorType::ConfigError);
         assert_eq!(
             err.message,
-            Some("JWT_PUBLIC_KEY_B64 is not set in env".to_string())
+            Some("Invalid configuration:\n  - JWT_PUBLIC_KEY_B64 is not set".to_string())
         );
     }
+
+    #[test]
+    fn test_later_layers_override() {
+        let config = Config::from_layers(vec![
+            layer(&[
+                ("JWT_PUBLIC_KEY_B64", &public_key_b64()),
+                ("PORT", "1234"),
+                ("HOST", "0.0.0.0"),
+            ]),
+            layer(&[("PORT", "4321")]),
+        ])
+        .unwrap();
+
+        assert_eq!(config.port, 4321);
+        assert_eq!(config.host, "0.0.0.0");
+    }
+
+    #[test]
+    fn test_all_errors_reported() {
+        let err = Config::from_layers(vec![layer(&[
+            ("JWT_PUBLIC_KEY_B64", "SGVsbG8hCg=="),
+            ("PORT", "http"),
+            ("TLS_CERT_PATH", "/nonexistent/cert.pem"),
+            ("CLUSTER_PEERS", "10.0.0.1:7946"),
+            ("WEBHOOK_URL", "https://example.com/hook"),
+            ("WEBHOOK_MAX_ATTEMPTS", "0"),
+            ("HEARTBEAT_INTERVAL", "60"),
+            ("CLIENT_TIMEOUT", "0"),
+            ("MAX_CONNECTIONS", "0"),
+            ("RATE_LIMIT_IP", "20/d"),
+            ("NOT_A_SETTING", "1"),
+        ])])
+        .unwrap_err();
+
+        assert_eq!(err.error_type, NotifluxErrorType::ConfigError);
+        let message = err.message();
+        for expected in [
+            "Unknown setting NOT_A_SETTING",
+            "JWT_PUBLIC_KEY_B64 is not a base64 encoded EC public key",
+            "PORT is invalid",
+            "TLS_CERT_PATH and TLS_KEY_PATH must be set together",
+            "CLUSTER_PEERS requires CLUSTER_BIND to be set",
+            "WEBHOOK_MAX_ATTEMPTS needs to be at least 1",
+            "CLIENT_TIMEOUT needs to be at least 1",
+            "CLIENT_TIMEOUT needs to be longer than HEARTBEAT_INTERVAL",
+            "MAX_CONNECTIONS needs to be at least 1",
+            "RATE_LIMIT_IP is invalid: expected a rate",
+        ] {
+            assert!(message.contains(expected), "{} in {}", expected, message);
+        }
+    }
+
+    #[test]
+    fn test_cluster_requires_secret() {
+        let err = Config::from_layers(vec![layer(&[
+            ("JWT_PUBLIC_KEY_B64", &public_key_b64()),
+            ("CLUSTER_BIND", "0.0.0.0:7946"),
+        ])])
+        .unwrap_err();
+        assert!(err
+            .message()
+            .contains("CLUSTER_BIND requires CLUSTER_SECRET to be set"));
+    }
+
+    #[test]
+    fn test_toml_file() {
+        let path = write_config(
+            "config.toml",
+            &format!(
+                r#"
+                jwt_public_key_b64 = "{}"
+                port = 9000
+
+                [cluster]
+                bind = "0.0.0.0:7946"
+                peers = ["10.0.0.1:7946", "10.0.0.2:7946"]
+                secret = "secret"
+
+                [webhook]
+                url = "https://example.com/hook"
+                session_events = true
+
+                [shutdown]
+                drain = 30
+
+                [heartbeat]
+                interval = 60
+
+                [client]
+                timeout = 180
+
+                [max]
+                connections = 10000
+                topics_per_session = 50
+
+                [rate_limit]
+                topic = "100/s"
+                "#,
+                public_key_b64()
+            ),
+        );
+
+        let config = Config::from_layers(vec![read_file(&path).unwrap()]).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(config.port, 9000);
+        let cluster = config.cluster.unwrap();
+        assert_eq!(cluster.advertise, "0.0.0.0:7946");
+        assert_eq!(cluster.peers, vec!["10.0.0.1:7946", "10.0.0.2:7946"]);
+        let webhook = config.webhook.unwrap();
+        assert!(webhook.session_events);
+        assert_eq!(webhook.max_attempts, DEFAULT_WEBHOOK_MAX_ATTEMPTS);
+        assert_eq!(config.shutdown.drain, Duration::from_secs(30));
+        assert_eq!(
+            config.shutdown.reconnect_delay,
+            Duration::from_secs(DEFAULT_SHUTDOWN_RECONNECT_DELAY_SECS)
+        );
+        assert_eq!(config.heartbeat.interval, Duration::from_secs(60));
+        assert_eq!(config.heartbeat.client_timeout, Duration::from_secs(180));
+        assert_eq!(
+            config.limits,
+            LimitsConfig {
+                max_connections: Some(10000),
+                max_topics_per_session: Some(50),
+                ..Config::new(b"").limits
+            }
+        );
+        assert_eq!(
+            config.rate_limits,
+            RateLimitsConfig {
+                per_topic: Some(Rate {
+                    requests: 100,
+                    period: Duration::from_secs(1)
+                }),
+                ..RateLimitsConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(
+            "600/m".parse(),
+            Ok(Rate {
+                requests: 600,
+                period: Duration::from_secs(60)
+            })
+        );
+        assert!("0/s".parse::<Rate>().is_err());
+        assert!("20".parse::<Rate>().is_err());
+        assert!("20/w".parse::<Rate>().is_err());
+    }
+
+    #[test]
+    fn test_heartbeat_negotiate() {
+        let heartbeat = Config::new(b"").heartbeat;
+        let secs = Duration::from_secs;
+
+        assert_eq!(heartbeat.negotiate(None, None), (secs(15), secs(30)));
+        assert_eq!(heartbeat.negotiate(Some(60), None), (secs(60), secs(120)));
+        assert_eq!(
+            heartbeat.negotiate(Some(60), Some(90)),
+            (secs(60), secs(120))
+        );
+        assert_eq!(
+            heartbeat.negotiate(Some(1), Some(3600)),
+            (secs(5), secs(300))
+        );
+        assert_eq!(heartbeat.negotiate(Some(600), None), (secs(120), secs(240)));
+        assert_eq!(heartbeat.negotiate(None, Some(45)), (secs(15), secs(45)));
+    }
+
+    #[test]
+    fn test_yaml_file() {
+        let path = write_config(
+            "config.yaml",
+            &format!(
+                "jwt_public_key_b64: {}\nworker_count: 2\nnats:\n  url: nats://nats:4222\n  rules:\n    - orders.*=order:{{1}}\n",
+                public_key_b64()
+            ),
+        );
+
+        let config = Config::from_layers(vec![read_file(&path).unwrap()]).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(config.worker_count, 2);
+        let nats = config.nats.unwrap();
+        assert_eq!(nats.url, "nats://nats:4222");
+        assert_eq!(nats.rules.len(), 1);
+    }
+
+    #[test]
+    fn test_unsupported_file() {
+        let path = write_config("config.ini", "port = 1");
+
+        let err = read_file(&path).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(err.error_type, NotifluxErrorType::ConfigError);
+    }
 }
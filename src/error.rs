@@ -11,6 +11,11 @@ pub enum NotifluxErrorType {
     Base64DecodeError,
     JWTError,
     ConfigError,
+    AuthorizationError,
+    /// The server can't take the request right now, such as while shutting down
+    Unavailable,
+    /// The client is over one of its limits
+    TooManyRequests,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -55,6 +60,9 @@ impl ResponseError for NotifluxError {
             | NotifluxErrorType::ConfigError
             | NotifluxErrorType::JWTError => StatusCode::INTERNAL_SERVER_ERROR,
             NotifluxErrorType::ValidationError => StatusCode::BAD_REQUEST,
+            NotifluxErrorType::AuthorizationError => StatusCode::FORBIDDEN,
+            NotifluxErrorType::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            NotifluxErrorType::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
         }
     }
 
@@ -66,7 +74,7 @@ impl ResponseError for NotifluxError {
 }
 
 impl NotifluxError {
-    fn message(&self) -> String {
+    pub(crate) fn message(&self) -> String {
         match self {
             NotifluxError {
                 message: Some(message),
@@ -0,0 +1,509 @@
+//! Filter expressions that subscribers can attach to a subscription, so that only the JSON
+//! messages they care about are delivered to them.
+//!
+//! The syntax supports comparisons of a dotted field path against a literal, membership in a
+//! list of literals and the usual boolean combinators, for example:
+//!
+//! ```text
+//! status == "failed" && (attempts >= 3 || owner.team in ["infra", "platform"])
+//! ```
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::{NotifluxError, NotifluxErrorType};
+
+/// Longest filter accepted, in bytes
+const MAX_LENGTH: usize = 4096;
+/// Deepest nesting of parentheses and negations accepted, as the parser recurses for each level
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Compare(Vec<String>, Op, Value),
+    In(Vec<String>, Vec<Value>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+/// A parsed filter expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Filter, NotifluxError> {
+        if source.len() > MAX_LENGTH {
+            return Err(parse_error(format!("Longer than {} bytes", MAX_LENGTH)));
+        }
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(parse_error(format!("Unexpected {}", token)));
+        }
+
+        Ok(Filter {
+            source: source.to_owned(),
+            expr,
+        })
+    }
+
+    /// Whether the JSON value matches the filter. Fields that are missing, or comparisons
+    /// between values of different types, never match.
+    pub fn matches(&self, value: &Value) -> bool {
+        self.expr.eval(value)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Filter {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Filter::parse(&source).map_err(|e| serde::de::Error::custom(e.message()))
+    }
+}
+
+impl Expr {
+    fn eval(&self, value: &Value) -> bool {
+        match self {
+            Expr::Compare(path, op, expected) => {
+                lookup(value, path).is_some_and(|actual| compare(actual, op, expected))
+            }
+            Expr::In(path, values) => {
+                lookup(value, path).is_some_and(|actual| values.contains(actual))
+            }
+            Expr::Not(expr) => !expr.eval(value),
+            Expr::And(left, right) => left.eval(value) && right.eval(value),
+            Expr::Or(left, right) => left.eval(value) || right.eval(value),
+        }
+    }
+}
+
+fn lookup<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(value, |value, key| match value {
+        Value::Object(map) => map.get(key),
+        Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    })
+}
+
+fn compare(actual: &Value, op: &Op, expected: &Value) -> bool {
+    match op {
+        Op::Eq => actual == expected,
+        Op::Ne => actual != expected,
+        _ => {
+            let ordering = match (actual, expected) {
+                (Value::Number(a), Value::Number(b)) => match (a.as_f64(), b.as_f64()) {
+                    (Some(a), Some(b)) => a.partial_cmp(&b),
+                    _ => None,
+                },
+                (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+                _ => None,
+            };
+            match ordering {
+                Some(Ordering::Less) => matches!(op, Op::Lt | Op::Le),
+                Some(Ordering::Equal) => matches!(op, Op::Le | Op::Ge),
+                Some(Ordering::Greater) => matches!(op, Op::Gt | Op::Ge),
+                None => false,
+            }
+        }
+    }
+}
+
+fn parse_error(message: String) -> NotifluxError {
+    NotifluxError {
+        message: Some(format!("Invalid filter: {}", message)),
+        error_type: NotifluxErrorType::ValidationError,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Value),
+    Op(Op),
+    In,
+    Not,
+    And,
+    Or,
+    Dot,
+    Comma,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(ident) => write!(f, "'{}'", ident),
+            Token::Literal(value) => write!(f, "{}", value),
+            Token::Op(op) => write!(f, "{:?}", op),
+            Token::In => write!(f, "'in'"),
+            Token::Not => write!(f, "'!'"),
+            Token::And => write!(f, "'&&'"),
+            Token::Or => write!(f, "'||'"),
+            Token::Dot => write!(f, "'.'"),
+            Token::Comma => write!(f, "','"),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::LBracket => write!(f, "'['"),
+            Token::RBracket => write!(f, "']'"),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, NotifluxError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        let next = source[start + c.len_utf8()..].chars().next();
+        let token = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            ('=', Some('=')) => Token::Op(Op::Eq),
+            ('!', Some('=')) => Token::Op(Op::Ne),
+            ('<', Some('=')) => Token::Op(Op::Le),
+            ('>', Some('=')) => Token::Op(Op::Ge),
+            ('&', Some('&')) => Token::And,
+            ('|', Some('|')) => Token::Or,
+            ('<', _) => Token::Op(Op::Lt),
+            ('>', _) => Token::Op(Op::Gt),
+            ('!', _) => Token::Not,
+            ('.', _) => Token::Dot,
+            (',', _) => Token::Comma,
+            ('(', _) => Token::LParen,
+            (')', _) => Token::RParen,
+            ('[', _) => Token::LBracket,
+            (']', _) => Token::RBracket,
+            ('"', _) => {
+                // Strings follow the JSON rules, so reuse the JSON parser for the escapes
+                let mut end = None;
+                let mut escaped = false;
+                for (i, c) in source[start + 1..].char_indices() {
+                    match c {
+                        '\\' if !escaped => escaped = true,
+                        '"' if !escaped => {
+                            end = Some(start + 1 + i);
+                            break;
+                        }
+                        _ => escaped = false,
+                    }
+                }
+                let end = end.ok_or_else(|| parse_error("Unterminated string".to_owned()))?;
+                let value = serde_json::from_str::<Value>(&source[start..=end])
+                    .map_err(|_| parse_error(format!("Invalid string at {}", start)))?;
+                while chars.next_if(|&(i, _)| i <= end).is_some() {}
+                tokens.push(Token::Literal(value));
+                continue;
+            }
+            (c, _) if c.is_ascii_digit() || c == '-' => {
+                let end = start + number_len(&source[start..]);
+                while chars.next_if(|&(i, _)| i < end).is_some() {}
+                let value = serde_json::from_str::<Value>(&source[start..end])
+                    .map_err(|_| parse_error(format!("Invalid number at {}", start)))?;
+                tokens.push(Token::Literal(value));
+                continue;
+            }
+            (c, _) if c.is_alphabetic() || c == '_' => {
+                let mut end = start;
+                while let Some((i, c)) =
+                    chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_' || c == '-')
+                {
+                    end = i + c.len_utf8();
+                }
+                tokens.push(match &source[start..end] {
+                    "in" => Token::In,
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    ident => Token::Ident(ident.to_owned()),
+                });
+                continue;
+            }
+            (c, _) => return Err(parse_error(format!("Unexpected '{}' at {}", c, start))),
+        };
+
+        let len = match token {
+            Token::Op(Op::Eq | Op::Ne | Op::Le | Op::Ge) | Token::And | Token::Or => 2,
+            _ => 1,
+        };
+        for _ in 0..len {
+            chars.next();
+        }
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+/// Length of the number at the start of the string, in the JSON number format
+fn number_len(source: &str) -> usize {
+    let bytes = source.as_bytes();
+    let digits = |from: usize| {
+        bytes[from..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count()
+    };
+
+    let mut len = usize::from(bytes[0] == b'-');
+    len += digits(len);
+    if bytes.get(len) == Some(&b'.') && digits(len + 1) > 0 {
+        len += 1 + digits(len + 1);
+    }
+    if matches!(bytes.get(len), Some(b'e' | b'E')) {
+        let sign = usize::from(matches!(bytes.get(len + 1), Some(b'+' | b'-')));
+        if digits(len + 1 + sign) > 0 {
+            len += 1 + sign + digits(len + 1 + sign);
+        }
+    }
+    len
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// How many parentheses and negations the current token is nested in
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), NotifluxError> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(parse_error(format!(
+                "Expected {} but got {}",
+                expected, token
+            ))),
+            None => Err(parse_error(format!("Expected {}", expected))),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, NotifluxError> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, NotifluxError> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, NotifluxError> {
+        if !matches!(self.peek(), Some(Token::Not | Token::LParen)) {
+            return self.comparison();
+        }
+        if self.depth == MAX_DEPTH {
+            return Err(parse_error(format!(
+                "Nested deeper than {} levels",
+                MAX_DEPTH
+            )));
+        }
+
+        self.depth += 1;
+        let expr = match self.next() {
+            Some(Token::Not) => self.unary().map(|expr| Expr::Not(Box::new(expr))),
+            _ => self
+                .or()
+                .and_then(|expr| self.expect(Token::RParen).map(|_| expr)),
+        };
+        self.depth -= 1;
+        expr
+    }
+
+    fn comparison(&mut self) -> Result<Expr, NotifluxError> {
+        let path = self.path()?;
+        match self.next() {
+            Some(Token::Op(op)) => Ok(Expr::Compare(path, op, self.literal()?)),
+            Some(Token::In) => {
+                self.expect(Token::LBracket)?;
+                let mut values = vec![self.literal()?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.next();
+                    values.push(self.literal()?);
+                }
+                self.expect(Token::RBracket)?;
+                Ok(Expr::In(path, values))
+            }
+            Some(token) => Err(parse_error(format!(
+                "Expected a comparison but got {}",
+                token
+            ))),
+            None => Err(parse_error("Expected a comparison".to_owned())),
+        }
+    }
+
+    fn path(&mut self) -> Result<Vec<String>, NotifluxError> {
+        let mut path = vec![self.ident()?];
+        while self.peek() == Some(&Token::Dot) {
+            self.next();
+            path.push(self.ident()?);
+        }
+        Ok(path)
+    }
+
+    fn ident(&mut self) -> Result<String, NotifluxError> {
+        match self.next() {
+            Some(Token::Ident(ident)) => Ok(ident),
+            // Allow array indices in paths, such as `items.0.id`
+            Some(Token::Literal(Value::Number(n))) if n.is_u64() => Ok(n.to_string()),
+            Some(token) => Err(parse_error(format!(
+                "Expected a field name but got {}",
+                token
+            ))),
+            None => Err(parse_error("Expected a field name".to_owned())),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Value, NotifluxError> {
+        match self.next() {
+            Some(Token::Literal(value)) => Ok(value),
+            Some(token) => Err(parse_error(format!("Expected a value but got {}", token))),
+            None => Err(parse_error("Expected a value".to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn matches(filter: &str, value: Value) -> bool {
+        Filter::parse(filter).unwrap().matches(&value)
+    }
+
+    #[test]
+    fn test_equality() {
+        assert!(matches(
+            r#"status == "failed""#,
+            json!({"status": "failed"})
+        ));
+        assert!(!matches(r#"status == "failed""#, json!({"status": "ok"})));
+        assert!(matches(r#"status != "failed""#, json!({"status": "ok"})));
+        assert!(matches("done == true", json!({"done": true})));
+        assert!(!matches(r#"status == "failed""#, json!({})));
+    }
+
+    #[test]
+    fn test_numeric_comparisons() {
+        assert!(matches("count > 3", json!({"count": 4})));
+        assert!(!matches("count > 3", json!({"count": 3})));
+        assert!(matches("count >= 3", json!({"count": 3})));
+        assert!(matches("count < 3.5", json!({"count": 3})));
+        assert!(matches("count <= -1", json!({"count": -2})));
+        assert!(!matches("count > 3", json!({"count": "4"})));
+    }
+
+    #[test]
+    fn test_membership() {
+        let filter = r#"build.stage in ["test", "deploy"]"#;
+        assert!(matches(filter, json!({"build": {"stage": "deploy"}})));
+        assert!(!matches(filter, json!({"build": {"stage": "lint"}})));
+        assert!(matches("items.0.id == 1", json!({"items": [{"id": 1}]})));
+    }
+
+    #[test]
+    fn test_boolean_combinators() {
+        let filter = r#"status == "failed" && !(retry == true || attempts < 3)"#;
+        assert!(matches(
+            filter,
+            json!({"status": "failed", "retry": false, "attempts": 3})
+        ));
+        assert!(!matches(
+            filter,
+            json!({"status": "failed", "retry": true, "attempts": 3})
+        ));
+        assert!(!matches(
+            filter,
+            json!({"status": "ok", "retry": false, "attempts": 3})
+        ));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for filter in [
+            "",
+            "status ==",
+            r#"status == "failed"#,
+            "status = 1",
+            "(count > 1",
+            "count > 1 count",
+            "status in []",
+        ] {
+            let err = Filter::parse(filter).unwrap_err();
+            assert_eq!(err.error_type, NotifluxErrorType::ValidationError);
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let nested = |depth: usize| {
+            format!(
+                "{}count > 1{}",
+                "(!".repeat(depth / 2),
+                ")".repeat(depth / 2)
+            )
+        };
+        assert!(Filter::parse(&nested(MAX_DEPTH)).is_ok());
+        let err = Filter::parse(&nested(MAX_DEPTH + 2)).unwrap_err();
+        assert_eq!(err.error_type, NotifluxErrorType::ValidationError);
+        assert_eq!(
+            err.message.as_deref(),
+            Some("Invalid filter: Nested deeper than 32 levels")
+        );
+
+        // Rejected before parsing, however deep
+        let err = Filter::parse(&"(".repeat(100_000)).unwrap_err();
+        assert_eq!(
+            err.message.as_deref(),
+            Some("Invalid filter: Longer than 4096 bytes")
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Connectors that ingest messages from other brokers and broadcast them to notiflux topics.
+//!
+//! Messages are mapped from the subject (NATS) or topic (Kafka) they were received on to a
+//! notiflux topic through a list of rules, and are pushed to the server as trusted broadcasts
+//! without a token.
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{NotifluxError, NotifluxErrorType};
+
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "nats")]
+pub mod nats;
+
+/// Maps a source subject to a notiflux topic.
+///
+/// The source is a dot separated pattern in the NATS style, where `*` matches a single token
+/// and a trailing `>` matches one or more tokens. The topic is a template where `{1}`, `{2}`, ...
+/// are replaced with what the wildcards matched, in order, `{subject}` with the whole subject
+/// and `{key}` with the message key, for sources that have keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub source: String,
+    pub topic: String,
+}
+
+impl Rule {
+    /// The notiflux topic for the subject, if the rule matches it
+    pub fn map(&self, subject: &str, key: Option<&str>) -> Option<String> {
+        let captures = capture(&self.source, subject)?;
+
+        let mut topic = self.topic.replace("{subject}", subject);
+        for (i, capture) in captures.iter().enumerate() {
+            topic = topic.replace(&format!("{{{}}}", i + 1), capture);
+        }
+        if let Some(key) = key {
+            topic = topic.replace("{key}", key);
+        }
+        if topic.contains('{') {
+            // The template refers to something the message doesn't have
+            return None;
+        }
+
+        Some(topic)
+    }
+}
+
+fn capture<'a>(pattern: &str, subject: &'a str) -> Option<Vec<&'a str>> {
+    let mut captures = Vec::new();
+    let mut tokens = subject.split('.');
+    let mut offset = 0;
+
+    for part in pattern.split('.') {
+        if part == ">" {
+            let rest = subject.get(offset..).filter(|rest| !rest.is_empty())?;
+            captures.push(rest);
+            return Some(captures);
+        }
+        let token = tokens.next()?;
+        offset += token.len() + 1;
+        match part {
+            "*" => captures.push(token),
+            part if part == token => (),
+            _ => return None,
+        }
+    }
+
+    tokens.next().is_none().then_some(captures)
+}
+
+impl FromStr for Rule {
+    type Err = NotifluxError;
+
+    /// Parse a rule in the `<source>=<topic>` format
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| NotifluxError {
+            message: Some(format!("Invalid ingest rule '{}': {}", rule, reason)),
+            error_type: NotifluxErrorType::ConfigError,
+        };
+
+        let (source, topic) = rule
+            .split_once('=')
+            .ok_or_else(|| invalid("it should be <source>=<topic>"))?;
+        let (source, topic) = (source.trim(), topic.trim());
+        if source.is_empty() || topic.is_empty() {
+            return Err(invalid("source and topic are required"));
+        }
+        let parts: Vec<&str> = source.split('.').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            return Err(invalid("source has an empty token"));
+        }
+        if parts[..parts.len() - 1].contains(&">") {
+            return Err(invalid("> is only allowed as the last token"));
+        }
+
+        Ok(Rule {
+            source: source.to_owned(),
+            topic: topic.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.source, self.topic)
+    }
+}
+
+/// Parse a comma separated list of rules
+pub fn parse_rules(rules: &str) -> Result<Vec<Rule>, NotifluxError> {
+    rules
+        .split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// The topic of the first rule that matches the subject
+#[cfg_attr(not(any(feature = "nats", feature = "kafka")), allow(dead_code))]
+pub fn map_subject(rules: &[Rule], subject: &str, key: Option<&str>) -> Option<String> {
+    rules.iter().find_map(|rule| rule.map(subject, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(rule: &str) -> Rule {
+        rule.parse().unwrap()
+    }
+
+    #[test]
+    fn test_map_wildcards() {
+        let rule = rule("orders.*.updated=order:{1}");
+
+        assert_eq!(
+            rule.map("orders.123.updated", None),
+            Some("order:123".to_owned())
+        );
+        assert_eq!(rule.map("orders.123.created", None), None);
+        assert_eq!(rule.map("orders.123.updated.extra", None), None);
+        assert_eq!(rule.map("orders.updated", None), None);
+    }
+
+    #[test]
+    fn test_map_tail_wildcard() {
+        let rule = rule("builds.>=builds:{1}");
+
+        assert_eq!(
+            rule.map("builds.infra.deploy", None),
+            Some("builds:infra.deploy".to_owned())
+        );
+        assert_eq!(rule.map("builds", None), None);
+    }
+
+    #[test]
+    fn test_map_subject_and_key() {
+        let by_key = rule("events=events:{key}");
+        let by_subject = rule("*.status={subject}");
+
+        assert_eq!(
+            by_key.map("events", Some("user-1")),
+            Some("events:user-1".to_owned())
+        );
+        // Messages without a key can't be mapped by a rule that needs one
+        assert_eq!(by_key.map("events", None), None);
+        assert_eq!(
+            by_subject.map("build.status", None),
+            Some("build.status".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let rules = parse_rules("orders.vip.*=vip:{1}, orders.*.*=order:{2}").unwrap();
+
+        assert_eq!(
+            map_subject(&rules, "orders.vip.1", None),
+            Some("vip:1".to_owned())
+        );
+        assert_eq!(
+            map_subject(&rules, "orders.regular.1", None),
+            Some("order:1".to_owned())
+        );
+        assert_eq!(map_subject(&rules, "payments.1", None), None);
+    }
+
+    #[test]
+    fn test_parse_invalid_rules() {
+        for rule in [
+            "orders",
+            "=topic",
+            "orders=",
+            "orders..x=topic",
+            "a.>.b=topic",
+        ] {
+            let err = rule.parse::<Rule>().unwrap_err();
+            assert_eq!(err.error_type, NotifluxErrorType::ConfigError);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Ingest messages from Kafka topics
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::Message;
+use std::time::Duration;
+
+use super::{map_subject, Rule};
+use crate::message;
+
+/// How long to wait after a failed receive, doubled on every failure in a row
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Start consuming the topics that the rules match, in the background
+pub fn start(
+    brokers: &str,
+    group_id: &str,
+    rules: Vec<Rule>,
+    server: actix::Recipient<message::Publish>,
+) -> rdkafka::error::KafkaResult<()> {
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("group.id", group_id)
+        .set("enable.auto.commit", "true")
+        // Notifications are only relevant as they happen, so don't replay old ones
+        .set("auto.offset.reset", "latest")
+        .create()?;
+
+    let topics: Vec<String> = rules
+        .iter()
+        .map(|rule| subscription(&rule.source))
+        .collect();
+    let topics: Vec<&str> = topics.iter().map(String::as_str).collect();
+    consumer.subscribe(&topics)?;
+    log::info!("Ingesting from Kafka at {}", brokers);
+
+    actix::spawn(async move {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let msg = match consumer.recv().await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    log::error!(
+                        "Error receiving from Kafka, retrying in {:?}: {}",
+                        backoff,
+                        e
+                    );
+                    actix::clock::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            };
+            backoff = INITIAL_BACKOFF;
+            let key = msg.key().and_then(|key| std::str::from_utf8(key).ok());
+            let Some(topic) = map_subject(&rules, msg.topic(), key) else {
+                log::debug!("No ingest rule for Kafka topic: {}", msg.topic());
+                continue;
+            };
+            // Anything that isn't UTF-8 is delivered as a binary message
+            let payload = match std::str::from_utf8(msg.payload().unwrap_or_default()) {
+                Ok(text) => message::Payload::from(text),
+                Err(_) => message::Payload::Binary(msg.payload().unwrap_or_default().to_vec()),
+            };
+
+            server.do_send(message::Publish {
+                payload,
+                topic,
+                retain: None,
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Kafka subscribes to a pattern through a regex starting with `^`
+fn subscription(source: &str) -> String {
+    if !source.contains('*') && !source.contains('>') {
+        return source.to_owned();
+    }
+
+    let parts: Vec<String> = source
+        .split('.')
+        .map(|part| match part {
+            "*" => r"[^.]+".to_owned(),
+            ">" => r".+".to_owned(),
+            part => regex_escape(part),
+        })
+        .collect();
+    format!("^{}$", parts.join(r"\."))
+}
+
+fn regex_escape(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '_' || c == '-' {
+                c.to_string()
+            } else {
+                format!(r"\{}", c)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::prelude::*;
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+    use std::sync::{Arc, Mutex};
+
+    struct Collector(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<message::Publish> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: message::Publish, _: &mut Context<Self>) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((msg.topic, msg.payload.to_string()));
+        }
+    }
+
+    #[actix::test]
+    #[ignore = "needs a Kafka broker at KAFKA_TEST_BROKERS, run by CI with --ignored"]
+    async fn test_ingest_from_kafka() {
+        let brokers =
+            std::env::var("KAFKA_TEST_BROKERS").unwrap_or_else(|_| "127.0.0.1:9092".to_owned());
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", &brokers)
+            .set("message.timeout.ms", "5000")
+            .create()
+            .unwrap();
+        let produce = |topic: &'static str, key: &'static str| {
+            producer.send(
+                FutureRecord::to(topic).key(key).payload("hello"),
+                Duration::from_secs(5),
+            )
+        };
+        // Creates the topics, if the broker creates them automatically
+        for topic in ["notiflux-orders", "notiflux-other"] {
+            produce(topic, "0").await.unwrap();
+        }
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let rules = super::super::parse_rules("notiflux-orders=order:{key}").unwrap();
+        let group_id = format!("notiflux-test-{}", ulid::Ulid::new());
+        start(
+            &brokers,
+            &group_id,
+            rules,
+            Collector(received.clone()).start().recipient(),
+        )
+        .unwrap();
+        // Only messages produced once the partitions are assigned are consumed
+        actix::clock::sleep(Duration::from_secs(5)).await;
+
+        produce("notiflux-orders", "1").await.unwrap();
+        produce("notiflux-other", "1").await.unwrap();
+        for _ in 0..50 {
+            if !received.lock().unwrap().is_empty() {
+                break;
+            }
+            actix::clock::sleep(Duration::from_millis(100)).await;
+        }
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![("order:1".to_owned(), "hello".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_subscription() {
+        assert_eq!(subscription("orders"), "orders");
+        assert_eq!(
+            subscription("orders.*.updated"),
+            r"^orders\.[^.]+\.updated$"
+        );
+        assert_eq!(subscription("builds.>"), r"^builds\..+$");
+    }
+}
@@ -0,0 +1,169 @@
+//! Ingest messages from NATS subjects
+use actix::prelude::*;
+use std::time::Duration;
+
+use super::{map_subject, Rule};
+use crate::message;
+
+/// How long to wait after a failed initial connection, doubled on every failure in a row
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct NatsIngest {
+    url: String,
+    rules: Vec<Rule>,
+    server: Recipient<message::Publish>,
+    backoff: Duration,
+}
+
+impl NatsIngest {
+    pub fn new(url: &str, rules: Vec<Rule>, server: Recipient<message::Publish>) -> Self {
+        NatsIngest {
+            url: url.to_owned(),
+            rules,
+            server,
+            backoff: INITIAL_BACKOFF,
+        }
+    }
+}
+
+impl Actor for NatsIngest {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connect(ctx);
+    }
+}
+
+impl NatsIngest {
+    fn connect(&mut self, ctx: &mut Context<Self>) {
+        let url = self.url.clone();
+        // The rule sources use the NATS wildcard syntax, so they can be subscribed to directly
+        let subjects: Vec<String> = self.rules.iter().map(|rule| rule.source.clone()).collect();
+
+        async move {
+            // The client reconnects by itself once the initial connection has been made
+            let client = async_nats::connect(url).await?;
+            let mut subscribers = Vec::new();
+            for subject in subjects {
+                subscribers.push(client.subscribe(subject).await?);
+            }
+            Ok::<_, async_nats::Error>(subscribers)
+        }
+        .into_actor(self)
+        .map(|res, act, ctx| match res {
+            Ok(subscribers) => {
+                log::info!("Ingesting from NATS at {}", act.url);
+                for subscriber in subscribers {
+                    ctx.add_stream(subscriber);
+                }
+            }
+            Err(e) => {
+                // NATS may start after notiflux, so keep trying rather than give up on ingesting
+                log::error!(
+                    "Unable to connect to NATS at {}, retrying in {:?}: {}",
+                    act.url,
+                    act.backoff,
+                    e
+                );
+                ctx.run_later(act.backoff, |act, ctx| act.connect(ctx));
+                act.backoff = (act.backoff * 2).min(MAX_BACKOFF);
+            }
+        })
+        .wait(ctx);
+    }
+}
+
+impl StreamHandler<async_nats::Message> for NatsIngest {
+    fn handle(&mut self, msg: async_nats::Message, _: &mut Context<Self>) {
+        let Some(topic) = map_subject(&self.rules, &msg.subject, None) else {
+            log::debug!("No ingest rule for NATS subject: {}", msg.subject);
+            return;
+        };
+        // Anything that isn't UTF-8 is delivered as a binary message
+        let payload = match String::from_utf8(msg.payload.to_vec()) {
+            Ok(text) => message::Payload::Text(text),
+            Err(e) => message::Payload::Binary(e.into_bytes()),
+        };
+
+        self.server.do_send(message::Publish {
+            payload,
+            topic,
+            retain: None,
+        });
+    }
+
+    fn finished(&mut self, _: &mut Context<Self>) {
+        // Subscriptions only end when the client is closed, keep the actor running for the
+        // remaining ones
+        log::warn!("NATS subscription ended");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    struct Collector(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<message::Publish> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: message::Publish, _: &mut Context<Self>) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((msg.topic, msg.payload.to_string()));
+        }
+    }
+
+    #[actix::test]
+    #[ignore = "needs a NATS server at NATS_TEST_URL, run by CI with --ignored"]
+    async fn test_ingest_from_nats() {
+        let url =
+            std::env::var("NATS_TEST_URL").unwrap_or_else(|_| "nats://127.0.0.1:4222".to_owned());
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let rules = super::super::parse_rules("orders.*.updated=order:{1}").unwrap();
+        NatsIngest::new(&url, rules, Collector(received.clone()).start().recipient()).start();
+        actix::clock::sleep(Duration::from_millis(200)).await;
+
+        let client = async_nats::connect(url).await.unwrap();
+        for subject in ["orders.1.updated", "orders.1.created"] {
+            client.publish(subject, "hello".into()).await.unwrap();
+        }
+        client.flush().await.unwrap();
+        actix::clock::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![("order:1".to_owned(), "hello".to_owned())]
+        );
+    }
+
+    #[actix::test]
+    async fn test_retries_initial_connect() {
+        // Nothing listens on the port, which was free a moment ago
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let collector = Collector(Default::default()).start();
+        let ingest = NatsIngest::new(
+            &format!("nats://127.0.0.1:{}", port),
+            Vec::new(),
+            collector.recipient(),
+        )
+        .start();
+        actix::clock::sleep(Duration::from_millis(500)).await;
+
+        assert!(ingest.connected());
+    }
+}
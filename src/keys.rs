@@ -0,0 +1,125 @@
+//! Key pairs and tokens, for the `gen-keys` and `token` commands
+use base64::prelude::*;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::auth::{sign_claims, Claims};
+use crate::{NotifluxError, NotifluxErrorType};
+
+/// The DER encoded SubjectPublicKeyInfo header of a P-256 public key, which is followed by the
+/// uncompressed point
+const P256_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+/// An ES256 key pair, with the private key to sign tokens with and the public key that notiflux
+/// verifies them with
+pub struct KeyPair {
+    pub private_key_pem: String,
+    pub public_key_pem: String,
+}
+
+impl KeyPair {
+    pub fn generate() -> Result<KeyPair, NotifluxError> {
+        let rng = SystemRandom::new();
+        let error = || NotifluxError {
+            message: Some("Unable to generate a key pair".to_owned()),
+            error_type: NotifluxErrorType::Error,
+        };
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            .map_err(|_| error())?;
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .map_err(|_| error())?;
+        let spki = [P256_SPKI_PREFIX, key_pair.public_key().as_ref()].concat();
+
+        Ok(KeyPair {
+            private_key_pem: pem("PRIVATE KEY", pkcs8.as_ref()),
+            public_key_pem: pem("PUBLIC KEY", &spki),
+        })
+    }
+
+    /// The public key as it's passed to notiflux in `JWT_PUBLIC_KEY_B64`
+    pub fn public_key_b64(&self) -> String {
+        BASE64_STANDARD.encode(&self.public_key_pem)
+    }
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = BASE64_STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+/// What a minted token allows
+pub struct TokenOptions {
+    pub sub: String,
+    /// One of `subscribe`, `broadcast` or `send`
+    pub scope: String,
+    pub topics: Vec<String>,
+    /// Subjects or session ids, for tokens with the send scope
+    pub targets: Vec<String>,
+    pub presence: bool,
+    pub expires_in: Duration,
+}
+
+/// Sign a token with an ES256 private key in PKCS8 PEM format
+pub fn mint_token(private_key_pem: &[u8], options: &TokenOptions) -> Result<String, NotifluxError> {
+    let exp = (SystemTime::now() + options.expires_in)
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let mut claims = Claims::new(&options.sub, &options.scope, options.topics.clone(), exp)
+        .with_targets(options.targets.clone());
+    claims.presence = options.presence;
+
+    sign_claims(&claims, private_key_pem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{get_claims, Action};
+
+    fn options(scope: &str) -> TokenOptions {
+        TokenOptions {
+            sub: "cli".to_owned(),
+            scope: scope.to_owned(),
+            topics: vec!["foo".to_owned()],
+            targets: Vec::new(),
+            presence: true,
+            expires_in: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn test_generated_key_pair_signs_tokens() {
+        let key_pair = KeyPair::generate().unwrap();
+        let token = mint_token(key_pair.private_key_pem.as_bytes(), &options("broadcast")).unwrap();
+
+        let public_key = BASE64_STANDARD.decode(key_pair.public_key_b64()).unwrap();
+        let claims = get_claims(&token, &public_key).unwrap();
+        assert_eq!(claims.sub, "cli");
+        assert!(claims.presence);
+        assert_eq!(
+            claims.action().unwrap(),
+            Action::Broadcast(vec!["foo".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_mint_token_rejects_invalid_scope() {
+        let private_key = include_bytes!("../scripts/private_key_pkcs8.pem");
+
+        assert!(mint_token(private_key, &options("subscribe")).is_ok());
+        assert!(mint_token(private_key, &options("admin")).is_err());
+    }
+}
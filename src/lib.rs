@@ -1,10 +1,27 @@
 mod app;
 mod auth;
+mod backplane;
+mod builder;
 mod config;
 mod error;
+mod filter;
+mod ingest;
+mod keys;
+mod limits;
 mod message;
+mod projection;
 mod server;
 mod session;
+mod tls;
+mod webhook;
 
 pub use app::run;
+pub use auth::{Authorizer, Credential, Decision, Grant, JwtAuthorizer, Operation};
+pub use builder::{Notiflux, NotifluxBuilder, NotifluxHandle};
+pub use config::{
+    ClientAuthConfig, ClusterConfig, Config, HeartbeatConfig, KafkaConfig, LimitsConfig,
+    NatsConfig, Rate, RateLimitsConfig, ShutdownConfig, TlsConfig, WebhookConfig, SETTINGS,
+};
 pub use error::{NotifluxError, NotifluxErrorType};
+pub use ingest::Rule;
+pub use keys::{mint_token, KeyPair, TokenOptions};
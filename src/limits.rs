@@ -0,0 +1,708 @@
+//! Limits on connections, subscriptions and sizes, and rate limits on broadcasts and commands,
+//! with counters of what they rejected
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{LimitsConfig, Rate, RateLimitsConfig};
+use crate::{NotifluxError, NotifluxErrorType};
+
+/// How often full buckets are forgotten, as they're the same as new ones
+pub(crate) const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// Buckets kept at most, past which the least recently used is forgotten for a new one
+const MAX_BUCKETS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Limit {
+    Connections,
+    ConnectionsPerIp,
+    ConnectionsPerSub,
+    TopicsPerSession,
+    SubscribersPerTopic,
+}
+
+const LIMITS: [Limit; 5] = [
+    Limit::Connections,
+    Limit::ConnectionsPerIp,
+    Limit::ConnectionsPerSub,
+    Limit::TopicsPerSession,
+    Limit::SubscribersPerTopic,
+];
+
+impl Limit {
+    fn name(self) -> &'static str {
+        match self {
+            Limit::Connections => "connections",
+            Limit::ConnectionsPerIp => "connections_per_ip",
+            Limit::ConnectionsPerSub => "connections_per_sub",
+            Limit::TopicsPerSession => "topics_per_session",
+            Limit::SubscribersPerTopic => "subscribers_per_topic",
+        }
+    }
+}
+
+/// What a rate limit is counted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RateKey<'a> {
+    Ip(IpAddr),
+    Sub(&'a str),
+    Topic(&'a str),
+}
+
+const RATE_KEYS: [&str; 3] = ["ip", "sub", "topic"];
+
+impl RateKey<'_> {
+    fn index(self) -> usize {
+        match self {
+            RateKey::Ip(_) => 0,
+            RateKey::Sub(_) => 1,
+            RateKey::Topic(_) => 2,
+        }
+    }
+
+    fn to_owned(self) -> BucketKey {
+        match self {
+            RateKey::Ip(ip) => BucketKey::Ip(ip),
+            RateKey::Sub(sub) => BucketKey::Sub(sub.to_owned()),
+            RateKey::Topic(topic) => BucketKey::Topic(topic.to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketKey {
+    Ip(IpAddr),
+    Sub(String),
+    Topic(String),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket was last used, in the order of `Buckets::used`
+    used: u64,
+}
+
+impl Bucket {
+    fn full(rate: &Rate) -> Bucket {
+        Bucket {
+            tokens: rate.requests as f64,
+            updated: Instant::now(),
+            used: 0,
+        }
+    }
+
+    fn refill(&mut self, rate: &Rate) {
+        let now = Instant::now();
+        let per_second = rate.requests as f64 / rate.period.as_secs_f64();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(rate.requests as f64);
+        self.updated = now;
+    }
+
+    /// Take a token, or how long until there is one
+    fn take(&mut self, rate: &Rate) -> Result<(), Duration> {
+        self.refill(rate);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let per_second = rate.requests as f64 / rate.period.as_secs_f64();
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / per_second))
+    }
+}
+
+/// The buckets of the keys, and the order they were used in so that the least recently used one
+/// can be forgotten in logarithmic time
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<BucketKey, Bucket>,
+    used: BTreeMap<u64, BucketKey>,
+    clock: u64,
+}
+
+impl Buckets {
+    /// The bucket of the key, a full one if it's new, marked as the most recently used
+    fn get(&mut self, key: BucketKey, rate: &Rate) -> &mut Bucket {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.buckets.get(&key) {
+            Some(bucket) => {
+                self.used.remove(&bucket.used);
+            }
+            None if self.buckets.len() >= MAX_BUCKETS => {
+                if let Some((_, oldest)) = self.used.pop_first() {
+                    self.buckets.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+        self.used.insert(clock, key.clone());
+        let bucket = self
+            .buckets
+            .entry(key)
+            .or_insert_with(|| Bucket::full(rate));
+        bucket.used = clock;
+        bucket
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&BucketKey, &mut Bucket) -> bool) {
+        let used = &mut self.used;
+        self.buckets.retain(|key, bucket| {
+            let kept = keep(key, bucket);
+            if !kept {
+                used.remove(&bucket.used);
+            }
+            kept
+        });
+    }
+}
+
+/// A request over a rate limit, which can be retried after a while
+#[derive(Debug, PartialEq)]
+pub(crate) struct RateLimited {
+    pub(crate) message: String,
+    pub(crate) retry_after: Duration,
+}
+
+impl From<RateLimited> for NotifluxError {
+    fn from(limited: RateLimited) -> Self {
+        NotifluxError {
+            message: Some(format!(
+                "{}, retry in {}ms",
+                limited.message,
+                limited.retry_after.as_millis()
+            )),
+            error_type: NotifluxErrorType::TooManyRequests,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Shared by the HTTP workers, which admit connections, and the server, which checks the
+/// subscription limits
+#[derive(Debug, Default)]
+pub(crate) struct Limiter {
+    config: LimitsConfig,
+    rates: RateLimitsConfig,
+    connections: Mutex<Connections>,
+    buckets: Mutex<Buckets>,
+    rejected: [AtomicU64; LIMITS.len()],
+    rate_limited: [AtomicU64; RATE_KEYS.len()],
+}
+
+impl Limiter {
+    pub(crate) fn new(config: LimitsConfig, rates: RateLimitsConfig) -> Limiter {
+        Limiter {
+            config,
+            rates,
+            ..Limiter::default()
+        }
+    }
+
+    pub(crate) fn config(&self) -> &LimitsConfig {
+        &self.config
+    }
+
+    /// Admit a connection from the address, which counts towards the limits until the returned
+    /// guard is dropped
+    pub(crate) fn connect(
+        self: &Arc<Self>,
+        ip: Option<IpAddr>,
+    ) -> Result<ConnectionGuard, NotifluxError> {
+        let mut connections = self.connections.lock().unwrap();
+
+        if let Some(max) = self.config.max_connections {
+            if connections.total >= max {
+                self.exceeded(Limit::Connections);
+                return Err(NotifluxError {
+                    message: Some("Too many connections".to_owned()),
+                    error_type: NotifluxErrorType::Unavailable,
+                });
+            }
+        }
+        if let (Some(ip), Some(max)) = (ip, self.config.max_connections_per_ip) {
+            if connections
+                .per_ip
+                .get(&ip)
+                .is_some_and(|count| *count >= max)
+            {
+                self.exceeded(Limit::ConnectionsPerIp);
+                return Err(NotifluxError {
+                    message: Some(format!("Too many connections from {}", ip)),
+                    error_type: NotifluxErrorType::TooManyRequests,
+                });
+            }
+        }
+
+        connections.total += 1;
+        if let Some(ip) = ip {
+            *connections.per_ip.entry(ip).or_default() += 1;
+        }
+        Ok(ConnectionGuard {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    fn disconnect(&self, ip: Option<IpAddr>) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.total -= 1;
+        if let Some(ip) = ip {
+            if let Some(count) = connections.per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn validate_topic(&self, topic: &str) -> Result<(), NotifluxError> {
+        let invalid = |message: String| NotifluxError {
+            message: Some(message),
+            error_type: NotifluxErrorType::ValidationError,
+        };
+
+        if topic.is_empty() {
+            return Err(invalid("Topic can't be empty".to_owned()));
+        }
+        if let Some(max) = self.config.max_topic_length {
+            if topic.len() > max {
+                return Err(invalid(format!("Topic is longer than {} bytes", max)));
+            }
+        }
+        let strict = self.config.strict_topics;
+        if let Some(c) = topic.chars().find(|c| {
+            c.is_control() || (strict && !c.is_ascii_alphanumeric() && !"-_.:@".contains(*c))
+        }) {
+            return Err(invalid(format!("Topic can't contain {:?}", c)));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn validate_message(&self, message: impl AsRef<[u8]>) -> Result<(), NotifluxError> {
+        match self.config.max_message_size {
+            Some(max) if message.as_ref().len() > max => Err(NotifluxError {
+                message: Some(format!("Message is larger than {} bytes", max)),
+                error_type: NotifluxErrorType::ValidationError,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count a rejection by the limit
+    pub(crate) fn exceeded(&self, limit: Limit) {
+        self.rejected[limit as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take a token from the bucket of the key, if there's a rate limit for it
+    pub(crate) fn check_rate(&self, key: RateKey) -> Result<(), RateLimited> {
+        let rate = match key {
+            RateKey::Ip(_) => self.rates.per_ip,
+            RateKey::Sub(_) => self.rates.per_sub,
+            RateKey::Topic(_) => self.rates.per_topic,
+        };
+        let Some(rate) = rate else {
+            return Ok(());
+        };
+
+        let result = self
+            .buckets
+            .lock()
+            .unwrap()
+            .get(key.to_owned(), &rate)
+            .take(&rate);
+        result.map_err(|retry_after| {
+            self.rate_limited[key.index()].fetch_add(1, Ordering::Relaxed);
+            let message = match key {
+                RateKey::Ip(ip) => format!("Rate limit exceeded for {}", ip),
+                RateKey::Sub(sub) => format!("Rate limit exceeded for {}", sub),
+                RateKey::Topic(topic) => format!("Rate limit exceeded for topic {}", topic),
+            };
+            RateLimited {
+                message,
+                retry_after,
+            }
+        })
+    }
+
+    /// Forget the full buckets, called every `PRUNE_INTERVAL`
+    pub(crate) fn prune_buckets(&self) {
+        let rates = &self.rates;
+        self.buckets.lock().unwrap().retain(|key, bucket| {
+            let rate = match key {
+                BucketKey::Ip(_) => rates.per_ip,
+                BucketKey::Sub(_) => rates.per_sub,
+                BucketKey::Topic(_) => rates.per_topic,
+            };
+            rate.is_some_and(|rate| {
+                bucket.refill(&rate);
+                bucket.tokens < rate.requests as f64
+            })
+        });
+    }
+
+    /// The open connections, rejections and rate limited requests in the Prometheus text format
+    pub(crate) fn metrics(&self) -> String {
+        let total = self.connections.lock().unwrap().total;
+        let mut metrics = String::new();
+
+        let _ = writeln!(
+            metrics,
+            "# HELP notiflux_connections Open WebSocket connections\n\
+             # TYPE notiflux_connections gauge\n\
+             notiflux_connections {}",
+            total
+        );
+        let _ = writeln!(
+            metrics,
+            "# HELP notiflux_limit_rejections_total Connections and subscriptions rejected by a limit\n\
+             # TYPE notiflux_limit_rejections_total counter"
+        );
+        for limit in LIMITS {
+            let _ = writeln!(
+                metrics,
+                "notiflux_limit_rejections_total{{limit=\"{}\"}} {}",
+                limit.name(),
+                self.rejected[limit as usize].load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(
+            metrics,
+            "# HELP notiflux_rate_limited_total Broadcasts and commands rejected by a rate limit\n\
+             # TYPE notiflux_rate_limited_total counter"
+        );
+        for (index, key) in RATE_KEYS.iter().enumerate() {
+            let _ = writeln!(
+                metrics,
+                "notiflux_rate_limited_total{{key=\"{}\"}} {}",
+                key,
+                self.rate_limited[index].load(Ordering::Relaxed)
+            );
+        }
+        metrics
+    }
+}
+
+/// Held by a session for as long as it's connected
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard {
+    limiter: Arc<Limiter>,
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionGuard {
+    pub(crate) fn limiter(&self) -> &Limiter {
+        &self.limiter
+    }
+
+    /// Take a token for a command from the bucket of the connection's address
+    pub(crate) fn check_rate(&self) -> Result<(), RateLimited> {
+        match self.ip {
+            Some(ip) => self.limiter.check_rate(RateKey::Ip(ip)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.limiter.disconnect(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::test_utils::sign_token;
+    use crate::builder::test_utils::*;
+    use tokio_tungstenite::tungstenite;
+
+    #[test]
+    fn test_connection_limits() {
+        let limiter = Arc::new(Limiter::new(
+            LimitsConfig {
+                max_connections: Some(3),
+                max_connections_per_ip: Some(2),
+                ..LimitsConfig::default()
+            },
+            RateLimitsConfig::default(),
+        ));
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        let second: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let a = limiter.connect(Some(first)).unwrap();
+        let _b = limiter.connect(Some(first)).unwrap();
+        let err = limiter.connect(Some(first)).unwrap_err();
+        assert_eq!(err.error_type, NotifluxErrorType::TooManyRequests);
+
+        let _c = limiter.connect(Some(second)).unwrap();
+        let err = limiter.connect(Some(second)).unwrap_err();
+        assert_eq!(err.error_type, NotifluxErrorType::Unavailable);
+
+        drop(a);
+        let _d = limiter.connect(Some(first)).unwrap();
+
+        let metrics = limiter.metrics();
+        assert!(metrics.contains("notiflux_connections 3\n"));
+        assert!(metrics.contains("notiflux_limit_rejections_total{limit=\"connections\"} 1\n"));
+        assert!(
+            metrics.contains("notiflux_limit_rejections_total{limit=\"connections_per_ip\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let limiter = Limiter::new(
+            LimitsConfig {
+                max_message_size: Some(5),
+                max_topic_length: Some(12),
+                ..LimitsConfig::default()
+            },
+            RateLimitsConfig::default(),
+        );
+
+        for topic in ["build:123", "a-b_c.d@e:f", "a/b", "foo bar", "büild", "🔔"] {
+            assert!(limiter.validate_topic(topic).is_ok(), "{}", topic);
+        }
+        for (topic, expected) in [
+            ("", "Topic can't be empty"),
+            ("build:1234567", "Topic is longer than 12 bytes"),
+            ("build\n123", "Topic can't contain '\\n'"),
+            ("build\u{0}", "Topic can't contain '\\0'"),
+        ] {
+            let err = limiter.validate_topic(topic).unwrap_err();
+            assert_eq!(err.error_type, NotifluxErrorType::ValidationError);
+            assert_eq!(err.message(), expected);
+        }
+
+        let strict = Limiter::new(
+            LimitsConfig {
+                strict_topics: true,
+                ..LimitsConfig::default()
+            },
+            RateLimitsConfig::default(),
+        );
+        assert!(strict.validate_topic("a-b_c.d@e:f").is_ok());
+        for (topic, expected) in [
+            ("a/b", "Topic can't contain '/'"),
+            ("build 123", "Topic can't contain ' '"),
+            ("büild", "Topic can't contain 'ü'"),
+        ] {
+            assert_eq!(
+                strict.validate_topic(topic).unwrap_err().message(),
+                expected
+            );
+        }
+
+        assert!(limiter.validate_message("hello").is_ok());
+        assert_eq!(
+            limiter.validate_message("hello!").unwrap_err().message(),
+            "Message is larger than 5 bytes"
+        );
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let limiter = Limiter::new(
+            LimitsConfig::default(),
+            RateLimitsConfig {
+                per_topic: Some(Rate {
+                    requests: 2,
+                    period: Duration::from_secs(1),
+                }),
+                ..RateLimitsConfig::default()
+            },
+        );
+
+        assert!(limiter.check_rate(RateKey::Topic("foo")).is_ok());
+        assert!(limiter.check_rate(RateKey::Topic("foo")).is_ok());
+        let limited = limiter.check_rate(RateKey::Topic("foo")).unwrap_err();
+        assert_eq!(limited.message, "Rate limit exceeded for topic foo");
+        assert!(limited.retry_after <= Duration::from_millis(500));
+
+        // Other topics have their own buckets, and there's no limit on the other keys
+        assert!(limiter.check_rate(RateKey::Topic("bar")).is_ok());
+        for _ in 0..10 {
+            assert!(limiter.check_rate(RateKey::Sub("alice")).is_ok());
+        }
+
+        std::thread::sleep(limited.retry_after + Duration::from_millis(10));
+        assert!(limiter.check_rate(RateKey::Topic("foo")).is_ok());
+        assert!(limiter
+            .metrics()
+            .contains("notiflux_rate_limited_total{key=\"topic\"} 1\n"));
+    }
+
+    #[test]
+    fn test_buckets_pruned_and_capped() {
+        let per_ip = |period| RateLimitsConfig {
+            per_ip: Some(Rate {
+                requests: 2,
+                period,
+            }),
+            ..RateLimitsConfig::default()
+        };
+        let ip = |n: u128| RateKey::Ip(IpAddr::from(n.to_be_bytes()));
+        let tracked = |limiter: &Limiter| limiter.buckets.lock().unwrap().buckets.len();
+
+        let limiter = Limiter::new(LimitsConfig::default(), per_ip(Duration::from_secs(60)));
+        for n in 0..MAX_BUCKETS as u128 {
+            assert!(limiter.check_rate(ip(n)).is_ok());
+        }
+        // Address 0 was used again, so address 1 is the least recently used
+        assert!(limiter.check_rate(ip(0)).is_ok());
+        assert!(limiter.check_rate(ip(0)).is_err());
+
+        // A new address gets a bucket in place of it, rather than being refused
+        assert!(limiter.check_rate(ip(u128::MAX)).is_ok());
+        assert!(limiter.check_rate(ip(u128::MAX)).is_ok());
+        assert_eq!(tracked(&limiter), MAX_BUCKETS);
+        assert!(limiter.check_rate(ip(0)).is_err());
+        assert!(!limiter
+            .buckets
+            .lock()
+            .unwrap()
+            .buckets
+            .contains_key(&BucketKey::Ip(IpAddr::from(1u128.to_be_bytes()))));
+
+        // Only full buckets are pruned
+        let limiter = Limiter::new(LimitsConfig::default(), per_ip(Duration::from_millis(50)));
+        assert!(limiter.check_rate(ip(0)).is_ok());
+        limiter.prune_buckets();
+        assert_eq!(tracked(&limiter), 1);
+        std::thread::sleep(Duration::from_millis(60));
+        limiter.prune_buckets();
+        assert_eq!(tracked(&limiter), 0);
+        assert!(limiter.buckets.lock().unwrap().used.is_empty());
+    }
+
+    #[actix::test]
+    async fn test_connection_limit() {
+        let mut config = config();
+        config.limits.max_connections = Some(1);
+        let (addr, _) = serve(config);
+        let ws_url = format!("ws://{}/notiflux/ws", addr);
+
+        let stream = connect(addr, "").await;
+        match tokio_tungstenite::connect_async(&ws_url).await {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 503),
+            result => panic!(
+                "Expected the upgrade to be refused, got {:?}",
+                result.map(|_| ())
+            ),
+        }
+
+        let metrics = reqwest::get(url(addr, "/metrics"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(metrics.contains("notiflux_connections 1\n"));
+        assert!(metrics.contains("notiflux_limit_rejections_total{limit=\"connections\"} 1\n"));
+
+        drop(stream);
+        actix::clock::sleep(Duration::from_millis(100)).await;
+        assert!(tokio_tungstenite::connect_async(&ws_url).await.is_ok());
+    }
+
+    #[actix::test]
+    async fn test_rate_limited_by_ip() {
+        let mut config = config();
+        config.rate_limits.per_ip = Some("3/m".parse().unwrap());
+        let (addr, _) = serve(config);
+
+        let mut stream = connect(addr, "").await;
+        for _ in 0..3 {
+            send(&mut stream, "/unsubscribe foo").await;
+        }
+        // Pings don't count
+        send(&mut stream, "/ping").await;
+        send(&mut stream, "/unsubscribe foo").await;
+
+        assert_eq!(next(&mut stream).await, tungstenite::Message::text("/pong"));
+        let reply = next(&mut stream).await.into_text().unwrap();
+        let event: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(event["event"], "error");
+        assert_eq!(event["error"], "rate_limited");
+        assert_eq!(event["message"], "Rate limit exceeded for 127.0.0.1");
+        assert!(event["retry_after_ms"].as_u64().unwrap() > 0);
+
+        // The address shares its bucket with broadcasts
+        let response = reqwest::Client::new()
+            .post(url(addr, "/broadcast"))
+            .json(&serde_json::json!({
+                "topic": "foo",
+                "message": "hello",
+                "token": sign_token("broadcast", &["foo"]),
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 429);
+    }
+
+    #[actix::test]
+    async fn test_rate_limited_by_forwarded_ip() {
+        let broadcast = |addr, forwarded_for: &str| {
+            reqwest::Client::new()
+                .post(url(addr, "/broadcast"))
+                .header("X-Forwarded-For", forwarded_for)
+                .json(&serde_json::json!({
+                    "topic": "foo",
+                    "message": "hello",
+                    "token": sign_token("broadcast", &["foo"]),
+                }))
+                .send()
+        };
+
+        let mut config = config();
+        config.rate_limits.per_ip = Some("1/m".parse().unwrap());
+        let (addr, _) = serve(config.clone());
+        // Without a trusted proxy, the header could be set by anyone
+        assert!(broadcast(addr, "10.0.0.1")
+            .await
+            .unwrap()
+            .status()
+            .is_success());
+        assert_eq!(broadcast(addr, "10.0.0.2").await.unwrap().status(), 429);
+
+        config.limits.trust_proxy = true;
+        let (addr, _) = serve(config);
+        assert!(broadcast(addr, "10.0.0.1")
+            .await
+            .unwrap()
+            .status()
+            .is_success());
+        assert!(broadcast(addr, "10.0.0.2")
+            .await
+            .unwrap()
+            .status()
+            .is_success());
+        // Only the entry the proxy appended counts, whatever the client sent before it
+        assert!(broadcast(addr, "spoofed, 10.0.0.9")
+            .await
+            .unwrap()
+            .status()
+            .is_success());
+        let response = broadcast(addr, "10.0.0.3, 10.0.0.9").await.unwrap();
+        assert_eq!(response.status(), 429);
+        let error = response.json::<serde_json::Value>().await.unwrap()["error"].clone();
+        assert!(error
+            .as_str()
+            .unwrap()
+            .starts_with("Rate limit exceeded for 10.0.0.9"));
+    }
+}
@@ -1,6 +1,409 @@
-use notiflux::NotifluxError;
+use base64::prelude::*;
+use clap::{Arg, ArgMatches, Args, FromArgMatches, Parser, Subcommand, ValueEnum};
+use futures_util::{SinkExt, StreamExt};
+use notiflux::{Config, KeyPair, NotifluxError, NotifluxErrorType, TokenOptions, SETTINGS};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite;
+
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Running without a command serves, with these flags
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server, which is the default when no command is given
+    Serve(ServeArgs),
+    /// Generate an ES256 key pair and print the base64 encoded public key
+    GenKeys {
+        /// Where to write the private key, for signing tokens
+        #[arg(long, default_value = "notiflux_private.pem")]
+        private_key: PathBuf,
+        /// Where to also write the public key in PEM format
+        #[arg(long)]
+        public_key: Option<PathBuf>,
+        /// Overwrite existing key files
+        #[arg(long)]
+        force: bool,
+    },
+    /// Mint a token for the given topics
+    Token {
+        /// Private key to sign the token with
+        #[arg(
+            long,
+            env = "NOTIFLUX_PRIVATE_KEY",
+            default_value = "notiflux_private.pem"
+        )]
+        private_key: PathBuf,
+        /// Subject of the token
+        #[arg(long, default_value = "notiflux")]
+        sub: String,
+        /// Seconds until the token expires
+        #[arg(long, default_value_t = 3600)]
+        expires_in: u64,
+        /// Allow seeing who else is subscribed to the topics
+        #[arg(long)]
+        presence: bool,
+        /// Subject or session that a token with the send scope may message, can be repeated
+        #[arg(long = "target")]
+        targets: Vec<String>,
+        scope: Scope,
+        topics: Vec<String>,
+    },
+    /// Broadcast a message to a topic
+    Publish {
+        #[command(flatten)]
+        connection: Connection,
+        /// Keep the message as the last value of the topic
+        #[arg(long)]
+        retain: bool,
+        /// Seconds to keep the retained message for
+        #[arg(long, requires = "retain")]
+        retain_ttl: Option<u64>,
+        topic: String,
+        /// The message, read from stdin when left out
+        message: Option<String>,
+    },
+    /// Subscribe to topics and print their messages to stdout
+    Subscribe {
+        #[command(flatten)]
+        connection: Connection,
+        /// Print every message in a JSON envelope with its topic, id and timestamp
+        #[arg(long)]
+        envelope: bool,
+        #[arg(required = true)]
+        topics: Vec<String>,
+    },
+}
+
+#[derive(Args, Default)]
+struct ServeArgs {
+    /// TOML or YAML config file
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Only validate the configuration and exit
+    #[arg(long)]
+    check_config: bool,
+    #[command(flatten)]
+    settings: SettingFlags,
+}
+
+/// A flag for every setting, such as `--tls-cert-path` for `TLS_CERT_PATH`, which override the
+/// config file and env vars
+#[derive(Default)]
+struct SettingFlags(HashMap<String, String>);
+
+impl FromArgMatches for SettingFlags {
+    fn from_arg_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let settings = SETTINGS
+            .iter()
+            .filter_map(|(key, _)| {
+                matches
+                    .get_one::<String>(key)
+                    .map(|value| (key.to_string(), value.clone()))
+            })
+            .collect();
+        Ok(SettingFlags(settings))
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), clap::Error> {
+        *self = SettingFlags::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+
+impl Args for SettingFlags {
+    fn augment_args(cmd: clap::Command) -> clap::Command {
+        cmd.args(SETTINGS.iter().map(|(key, help)| {
+            Arg::new(*key)
+                .long(key.to_lowercase().replace('_', "-"))
+                .value_name("VALUE")
+                .help(*help)
+                .help_heading("Settings")
+        }))
+    }
+
+    fn augment_args_for_update(cmd: clap::Command) -> clap::Command {
+        SettingFlags::augment_args(cmd)
+    }
+}
+
+#[derive(Args)]
+struct Connection {
+    /// Base URL of the notiflux server
+    #[arg(long, env = "NOTIFLUX_URL", default_value = "http://127.0.0.1:8080")]
+    url: String,
+    #[arg(long, env = "NOTIFLUX_TOKEN")]
+    token: String,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Scope {
+    Subscribe,
+    Broadcast,
+    Send,
+}
+
+impl Scope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Subscribe => "subscribe",
+            Scope::Broadcast => "broadcast",
+            Scope::Send => "send",
+        }
+    }
+}
 
 #[actix_web::main]
-async fn main() -> Result<(), NotifluxError> {
-    notiflux::run().await
+async fn main() {
+    let cli = Cli::parse();
+
+    let result = match cli.command.unwrap_or(Command::Serve(cli.serve)) {
+        Command::Serve(args) => serve(args).await,
+        Command::GenKeys {
+            private_key,
+            public_key,
+            force,
+        } => gen_keys(&private_key, public_key.as_deref(), force),
+        Command::Token {
+            private_key,
+            sub,
+            expires_in,
+            presence,
+            targets,
+            scope,
+            topics,
+        } => token(
+            &private_key,
+            &TokenOptions {
+                sub,
+                scope: scope.as_str().to_owned(),
+                topics,
+                targets,
+                presence,
+                expires_in: Duration::from_secs(expires_in),
+            },
+        ),
+        Command::Publish {
+            connection,
+            retain,
+            retain_ttl,
+            topic,
+            message,
+        } => publish(&connection, &topic, message, retain, retain_ttl).await,
+        Command::Subscribe {
+            connection,
+            envelope,
+            topics,
+        } => subscribe(&connection, &topics, envelope).await,
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn error(error_type: NotifluxErrorType, message: String) -> NotifluxError {
+    NotifluxError {
+        message: Some(message),
+        error_type,
+    }
+}
+
+async fn serve(args: ServeArgs) -> Result<(), NotifluxError> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
+
+    let config = Config::load(args.config.as_deref(), args.settings.0)?;
+    if args.check_config {
+        println!("Configuration is valid");
+        return Ok(());
+    }
+
+    notiflux::run(config).await
+}
+
+fn gen_keys(
+    private_key: &Path,
+    public_key: Option<&Path>,
+    force: bool,
+) -> Result<(), NotifluxError> {
+    for path in [Some(private_key), public_key].into_iter().flatten() {
+        if path.exists() && !force {
+            return Err(error(
+                NotifluxErrorType::ValidationError,
+                format!(
+                    "{} already exists, pass --force to overwrite it",
+                    path.display()
+                ),
+            ));
+        }
+    }
+
+    let key_pair = KeyPair::generate()?;
+    write_private(private_key, &key_pair.private_key_pem)?;
+    if let Some(public_key) = public_key {
+        std::fs::write(public_key, &key_pair.public_key_pem)?;
+    }
+
+    eprintln!("Wrote the private key to {}", private_key.display());
+    println!("{}", key_pair.public_key_b64());
+    Ok(())
+}
+
+/// Write a file that only the current user can read
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    std::io::Write::write_all(&mut options.open(path)?, contents.as_bytes())
+}
+
+fn token(private_key: &Path, options: &TokenOptions) -> Result<(), NotifluxError> {
+    let private_key = std::fs::read(private_key).map_err(|e| {
+        error(
+            NotifluxErrorType::IOError,
+            format!("Unable to read {}: {}", private_key.display(), e),
+        )
+    })?;
+
+    println!("{}", notiflux::mint_token(&private_key, options)?);
+    Ok(())
+}
+
+async fn publish(
+    connection: &Connection,
+    topic: &str,
+    message: Option<String>,
+    retain: bool,
+    retain_ttl: Option<u64>,
+) -> Result<(), NotifluxError> {
+    let message = match message {
+        Some(message) => message,
+        None => {
+            let mut message = String::new();
+            std::io::stdin().read_to_string(&mut message)?;
+            message
+        }
+    };
+
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/broadcast",
+            connection.url.trim_end_matches('/')
+        ))
+        .json(&serde_json::json!({
+            "topic": topic,
+            "message": message,
+            "token": connection.token,
+            "retain": retain,
+            "retain_ttl": retain_ttl,
+        }))
+        .send()
+        .await
+        .map_err(|e| {
+            error(
+                NotifluxErrorType::Error,
+                format!("Unable to publish: {}", e),
+            )
+        })?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(error(
+            NotifluxErrorType::Error,
+            format!("Unable to publish: {} {}", status, body),
+        ));
+    }
+    Ok(())
+}
+
+async fn subscribe(
+    connection: &Connection,
+    topics: &[String],
+    envelope: bool,
+) -> Result<(), NotifluxError> {
+    let url = ws_url(&connection.url, envelope);
+    let ws_error =
+        |e: tungstenite::Error| error(NotifluxErrorType::Error, format!("WebSocket error: {}", e));
+
+    let (mut stream, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .map_err(ws_error)?;
+    for topic in topics {
+        let command = format!("/subscribe {} {}", topic, connection.token);
+        stream
+            .send(tungstenite::Message::text(command))
+            .await
+            .map_err(ws_error)?;
+    }
+
+    while let Some(message) = stream.next().await {
+        match message.map_err(ws_error)? {
+            tungstenite::Message::Text(text) => println!("{}", text.as_str()),
+            tungstenite::Message::Binary(data) => println!("{}", BASE64_STANDARD.encode(data)),
+            tungstenite::Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The WebSocket endpoint for a base URL, which can be given as http(s) or ws(s)
+fn ws_url(base: &str, envelope: bool) -> String {
+    let base = base.trim_end_matches('/');
+    let base = if let Some(rest) = base.strip_prefix("http") {
+        format!("ws{}", rest)
+    } else {
+        base.to_owned()
+    };
+
+    if envelope {
+        format!("{}/ws?envelope=true", base)
+    } else {
+        format!("{}/ws", base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ws_url() {
+        assert_eq!(
+            ws_url("http://localhost:8080/", false),
+            "ws://localhost:8080/ws"
+        );
+        assert_eq!(
+            ws_url("https://notiflux.example.com", true),
+            "wss://notiflux.example.com/ws?envelope=true"
+        );
+        assert_eq!(ws_url("ws://localhost", false), "ws://localhost/ws");
+    }
+
+    #[test]
+    fn test_setting_flags() {
+        let cli = Cli::parse_from(["notiflux", "--port", "9000", "--tls-cert-path", "cert.pem"]);
+
+        assert!(cli.command.is_none());
+        assert_eq!(cli.serve.settings.0.get("PORT").unwrap(), "9000");
+        assert_eq!(
+            cli.serve.settings.0.get("TLS_CERT_PATH").unwrap(),
+            "cert.pem"
+        );
+        assert_eq!(cli.serve.settings.0.len(), 2);
+    }
 }
@@ -1,9 +1,232 @@
 use actix::prelude::*;
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use ulid::Ulid;
 
-#[derive(Message)]
+use crate::auth::Credential;
+use crate::filter::Filter;
+use crate::limits::RateLimited;
+use crate::projection::Projection;
+use crate::{NotifluxError, NotifluxErrorType};
+
+#[derive(Message, Debug, Clone)]
 #[rtype(result = "()")]
-pub struct Message(pub String);
+pub enum Message {
+    /// A published message, wrapped in an envelope for sessions that asked for one
+    Delivery(Delivery),
+    /// A server generated event, such as a presence update or an error
+    Event(Event),
+    /// The server is shutting down, so the session is closed with a suggested delay before
+    /// reconnecting
+    GoingAway(Duration),
+}
+
+/// The body of a published message, delivered to sessions as a text or binary frame
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Payload {
+    /// The text of the message, to parse as JSON, which binary messages don't have
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Payload::Text(text) => Some(text),
+            Payload::Binary(_) => None,
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Payload::Binary(_))
+    }
+}
+
+impl From<&str> for Payload {
+    fn from(text: &str) -> Self {
+        Payload::Text(text.to_owned())
+    }
+}
+
+impl From<String> for Payload {
+    fn from(text: String) -> Self {
+        Payload::Text(text)
+    }
+}
+
+/// Text is encoded as a string and binary as bytes, which MessagePack and CBOR keep as they are.
+/// JSON has no bytes and would write an array of numbers, so binary payloads are base64 encoded
+/// before they get there, by the envelope of a [Delivery] and by the JSON codec of sessions.
+impl Serialize for Payload {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Payload::Text(text) => serializer.serialize_str(text),
+            Payload::Binary(bytes) => serializer.serialize_bytes(bytes),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Payload {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PayloadVisitor;
+
+        impl serde::de::Visitor<'_> for PayloadVisitor {
+            type Value = Payload;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string or bytes")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, text: &str) -> Result<Payload, E> {
+                Ok(Payload::from(text))
+            }
+
+            fn visit_string<E: serde::de::Error>(self, text: String) -> Result<Payload, E> {
+                Ok(Payload::Text(text))
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Payload, E> {
+                Ok(Payload::Binary(bytes.to_vec()))
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, bytes: Vec<u8>) -> Result<Payload, E> {
+                Ok(Payload::Binary(bytes))
+            }
+        }
+
+        deserializer.deserialize_any(PayloadVisitor)
+    }
+}
+
+impl AsRef<[u8]> for Payload {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Payload::Text(text) => text.as_bytes(),
+            Payload::Binary(bytes) => bytes,
+        }
+    }
+}
+
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Payload::Text(text) => write!(f, "{}", text),
+            Payload::Binary(bytes) => write!(f, "<{} bytes>", bytes.len()),
+        }
+    }
+}
+
+/// How the payload of a JSON envelope or request is encoded, as is when not set
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Base64,
+}
+
+/// A single published message, shared by every session it is delivered to. Serialized as the
+/// JSON envelope, with binary payloads in base64.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    /// The topic the message was published to, not set for direct messages
+    pub topic: Option<String>,
+    pub id: Ulid,
+    /// Milliseconds since the unix epoch when the server received the message
+    pub timestamp: u64,
+    pub payload: Payload,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<'a> {
+    topic: Option<Cow<'a, str>>,
+    #[serde(
+        serialize_with = "serialize_ulid",
+        deserialize_with = "deserialize_ulid"
+    )]
+    id: Ulid,
+    timestamp: u64,
+    payload: Cow<'a, str>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<Encoding>,
+}
+
+impl Serialize for Delivery {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (payload, encoding) = match &self.payload {
+            Payload::Text(text) => (Cow::Borrowed(text.as_str()), None),
+            Payload::Binary(bytes) => (
+                Cow::Owned(BASE64_STANDARD.encode(bytes)),
+                Some(Encoding::Base64),
+            ),
+        };
+
+        Envelope {
+            topic: self.topic.as_deref().map(Cow::Borrowed),
+            id: self.id,
+            timestamp: self.timestamp,
+            payload,
+            encoding,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Delivery {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let envelope = Envelope::deserialize(deserializer)?;
+        let payload = match envelope.encoding {
+            None => Payload::Text(envelope.payload.into_owned()),
+            Some(Encoding::Base64) => Payload::Binary(
+                BASE64_STANDARD
+                    .decode(envelope.payload.as_bytes())
+                    .map_err(serde::de::Error::custom)?,
+            ),
+        };
+
+        Ok(Delivery {
+            topic: envelope.topic.map(Cow::into_owned),
+            id: envelope.id,
+            timestamp: envelope.timestamp,
+            payload,
+        })
+    }
+}
+
+impl Delivery {
+    pub fn new(topic: Option<&str>, payload: impl Into<Payload>) -> Self {
+        Delivery {
+            topic: topic.map(str::to_owned),
+            id: Ulid::new(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .and_then(|d| u64::try_from(d.as_millis()).ok())
+                .unwrap_or_default(),
+            payload: payload.into(),
+        }
+    }
+
+    /// The same message with a different payload, such as a projection of the original one
+    pub fn with_payload(&self, payload: Payload) -> Self {
+        Delivery {
+            topic: self.topic.clone(),
+            id: self.id,
+            timestamp: self.timestamp,
+            payload,
+        }
+    }
+}
+
+fn serialize_ulid<S: serde::Serializer>(id: &Ulid, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(id)
+}
+
+fn deserialize_ulid<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Ulid, D::Error> {
+    let id = String::deserialize(deserializer)?;
+    Ulid::from_string(&id).map_err(serde::de::Error::custom)
+}
 
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -18,12 +241,37 @@ pub struct Disconnect {
     pub id: Ulid,
 }
 
+/// Stop accepting broadcasts and close every session once those in flight are delivered, telling
+/// each to wait a random delay up to `reconnect_delay` before reconnecting. Responds with the
+/// number of sessions being closed.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct Shutdown {
+    pub reconnect_delay: Duration,
+}
+
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct SessionCount;
+
+/// Optional settings a client can pass as a JSON object after the subscribe token
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct SubscribeOptions {
+    /// Client supplied metadata, shared with other subscribers through presence
+    pub meta: Option<serde_json::Value>,
+    /// Only deliver JSON messages that match the filter expression
+    pub filter: Option<Filter>,
+    /// Only deliver the selected fields of JSON messages
+    pub fields: Option<Projection>,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct SubscribeToTopic {
     pub id: Ulid,
     pub topic: String,
-    pub token: String,
+    pub credential: Credential,
+    pub options: SubscribeOptions,
 }
 
 #[derive(Message)]
@@ -42,7 +290,222 @@ pub struct UnsubscribeAll {
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct Broadcast {
+    /// The publishing session
+    pub id: Ulid,
+    pub payload: Payload,
+    pub topic: String,
+    pub credential: Credential,
+    /// Leave the publishing session out of the fan-out, for when it's subscribed to the topic
+    /// itself
+    pub exclude: bool,
+    /// Keep the message as the topic's last value, delivered to new subscribers
+    pub retain: Option<Retain>,
+}
+
+/// A broadcast from a trusted source, such as an ingest connector or an HTTP request that has
+/// already been authorised
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct Publish {
+    pub payload: Payload,
+    pub topic: String,
+    pub retain: Option<Retain>,
+}
+
+#[derive(Debug)]
+pub struct Retain {
+    /// How long the retained message is kept for, forever if not set
+    pub ttl: Option<Duration>,
+}
+
+/// Forget the retained message of a topic, which has already been authorised
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct ClearRetained {
+    pub topic: String,
+}
+
+/// A message broadcast on another instance, to be delivered to the local subscribers of its
+/// topic. It has already been authorised on the instance it was broadcast on.
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct Relay {
+    pub delivery: Delivery,
+    /// Set for direct messages, which are delivered to the local sessions of the target rather
+    /// than to the subscribers of a topic
+    pub target: Option<SendTarget>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SendTarget {
+    /// Every session that has authenticated with a token for this subject
+    Subject(String),
+    Session(
+        #[serde(
+            serialize_with = "serialize_ulid",
+            deserialize_with = "deserialize_ulid"
+        )]
+        Ulid,
+    ),
+}
+
+/// A message for the sessions of a subject or a single session, failing when the credential
+/// doesn't allow it
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), NotifluxError>")]
+pub struct DirectMessage {
     pub msg: String,
+    pub target: SendTarget,
+    pub credential: Credential,
+}
+
+/// How a presence query is authorised, either already by the caller or by a session that
+/// subscribed to the topic with the presence permission
+#[derive(Debug)]
+pub enum PresenceAuth {
+    Authorized,
+    Session(Ulid),
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Vec<PresenceEntry>, NotifluxError>")]
+pub struct GetPresence {
     pub topic: String,
-    pub token: String,
+    pub auth: PresenceAuth,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PresenceEntry {
+    pub session: String,
+    pub sub: String,
+    pub meta: Option<serde_json::Value>,
+}
+
+/// An event generated by the server for websocket clients, tagged with `event` when serialized
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Event {
+    Presence(PresenceEvent),
+    Error(ErrorEvent),
+}
+
+impl Event {
+    /// The event as sent to clients of the text protocol, which get most errors as plain text
+    /// and everything else as JSON
+    pub fn text(&self) -> String {
+        match self {
+            Event::Error(error) if error.retry_after_ms.is_none() => error.message.clone(),
+            event => serde_json::to_string(event).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<PresenceEvent> for Event {
+    fn from(event: PresenceEvent) -> Self {
+        Event::Presence(event)
+    }
+}
+
+impl From<ErrorEvent> for Event {
+    fn from(event: ErrorEvent) -> Self {
+        Event::Error(event)
+    }
+}
+
+/// Presence updates and replies sent to websocket clients
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum PresenceEvent {
+    Join {
+        topic: String,
+        #[serde(flatten)]
+        entry: PresenceEntry,
+    },
+    Leave {
+        topic: String,
+        #[serde(flatten)]
+        entry: PresenceEntry,
+    },
+    Presence {
+        topic: String,
+        members: Vec<PresenceEntry>,
+    },
+}
+
+/// An error sent to websocket clients, which they can tell apart from messages and handle
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename = "error")]
+pub struct ErrorEvent {
+    /// What kind of error it is, such as `invalid`, `forbidden` or `rate_limited`
+    pub error: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+impl ErrorEvent {
+    pub fn new(error: &'static str, message: impl Into<String>) -> Self {
+        ErrorEvent {
+            error,
+            message: message.into(),
+            retry_after_ms: None,
+        }
+    }
+
+    pub fn rate_limited(limited: &RateLimited) -> Self {
+        ErrorEvent {
+            error: "rate_limited",
+            message: limited.message.clone(),
+            retry_after_ms: Some(
+                u64::try_from(limited.retry_after.as_millis()).unwrap_or(u64::MAX),
+            ),
+        }
+    }
+}
+
+impl From<NotifluxError> for ErrorEvent {
+    fn from(error: NotifluxError) -> Self {
+        let kind = match error.error_type {
+            NotifluxErrorType::ValidationError => "invalid",
+            NotifluxErrorType::AuthorizationError => "forbidden",
+            NotifluxErrorType::TooManyRequests => "rate_limited",
+            NotifluxErrorType::Unavailable => "unavailable",
+            _ => "error",
+        };
+        ErrorEvent::new(kind, error.message())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delivery_envelope() {
+        let delivery = Delivery::new(Some("foo"), "hello");
+
+        let envelope: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&delivery).unwrap()).unwrap();
+
+        assert_eq!(envelope["topic"], "foo");
+        assert_eq!(envelope["id"], delivery.id.to_string());
+        assert_eq!(envelope["timestamp"], delivery.timestamp);
+        assert_eq!(envelope["payload"], "hello");
+        assert!(envelope.get("encoding").is_none());
+    }
+
+    #[test]
+    fn test_binary_delivery_envelope() {
+        let delivery = Delivery::new(Some("foo"), Payload::Binary(vec![0, 159, 146, 150]));
+
+        let json = serde_json::to_string(&delivery).unwrap();
+        let envelope: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(envelope["payload"], "AJ+Slg==");
+        assert_eq!(envelope["encoding"], "base64");
+
+        let decoded: Delivery = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.id, delivery.id);
+        assert_eq!(decoded.payload, delivery.payload);
+    }
 }
@@ -0,0 +1,187 @@
+//! Field projections that subscribers can attach to a subscription, so that only the selected
+//! fields of a JSON message are sent to them.
+use serde_json::{Map, Value};
+
+use crate::{NotifluxError, NotifluxErrorType};
+
+/// A list of JSON pointers (RFC 6901) selecting the fields to keep
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Projection {
+    pointers: Vec<String>,
+}
+
+impl Projection {
+    pub fn new(pointers: Vec<String>) -> Result<Projection, NotifluxError> {
+        if pointers.is_empty() {
+            return Err(NotifluxError {
+                message: Some("Invalid fields: at least one field is required".to_owned()),
+                error_type: NotifluxErrorType::ValidationError,
+            });
+        }
+        if let Some(pointer) = pointers.iter().find(|p| !p.starts_with('/')) {
+            return Err(NotifluxError {
+                message: Some(format!(
+                    "Invalid fields: {} is not a JSON pointer, it should start with /",
+                    pointer
+                )),
+                error_type: NotifluxErrorType::ValidationError,
+            });
+        }
+
+        Ok(Projection { pointers })
+    }
+
+    /// Build a new value with only the selected fields, kept at the same path as in the
+    /// original value. Fields that don't exist are left out, and arrays keep the selected
+    /// elements in their original order.
+    pub fn apply(&self, value: &Value) -> Value {
+        let mut selection = Selection::default();
+        for pointer in &self.pointers {
+            selection.insert(pointer[1..].split('/').map(unescape));
+        }
+
+        selection
+            .apply(value)
+            .unwrap_or_else(|| Value::Object(Map::new()))
+    }
+}
+
+/// The pointers of a projection as a tree, so that those sharing a parent are applied together
+#[derive(Default)]
+struct Selection {
+    /// Selected as a whole, by a pointer that ends here
+    whole: bool,
+    children: Vec<(String, Selection)>,
+}
+
+impl Selection {
+    fn insert(&mut self, mut keys: impl Iterator<Item = String>) {
+        let Some(key) = keys.next() else {
+            self.whole = true;
+            return;
+        };
+
+        let index = match self.children.iter().position(|(k, _)| *k == key) {
+            Some(index) => index,
+            None => {
+                self.children.push((key, Selection::default()));
+                self.children.len() - 1
+            }
+        };
+        self.children[index].1.insert(keys);
+    }
+
+    /// The selected part of the value, or nothing if none of the selected fields exist
+    fn apply(&self, value: &Value) -> Option<Value> {
+        if self.whole {
+            return Some(value.clone());
+        }
+
+        match value {
+            Value::Object(map) => {
+                let projected: Map<String, Value> = self
+                    .children
+                    .iter()
+                    .filter_map(|(key, child)| Some((key.clone(), child.apply(map.get(key)?)?)))
+                    .collect();
+                (!projected.is_empty()).then_some(Value::Object(projected))
+            }
+            Value::Array(items) => {
+                let mut projected: Vec<(usize, Value)> = self
+                    .children
+                    .iter()
+                    .filter_map(|(key, child)| {
+                        let index = parse_index(key)?;
+                        Some((index, child.apply(items.get(index)?)?))
+                    })
+                    .collect();
+                projected.sort_by_key(|(index, _)| *index);
+                (!projected.is_empty())
+                    .then(|| Value::Array(projected.into_iter().map(|(_, v)| v).collect()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Projection {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pointers = Vec::<String>::deserialize(deserializer)?;
+        Projection::new(pointers).map_err(|e| serde::de::Error::custom(e.message()))
+    }
+}
+
+fn unescape(key: &str) -> String {
+    key.replace("~1", "/").replace("~0", "~")
+}
+
+/// An array index as JSON pointers have them, in decimal without leading zeros
+fn parse_index(key: &str) -> Option<usize> {
+    if key.is_empty()
+        || !key.bytes().all(|b| b.is_ascii_digit())
+        || key.len() > 1 && key.starts_with('0')
+    {
+        return None;
+    }
+    key.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn projection(pointers: &[&str]) -> Projection {
+        Projection::new(pointers.iter().map(|p| p.to_string()).collect()).unwrap()
+    }
+
+    #[test]
+    fn test_apply() {
+        let value = json!({
+            "id": 1,
+            "status": "failed",
+            "build": {"stage": "test", "log": "...", "a/b": true},
+            "large": [1, 2, 3],
+        });
+
+        assert_eq!(
+            projection(&["/id", "/build/stage", "/build/a~1b", "/missing"]).apply(&value),
+            json!({"id": 1, "build": {"stage": "test", "a/b": true}})
+        );
+        assert_eq!(
+            projection(&["/build", "/build/stage"]).apply(&value),
+            json!({"build": {"stage": "test", "log": "...", "a/b": true}})
+        );
+    }
+
+    #[test]
+    fn test_apply_arrays() {
+        let value = json!({
+            "jobs": [
+                {"name": "lint", "status": "ok"},
+                {"name": "test", "status": "failed", "log": "..."},
+                {"name": "deploy", "status": "skipped"},
+            ],
+            "matrix": [[1, 2], [3, 4]],
+        });
+
+        assert_eq!(
+            projection(&["/jobs/1/status", "/jobs/0/name", "/jobs/1/name", "/jobs/9"])
+                .apply(&value),
+            json!({"jobs": [{"name": "lint"}, {"name": "test", "status": "failed"}]})
+        );
+        assert_eq!(
+            projection(&["/matrix/1/0", "/matrix/01", "/matrix/-"]).apply(&value),
+            json!({"matrix": [[3]]})
+        );
+        assert_eq!(projection(&["/jobs/0/missing"]).apply(&value), json!({}));
+    }
+
+    #[test]
+    fn test_invalid_pointers() {
+        assert!(Projection::new(Vec::new()).is_err());
+
+        let err = Projection::new(vec!["id".to_owned()]).unwrap_err();
+        assert_eq!(err.error_type, NotifluxErrorType::ValidationError);
+    }
+}
@@ -1,15 +1,106 @@
 use actix::prelude::*;
+use rand::Rng;
+use serde_json::Value;
+use std::cell::OnceCell;
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use ulid::Ulid;
 
-use crate::auth::{get_action, Action};
-use crate::message;
+use crate::auth::{Authorizer, Decision, Grant, JwtAuthorizer, Operation};
+use crate::backplane;
+use crate::filter::Filter;
+use crate::limits::{self, Limit, Limiter, RateKey, RateLimited};
+use crate::message::{
+    self, Delivery, ErrorEvent, Event, PresenceAuth, PresenceEntry, PresenceEvent, SendTarget,
+};
+use crate::projection::Projection;
+use crate::webhook;
+use crate::{NotifluxError, NotifluxErrorType};
 
+/// How often retained values past their TTL are dropped, as they are otherwise only dropped
+/// when someone subscribes to their topic
+const RETAINED_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A single session's subscription to a topic
 #[derive(Debug)]
+struct Subscription {
+    sub: String,
+    /// Whether the subscribing token carried the presence permission, which means the session
+    /// receives join/leave events and can query the presence of the topic
+    presence: bool,
+    meta: Option<Value>,
+    filter: Option<Filter>,
+    fields: Option<Projection>,
+}
+
+impl Subscription {
+    /// Whether the message passes the subscription's filter. The payload is parsed as JSON at
+    /// most once per message, the first time a subscription with a filter needs it.
+    fn accepts(&self, delivery: &Delivery, json: &OnceCell<Option<Value>>) -> bool {
+        let Some(filter) = &self.filter else {
+            return true;
+        };
+
+        json.get_or_init(|| parse_json(delivery))
+            .as_ref()
+            .is_some_and(|value| filter.matches(value))
+    }
+}
+
+/// The payload as JSON, which binary messages never are
+fn parse_json(delivery: &Delivery) -> Option<Value> {
+    serde_json::from_str(delivery.payload.as_text()?).ok()
+}
+
+/// Apply the projection to a JSON message, messages that aren't JSON are passed through as is
+fn project(
+    delivery: &Delivery,
+    projection: &Projection,
+    json: &OnceCell<Option<Value>>,
+) -> Delivery {
+    let value = json.get_or_init(|| parse_json(delivery));
+
+    match value {
+        Some(value) => delivery.with_payload(projection.apply(value).to_string().into()),
+        None => delivery.clone(),
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    addr: Recipient<message::Message>,
+    /// The subject of the last token the session subscribed with
+    sub: Option<String>,
+}
+
+/// The last value of a topic, delivered to sessions as soon as they subscribe
+#[derive(Debug)]
+struct Retained {
+    delivery: Delivery,
+    expires_at: Option<Instant>,
+}
+
+impl Retained {
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Instant::now())
+    }
+}
+
 pub struct Server {
-    sessions: HashMap<Ulid, Recipient<message::Message>>,
-    topics: HashMap<String, HashSet<Ulid>>,
-    jwt_public_key: Vec<u8>,
+    sessions: HashMap<Ulid, Session>,
+    topics: HashMap<String, HashMap<Ulid, Subscription>>,
+    subjects: HashMap<String, HashSet<Ulid>>,
+    retained: HashMap<String, Retained>,
+    authorizer: Arc<dyn Authorizer>,
+    limiter: Arc<Limiter>,
+    backplane: Option<Recipient<backplane::Outbound>>,
+    webhook: Option<Recipient<webhook::Event>>,
+    /// Broadcasts from sessions still being authorised
+    pending_broadcasts: usize,
+    /// The longest reconnect delay to suggest, once shutting down
+    shutdown: Option<Duration>,
 }
 
 impl Server {
@@ -17,45 +108,391 @@ impl Server {
         Server {
             sessions: HashMap::new(),
             topics: HashMap::new(),
-            jwt_public_key: jwt_public_key.to_vec(),
+            subjects: HashMap::new(),
+            retained: HashMap::new(),
+            authorizer: Arc::new(JwtAuthorizer::new(jwt_public_key)),
+            limiter: Arc::new(Limiter::default()),
+            backplane: None,
+            webhook: None,
+            pending_broadcasts: 0,
+            shutdown: None,
+        }
+    }
+
+    /// Authorise sessions with something other than the JWTs signed by the public key
+    pub fn set_authorizer(&mut self, authorizer: Arc<dyn Authorizer>) {
+        self.authorizer = authorizer;
+    }
+
+    /// Enforce the subscription limits, counting rejections with those of the HTTP workers
+    pub(crate) fn set_limiter(&mut self, limiter: Arc<Limiter>) {
+        self.limiter = limiter;
+    }
+
+    /// Forward broadcasts to other instances through the backplane, and receive theirs
+    pub fn set_backplane(&mut self, backplane: Recipient<backplane::Outbound>) {
+        self.backplane = Some(backplane);
+    }
+
+    fn notify_backplane(&self, msg: backplane::Outbound) {
+        if let Some(backplane) = &self.backplane {
+            backplane.do_send(msg);
         }
     }
 
-    fn broadcast(&self, topic: &str, message: &str) {
-        log::debug!("Broadcasting message to topic: {}: {}", topic, message);
-        if let Some(sessions) = self.topics.get(topic) {
-            for id in sessions {
-                if let Some(addr) = self.sessions.get(id) {
-                    addr.do_send(message::Message(message.to_owned()));
+    /// Report subscription lifecycle events to a webhook
+    pub fn set_webhook(&mut self, webhook: Recipient<webhook::Event>) {
+        self.webhook = Some(webhook);
+    }
+
+    fn notify_webhook(&self, event: webhook::Event) {
+        if let Some(webhook) = &self.webhook {
+            webhook.do_send(event);
+        }
+    }
+
+    fn broadcast(&self, topic: &str, delivery: &Delivery, exclude: Option<Ulid>) {
+        log::debug!(
+            "Broadcasting message to topic: {}: {}",
+            topic,
+            delivery.payload
+        );
+        if let Some(subscriptions) = self.topics.get(topic) {
+            let json = OnceCell::new();
+            // Each distinct projection is only computed once per message, no matter how many
+            // subscribers share it
+            let mut projected: HashMap<&Projection, Delivery> = HashMap::new();
+            for (id, subscription) in subscriptions {
+                if Some(*id) == exclude || !subscription.accepts(delivery, &json) {
+                    continue;
                 }
+                let Some(session) = self.sessions.get(id) else {
+                    continue;
+                };
+                let delivery = match &subscription.fields {
+                    Some(projection) => projected
+                        .entry(projection)
+                        .or_insert_with(|| project(delivery, projection, &json))
+                        .clone(),
+                    None => delivery.clone(),
+                };
+                session.addr.do_send(message::Message::Delivery(delivery));
+            }
+        }
+    }
+
+    /// Deliver a message broadcast on this instance to local subscribers and to the other
+    /// instances through the backplane
+    fn publish(&self, topic: &str, delivery: Delivery, exclude: Option<Ulid>) {
+        self.broadcast(topic, &delivery, exclude);
+        self.notify_backplane(backplane::Outbound::Publish(delivery));
+    }
+
+    fn retain(&mut self, topic: &str, delivery: &Delivery, retain: &message::Retain) {
+        log::debug!("Retaining message for topic: {}", topic);
+        self.retained.insert(
+            topic.to_owned(),
+            Retained {
+                delivery: delivery.clone(),
+                expires_at: retain.ttl.map(|ttl| Instant::now() + ttl),
+            },
+        );
+    }
+
+    fn sweep_retained(&mut self) {
+        self.retained.retain(|_, retained| !retained.is_expired());
+    }
+
+    /// Deliver the retained message of the topic to a session, if there is one that hasn't
+    /// expired yet
+    fn deliver_retained(&mut self, topic: &str, id: Ulid) {
+        if self.retained.get(topic).is_some_and(Retained::is_expired) {
+            self.retained.remove(topic);
+        }
+        let (Some(retained), Some(session), Some(subscription)) = (
+            self.retained.get(topic),
+            self.sessions.get(&id),
+            self.topics.get(topic).and_then(|s| s.get(&id)),
+        ) else {
+            return;
+        };
+        let json = OnceCell::new();
+        if !subscription.accepts(&retained.delivery, &json) {
+            return;
+        }
+        let delivery = match &subscription.fields {
+            Some(projection) => project(&retained.delivery, projection, &json),
+            None => retained.delivery.clone(),
+        };
+
+        session.addr.do_send(message::Message::Delivery(delivery));
+    }
+
+    /// Send a presence event to every other subscriber of the topic that has the presence
+    /// permission
+    fn notify_presence(&self, topic: &str, event: PresenceEvent, skip: Ulid) {
+        let Some(subscriptions) = self.topics.get(topic) else {
+            return;
+        };
+        let event = Event::from(event);
+
+        for (id, subscription) in subscriptions {
+            if *id == skip || !subscription.presence {
+                continue;
+            }
+            if let Some(session) = self.sessions.get(id) {
+                session.addr.do_send(message::Message::Event(event.clone()));
             }
         }
     }
+
+    /// The names a direct message target can be authorised by. A session can be targeted
+    /// either by its id or by the subject the session authenticated as.
+    fn target_names(&self, target: &SendTarget) -> Vec<String> {
+        match target {
+            SendTarget::Subject(sub) => vec![sub.clone()],
+            SendTarget::Session(id) => {
+                let sub = self
+                    .sessions
+                    .get(id)
+                    .and_then(|session| session.sub.clone());
+                [Some(id.to_string()), sub].into_iter().flatten().collect()
+            }
+        }
+    }
+
+    /// Send a message directly to the local sessions of the target
+    fn send_direct(&self, target: &SendTarget, delivery: &Delivery) {
+        let ids: Vec<Ulid> = match target {
+            SendTarget::Subject(sub) => self
+                .subjects
+                .get(sub)
+                .map(|ids| ids.iter().copied().collect())
+                .unwrap_or_default(),
+            SendTarget::Session(id) => vec![*id],
+        };
+
+        log::debug!("Sending message to {} session(s): {:?}", ids.len(), target);
+        for id in ids {
+            if let Some(session) = self.sessions.get(&id) {
+                session
+                    .addr
+                    .do_send(message::Message::Delivery(delivery.clone()));
+            }
+        }
+    }
+
+    /// Record the subject a session authenticated as, replacing any previous one
+    fn identify(&mut self, id: Ulid, sub: &str) {
+        let Some(session) = self.sessions.get_mut(&id) else {
+            return;
+        };
+        if session.sub.as_deref() == Some(sub) {
+            return;
+        }
+
+        if let Some(previous) = session.sub.replace(sub.to_owned()) {
+            self.forget_subject(&previous, id);
+        }
+        self.subjects.entry(sub.to_owned()).or_default().insert(id);
+    }
+
+    fn forget_subject(&mut self, sub: &str, id: Ulid) {
+        if let Some(ids) = self.subjects.get_mut(sub) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.subjects.remove(sub);
+            }
+        }
+    }
+
+    fn presence(&self, topic: &str) -> Vec<PresenceEntry> {
+        self.topics
+            .get(topic)
+            .map(|subscriptions| {
+                subscriptions
+                    .iter()
+                    .map(|(id, subscription)| presence_entry(*id, subscription))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn leave(&mut self, topic: &str, id: Ulid) {
+        let Some(subscriptions) = self.topics.get_mut(topic) else {
+            return;
+        };
+        let Some(subscription) = subscriptions.remove(&id) else {
+            return;
+        };
+        if subscriptions.is_empty() {
+            self.topics.remove(topic);
+            self.notify_backplane(backplane::Outbound::Unsubscribe(topic.to_owned()));
+            self.notify_webhook(webhook::Event::TopicVacated {
+                topic: topic.to_owned(),
+            });
+        }
+
+        self.notify_presence(
+            topic,
+            PresenceEvent::Leave {
+                topic: topic.to_owned(),
+                entry: presence_entry(id, &subscription),
+            },
+            id,
+        );
+    }
+
+    fn leave_all(&mut self, id: Ulid) {
+        let topics: Vec<String> = self
+            .topics
+            .iter()
+            .filter(|(_, subscriptions)| subscriptions.contains_key(&id))
+            .map(|(topic, _)| topic.clone())
+            .collect();
+
+        for topic in topics {
+            self.leave(&topic, id);
+        }
+    }
+}
+
+fn presence_entry(id: Ulid, subscription: &Subscription) -> PresenceEntry {
+    PresenceEntry {
+        session: id.to_string(),
+        sub: subscription.sub.clone(),
+        meta: subscription.meta.clone(),
+    }
 }
 
 impl Actor for Server {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(RETAINED_SWEEP_INTERVAL, |act, _| act.sweep_retained());
+        ctx.run_interval(limits::PRUNE_INTERVAL, |act, _| act.limiter.prune_buckets());
+    }
 }
 
 impl Handler<message::Broadcast> for Server {
     type Result = ();
 
-    fn handle(&mut self, msg: message::Broadcast, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: message::Broadcast, ctx: &mut Context<Self>) {
         log::debug!("handling Broadcast: {:?}", msg);
 
-        match get_action(&msg.token, &self.jwt_public_key) {
-            Ok(Action::Broadcast(topics)) => {
-                if topics.contains(&msg.topic) {
-                    log::debug!("Broadcasting message to topic: {}", msg.topic);
-                    self.broadcast(&msg.topic, &msg.msg);
-                } else {
-                    log::error!("Not allowed to broadcast message to topic: {}", msg.topic);
-                }
-            }
-            _ => {
-                log::error!("Not allowed to broadcast message");
+        if self.shutdown.is_some() {
+            if let Some(session) = self.sessions.get(&msg.id) {
+                let error = ErrorEvent::new("unavailable", "Shutting down");
+                session.addr.do_send(message::Message::Event(error.into()));
             }
+            return;
         }
+
+        self.pending_broadcasts += 1;
+        self.authorizer
+            .authorize(&msg.credential, Operation::Broadcast, &msg.topic)
+            .into_actor(self)
+            .map(move |decision, act, _| {
+                act.broadcast_authorized(msg, decision);
+                act.pending_broadcasts -= 1;
+                act.close_if_drained();
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Handler<message::Publish> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: message::Publish, _: &mut Context<Self>) {
+        if self.shutdown.is_some() {
+            log::warn!("Not publishing to topic {} while shutting down", msg.topic);
+            return;
+        }
+        log::debug!("Publishing trusted message to topic: {}", msg.topic);
+
+        let delivery = Delivery::new(Some(&msg.topic), msg.payload);
+        if let Some(retain) = &msg.retain {
+            self.retain(&msg.topic, &delivery, retain);
+        }
+        self.publish(&msg.topic, delivery, None);
+    }
+}
+
+impl Handler<message::Relay> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: message::Relay, _: &mut Context<Self>) {
+        if let Some(target) = &msg.target {
+            self.send_direct(target, &msg.delivery);
+            return;
+        }
+        let Some(topic) = msg.delivery.topic.clone() else {
+            return;
+        };
+        log::debug!("Relaying message to topic: {}", topic);
+
+        self.broadcast(&topic, &msg.delivery, None);
+    }
+}
+
+impl Handler<message::DirectMessage> for Server {
+    type Result = ResponseActFuture<Self, Result<(), NotifluxError>>;
+
+    fn handle(&mut self, msg: message::DirectMessage, _: &mut Context<Self>) -> Self::Result {
+        log::debug!("handling DirectMessage: {:?}", msg);
+
+        // Allowed if the credential allows sending to any of the names of the target
+        let authorizer = self.authorizer.clone();
+        let names = self.target_names(&msg.target);
+        let credential = msg.credential.clone();
+        let decision = async move {
+            let mut decision = Decision::Deny("No target".to_owned());
+            for name in names {
+                decision = authorizer
+                    .authorize(&credential, Operation::Send, &name)
+                    .await;
+                if matches!(decision, Decision::Allow(_)) {
+                    break;
+                }
+            }
+            decision
+        };
+
+        Box::pin(
+            decision
+                .into_actor(self)
+                .map(move |decision, act, _| match decision {
+                    Decision::Allow(_) => {
+                        let delivery = Delivery::new(None, msg.msg);
+                        act.send_direct(&msg.target, &delivery);
+                        // Sessions of the target may be connected to other instances, unless it
+                        // is a session of this one
+                        let local = match &msg.target {
+                            SendTarget::Session(id) => act.sessions.contains_key(id),
+                            SendTarget::Subject(_) => false,
+                        };
+                        if !local {
+                            act.notify_backplane(backplane::Outbound::Send {
+                                target: msg.target,
+                                delivery,
+                            });
+                        }
+                        Ok(())
+                    }
+                    Decision::Deny(reason) => {
+                        log::error!(
+                            "Not allowed to send direct message to {:?}: {}",
+                            msg.target,
+                            reason
+                        );
+                        Err(NotifluxError {
+                            message: Some(reason),
+                            error_type: NotifluxErrorType::AuthorizationError,
+                        })
+                    }
+                }),
+        )
     }
 }
 
@@ -63,7 +500,16 @@ impl Handler<message::Connect> for Server {
     type Result = ();
 
     fn handle(&mut self, msg: message::Connect, _: &mut Context<Self>) {
-        self.sessions.insert(msg.id, msg.addr);
+        self.sessions.insert(
+            msg.id,
+            Session {
+                addr: msg.addr,
+                sub: None,
+            },
+        );
+        self.notify_webhook(webhook::Event::SessionConnected {
+            session: msg.id.to_string(),
+        });
     }
 }
 
@@ -71,41 +517,243 @@ impl Handler<message::Disconnect> for Server {
     type Result = ();
 
     fn handle(&mut self, msg: message::Disconnect, _: &mut Context<Self>) {
-        self.sessions.remove(&msg.id);
+        let sub = self
+            .sessions
+            .remove(&msg.id)
+            .and_then(|session| session.sub);
+        if let Some(sub) = &sub {
+            self.forget_subject(sub, msg.id);
+        }
+        self.leave_all(msg.id);
+        self.notify_webhook(webhook::Event::SessionDisconnected {
+            session: msg.id.to_string(),
+            sub,
+        });
+    }
+}
+
+impl Handler<message::Shutdown> for Server {
+    type Result = usize;
+
+    fn handle(&mut self, msg: message::Shutdown, _: &mut Context<Self>) -> usize {
+        self.shutdown = Some(msg.reconnect_delay);
+        self.close_if_drained();
+        self.sessions.len()
+    }
+}
+
+impl Handler<message::SessionCount> for Server {
+    type Result = usize;
+
+    fn handle(&mut self, _: message::SessionCount, _: &mut Context<Self>) -> usize {
+        self.sessions.len()
     }
 }
 
 impl Handler<message::SubscribeToTopic> for Server {
     type Result = ();
 
-    fn handle(&mut self, msg: message::SubscribeToTopic, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: message::SubscribeToTopic, ctx: &mut Context<Self>) {
         log::debug!("{:?} subscribing topic {}", msg.id, msg.topic);
 
-        match get_action(&msg.token, &self.jwt_public_key) {
-            Ok(Action::Subscribe(topics)) => {
-                if topics.contains(&msg.topic) {
-                    log::debug!("{:?} is allowed to subscribe topic {}", msg.id, msg.topic);
-                    self.topics
-                        .entry(msg.topic.clone())
-                        .or_default()
-                        .insert(msg.id);
-                } else {
+        self.authorizer
+            .authorize(&msg.credential, Operation::Subscribe, &msg.topic)
+            .into_actor(self)
+            .map(move |decision, act, _| match decision {
+                Decision::Allow(grant) => act.subscribe(msg, grant),
+                Decision::Deny(reason) => {
                     log::error!(
-                        "{:?} is not allowed to subscribe topic {}",
+                        "{:?} is not allowed to subscribe topic {}: {}",
                         msg.id,
-                        msg.topic
+                        msg.topic,
+                        reason
                     );
                 }
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Server {
+    fn subscribe(&mut self, msg: message::SubscribeToTopic, grant: Grant) {
+        if !self.sessions.contains_key(&msg.id) {
+            // Disconnected while being authorised
+            return;
+        }
+
+        log::debug!("{:?} is allowed to subscribe topic {}", msg.id, msg.topic);
+        if let Err(limited) = self.limiter.check_rate(RateKey::Sub(&grant.sub)) {
+            self.rate_limited(msg.id, &limited);
+            return;
+        }
+        if let Err(reason) = self.check_limits(msg.id, &msg.topic, &grant.sub) {
+            log::warn!(
+                "{:?} is not able to subscribe topic {}: {}",
+                msg.id,
+                msg.topic,
+                reason
+            );
+            if let Some(session) = self.sessions.get(&msg.id) {
+                let error = ErrorEvent::new(
+                    "limit_exceeded",
+                    format!("Unable to subscribe to {}: {}", msg.topic, reason),
+                );
+                session.addr.do_send(message::Message::Event(error.into()));
+            }
+            return;
+        }
+        self.identify(msg.id, &grant.sub);
+        let subscription = Subscription {
+            sub: grant.sub,
+            presence: grant.presence,
+            meta: msg.options.meta,
+            filter: msg.options.filter,
+            fields: msg.options.fields,
+        };
+        let entry = presence_entry(msg.id, &subscription);
+        if !self.topics.contains_key(&msg.topic) {
+            self.notify_backplane(backplane::Outbound::Subscribe(msg.topic.clone()));
+            self.notify_webhook(webhook::Event::TopicOccupied {
+                topic: msg.topic.clone(),
+            });
+        }
+        let joined = self
+            .topics
+            .entry(msg.topic.clone())
+            .or_default()
+            .insert(msg.id, subscription)
+            .is_none();
+
+        if joined {
+            self.notify_presence(
+                &msg.topic,
+                PresenceEvent::Join {
+                    topic: msg.topic.clone(),
+                    entry,
+                },
+                msg.id,
+            );
+        }
+        self.deliver_retained(&msg.topic, msg.id);
+    }
+
+    fn broadcast_authorized(&mut self, msg: message::Broadcast, decision: Decision) {
+        match decision {
+            Decision::Allow(grant) => {
+                let rate = self
+                    .limiter
+                    .check_rate(RateKey::Sub(&grant.sub))
+                    .and_then(|_| self.limiter.check_rate(RateKey::Topic(&msg.topic)));
+                if let Err(limited) = rate {
+                    self.rate_limited(msg.id, &limited);
+                    return;
+                }
+
+                log::debug!("Broadcasting message to topic: {}", msg.topic);
+                let delivery = Delivery::new(Some(&msg.topic), msg.payload);
+                if let Some(retain) = &msg.retain {
+                    self.retain(&msg.topic, &delivery, retain);
+                }
+                self.publish(&msg.topic, delivery, msg.exclude.then_some(msg.id));
             }
-            _ => {
+            Decision::Deny(reason) => {
                 log::error!(
-                    "{:?} is not allowed to subscribe topic {}",
-                    msg.id,
-                    msg.topic
+                    "Not allowed to broadcast message to topic {}: {}",
+                    msg.topic,
+                    reason
                 );
+                // Let the publishing session know, like HTTP clients get a 403
+                if let Some(session) = self.sessions.get(&msg.id) {
+                    let error = ErrorEvent::new("forbidden", reason);
+                    session.addr.do_send(message::Message::Event(error.into()));
+                }
             }
         }
     }
+
+    /// Closes every session once shutting down, but only after the broadcasts still being
+    /// authorised have been delivered. Deliveries already queued for a session go out before
+    /// its close frame.
+    fn close_if_drained(&self) {
+        let Some(reconnect_delay) = self.shutdown else {
+            return;
+        };
+        if self.pending_broadcasts > 0 {
+            log::info!(
+                "Waiting for {} broadcast(s) before closing sessions",
+                self.pending_broadcasts
+            );
+            return;
+        }
+        let mut rng = rand::rng();
+        for session in self.sessions.values() {
+            let delay = rng.random_range(Duration::ZERO..=reconnect_delay);
+            session.addr.do_send(message::Message::GoingAway(delay));
+        }
+    }
+
+    fn rate_limited(&self, id: Ulid, limited: &RateLimited) {
+        log::warn!("{:?} is rate limited: {}", id, limited.message);
+        if let Some(session) = self.sessions.get(&id) {
+            session.addr.do_send(message::Message::Event(
+                ErrorEvent::rate_limited(limited).into(),
+            ));
+        }
+    }
+
+    /// Whether the session can subscribe to the topic with a token for the subject, without
+    /// going over a limit. Resubscribing to a topic doesn't count as another subscription.
+    fn check_limits(&self, id: Ulid, topic: &str, sub: &str) -> Result<(), String> {
+        let limits = self.limiter.config();
+
+        if let Some(max) = limits.max_connections_per_sub {
+            let identified = self
+                .sessions
+                .get(&id)
+                .is_some_and(|session| session.sub.as_deref() == Some(sub));
+            let sessions = self.subjects.get(sub).map_or(0, HashSet::len);
+            if !identified && sessions >= max {
+                self.limiter.exceeded(Limit::ConnectionsPerSub);
+                return Err(format!("Too many connections for {}", sub));
+            }
+        }
+
+        let subscribers = self.topics.get(topic);
+        if subscribers.is_some_and(|subscribers| subscribers.contains_key(&id)) {
+            return Ok(());
+        }
+        if let Some(max) = limits.max_topics_per_session {
+            let topics = self
+                .topics
+                .values()
+                .filter(|subscribers| subscribers.contains_key(&id))
+                .count();
+            if topics >= max {
+                self.limiter.exceeded(Limit::TopicsPerSession);
+                return Err(format!(
+                    "Subscribed to too many topics, the limit is {}",
+                    max
+                ));
+            }
+        }
+        if let Some(max) = limits.max_subscribers_per_topic {
+            if subscribers.map_or(0, HashMap::len) >= max {
+                self.limiter.exceeded(Limit::SubscribersPerTopic);
+                return Err("Too many subscribers to the topic".to_owned());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Handler<message::ClearRetained> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: message::ClearRetained, _: &mut Context<Self>) {
+        log::debug!("Clearing retained message for topic: {}", msg.topic);
+        self.retained.remove(&msg.topic);
+    }
 }
 
 impl Handler<message::UnsubscribeFromTopic> for Server {
@@ -114,9 +762,7 @@ impl Handler<message::UnsubscribeFromTopic> for Server {
     fn handle(&mut self, msg: message::UnsubscribeFromTopic, _: &mut Context<Self>) {
         log::debug!("{:?} leaving topic {}", msg.id, msg.topic);
 
-        if let Some(topic) = self.topics.get_mut(&msg.topic) {
-            topic.remove(&msg.id);
-        }
+        self.leave(&msg.topic, msg.id);
     }
 }
 
@@ -126,8 +772,763 @@ impl Handler<message::UnsubscribeAll> for Server {
     fn handle(&mut self, msg: message::UnsubscribeAll, _: &mut Context<Self>) {
         log::debug!("{:?} leaving all topics", msg.id);
 
-        for (_, topic) in self.topics.iter_mut() {
-            topic.remove(&msg.id);
+        self.leave_all(msg.id);
+    }
+}
+
+impl Handler<message::GetPresence> for Server {
+    type Result = Result<Vec<PresenceEntry>, NotifluxError>;
+
+    fn handle(&mut self, msg: message::GetPresence, _: &mut Context<Self>) -> Self::Result {
+        let allowed = match &msg.auth {
+            PresenceAuth::Authorized => true,
+            PresenceAuth::Session(id) => self
+                .topics
+                .get(&msg.topic)
+                .and_then(|subscriptions| subscriptions.get(id))
+                .is_some_and(|subscription| subscription.presence),
+        };
+
+        if !allowed {
+            return Err(NotifluxError {
+                message: Some(format!(
+                    "Not allowed to query presence for topic: {}",
+                    msg.topic
+                )),
+                error_type: NotifluxErrorType::AuthorizationError,
+            });
+        }
+
+        Ok(self.presence(&msg.topic))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::test_utils::{
+        sign_presence_token, sign_send_token, sign_subject_token, sign_token,
+    };
+    use crate::auth::Credential;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Actor standing in for a websocket session, collecting every message it receives
+    struct Collector(Arc<Mutex<Vec<String>>>);
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<message::Message> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: message::Message, _: &mut Context<Self>) {
+            let msg = match msg {
+                message::Message::Delivery(delivery) => delivery.payload.to_string(),
+                message::Message::Event(event) => event.text(),
+                message::Message::GoingAway(delay) => {
+                    assert!(delay <= Duration::from_secs(1));
+                    "going away".to_owned()
+                }
+            };
+            self.0.lock().unwrap().push(msg);
+        }
+    }
+
+    async fn connect(server: &Addr<Server>, topic: &str) -> (Ulid, Arc<Mutex<Vec<String>>>) {
+        connect_with_token(server, topic, sign_token("subscribe", &[topic])).await
+    }
+
+    async fn connect_with_token(
+        server: &Addr<Server>,
+        topic: &str,
+        token: String,
+    ) -> (Ulid, Arc<Mutex<Vec<String>>>) {
+        connect_with_options(server, topic, token, message::SubscribeOptions::default()).await
+    }
+
+    async fn connect_with_options(
+        server: &Addr<Server>,
+        topic: &str,
+        token: String,
+        options: message::SubscribeOptions,
+    ) -> (Ulid, Arc<Mutex<Vec<String>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let id = Ulid::new();
+        let addr = Collector(received.clone()).start();
+
+        server
+            .send(message::Connect {
+                addr: addr.recipient(),
+                id,
+            })
+            .await
+            .unwrap();
+        server
+            .send(message::SubscribeToTopic {
+                id,
+                topic: topic.to_owned(),
+                credential: Credential::Token(token),
+                options,
+            })
+            .await
+            .unwrap();
+
+        (id, received)
+    }
+
+    fn start_server() -> Addr<Server> {
+        Server::new(include_bytes!("../scripts/public_key.pem")).start()
+    }
+
+    #[actix::test]
+    async fn test_broadcast_excludes_session() {
+        let server = start_server();
+        let (publisher, publisher_received) = connect(&server, "foo").await;
+        let (_, other_received) = connect(&server, "foo").await;
+
+        server
+            .send(message::Broadcast {
+                id: publisher,
+                payload: "hello".into(),
+                topic: "foo".to_owned(),
+                credential: Credential::Token(sign_token("broadcast", &["foo"])),
+                exclude: true,
+                retain: None,
+            })
+            .await
+            .unwrap();
+        // Let the collectors process their mailboxes
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        assert!(publisher_received.lock().unwrap().is_empty());
+        assert_eq!(*other_received.lock().unwrap(), vec!["hello".to_owned()]);
+    }
+
+    #[actix::test]
+    async fn test_broadcast_requires_broadcast_scope() {
+        let server = start_server();
+        let (id, received) = connect(&server, "foo").await;
+
+        server
+            .send(message::Broadcast {
+                id,
+                payload: "hello".into(),
+                topic: "foo".to_owned(),
+                credential: Credential::Token(sign_token("subscribe", &["foo"])),
+                exclude: false,
+                retain: None,
+            })
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        // Only the denial reaches the publishing session, not the message
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec!["Token doesn't allow broadcast on foo"]
+        );
+    }
+
+    #[actix::test]
+    async fn test_presence_join_and_leave_events() {
+        let server = start_server();
+        let (_, watcher_received) =
+            connect_with_token(&server, "foo", sign_presence_token("subscribe", &["foo"])).await;
+        let (_, plain_received) = connect(&server, "foo").await;
+        let (joiner, _) = connect(&server, "foo").await;
+
+        server
+            .send(message::Disconnect { id: joiner })
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        let events: Vec<serde_json::Value> = watcher_received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| serde_json::from_str(e).unwrap())
+            .collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["event"], "join");
+        assert_eq!(events[1]["event"], "join");
+        assert_eq!(events[2]["event"], "leave");
+        assert_eq!(events[2]["session"], joiner.to_string());
+        assert_eq!(events[2]["sub"], "notiflux");
+        // Subscribers without the presence permission don't see any events
+        assert!(plain_received.lock().unwrap().is_empty());
+    }
+
+    #[actix::test]
+    async fn test_get_presence() {
+        let server = start_server();
+        let (watcher, _) =
+            connect_with_token(&server, "foo", sign_presence_token("subscribe", &["foo"])).await;
+        let (plain, _) = connect(&server, "foo").await;
+
+        let members = server
+            .send(message::GetPresence {
+                topic: "foo".to_owned(),
+                auth: PresenceAuth::Authorized,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(members.len(), 2);
+
+        let members = server
+            .send(message::GetPresence {
+                topic: "foo".to_owned(),
+                auth: PresenceAuth::Session(watcher),
+            })
+            .await
+            .unwrap();
+        assert!(members.is_ok());
+
+        let err = server
+            .send(message::GetPresence {
+                topic: "foo".to_owned(),
+                auth: PresenceAuth::Session(plain),
+            })
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.error_type, NotifluxErrorType::AuthorizationError);
+    }
+
+    #[actix::test]
+    async fn test_direct_message_to_subject() {
+        let server = start_server();
+        let (_, phone_received) = connect_with_token(
+            &server,
+            "foo",
+            sign_subject_token("alice", "subscribe", &["foo"]),
+        )
+        .await;
+        let (_, laptop_received) = connect_with_token(
+            &server,
+            "bar",
+            sign_subject_token("alice", "subscribe", &["bar"]),
+        )
+        .await;
+        let (_, other_received) = connect_with_token(
+            &server,
+            "foo",
+            sign_subject_token("bob", "subscribe", &["foo"]),
+        )
+        .await;
+
+        server
+            .send(message::DirectMessage {
+                msg: "hello".to_owned(),
+                target: SendTarget::Subject("alice".to_owned()),
+                credential: Credential::Token(sign_send_token(&["alice"])),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        server
+            .send(message::DirectMessage {
+                msg: "not allowed".to_owned(),
+                target: SendTarget::Subject("bob".to_owned()),
+                credential: Credential::Token(sign_send_token(&["alice"])),
+            })
+            .await
+            .unwrap()
+            .unwrap_err();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(*phone_received.lock().unwrap(), vec!["hello".to_owned()]);
+        assert_eq!(*laptop_received.lock().unwrap(), vec!["hello".to_owned()]);
+        assert!(other_received.lock().unwrap().is_empty());
+    }
+
+    #[actix::test]
+    async fn test_direct_message_to_session() {
+        let server = start_server();
+        let (target, target_received) = connect(&server, "foo").await;
+        let (_, other_received) = connect(&server, "foo").await;
+
+        server
+            .send(message::DirectMessage {
+                msg: "hello".to_owned(),
+                target: SendTarget::Session(target),
+                credential: Credential::Token(sign_send_token(&[&target.to_string()])),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        server
+            .send(message::DirectMessage {
+                msg: "wrong scope".to_owned(),
+                target: SendTarget::Session(target),
+                credential: Credential::Token(sign_token("broadcast", &["foo"])),
+            })
+            .await
+            .unwrap()
+            .unwrap_err();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(*target_received.lock().unwrap(), vec!["hello".to_owned()]);
+        assert!(other_received.lock().unwrap().is_empty());
+    }
+
+    async fn broadcast_retained(server: &Addr<Server>, msg: &str, ttl: Option<Duration>) {
+        server
+            .send(message::Broadcast {
+                id: Ulid::new(),
+                payload: msg.into(),
+                topic: "foo".to_owned(),
+                credential: Credential::Token(sign_token("broadcast", &["foo"])),
+                exclude: false,
+                retain: Some(message::Retain { ttl }),
+            })
+            .await
+            .unwrap();
+    }
+
+    #[actix::test]
+    async fn test_retained_delivered_on_subscribe() {
+        let server = start_server();
+        broadcast_retained(&server, "first", None).await;
+        broadcast_retained(&server, "second", None).await;
+
+        let (_, received) = connect(&server, "foo").await;
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(*received.lock().unwrap(), vec!["second".to_owned()]);
+    }
+
+    #[actix::test]
+    async fn test_retained_cleared() {
+        let server = start_server();
+        broadcast_retained(&server, "status", None).await;
+
+        server
+            .send(message::ClearRetained {
+                topic: "foo".to_owned(),
+            })
+            .await
+            .unwrap();
+        let (_, received) = connect(&server, "foo").await;
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[actix::test]
+    async fn test_retained_expires() {
+        let server = start_server();
+        broadcast_retained(&server, "status", Some(Duration::from_millis(5))).await;
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        let (_, received) = connect(&server, "foo").await;
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_retained_swept() {
+        let mut server = Server::new(include_bytes!("../scripts/public_key.pem"));
+        let delivery = Delivery::new(Some("foo"), "status");
+        let expiring = message::Retain {
+            ttl: Some(Duration::from_millis(5)),
+        };
+        server.retain("foo", &delivery, &expiring);
+        server.retain("bar", &delivery, &message::Retain { ttl: None });
+        std::thread::sleep(Duration::from_millis(10));
+
+        server.sweep_retained();
+
+        assert_eq!(server.retained.keys().collect::<Vec<_>>(), vec!["bar"]);
+    }
+
+    #[actix::test]
+    async fn test_broadcast_filtered() {
+        let server = start_server();
+        let (_, received) = connect_with_options(
+            &server,
+            "foo",
+            sign_token("subscribe", &["foo"]),
+            serde_json::from_str(r#"{"filter": "status == \"failed\""}"#).unwrap(),
+        )
+        .await;
+        let (_, unfiltered_received) = connect(&server, "foo").await;
+
+        for msg in [r#"{"status": "ok"}"#, r#"{"status": "failed"}"#, "not json"] {
+            server
+                .send(message::Broadcast {
+                    id: Ulid::new(),
+                    payload: msg.into(),
+                    topic: "foo".to_owned(),
+                    credential: Credential::Token(sign_token("broadcast", &["foo"])),
+                    exclude: false,
+                    retain: None,
+                })
+                .await
+                .unwrap();
+        }
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![r#"{"status": "failed"}"#.to_owned()]
+        );
+        assert_eq!(unfiltered_received.lock().unwrap().len(), 3);
+    }
+
+    #[actix::test]
+    async fn test_broadcast_projected() {
+        let server = start_server();
+        let (_, projected_received) = connect_with_options(
+            &server,
+            "foo",
+            sign_token("subscribe", &["foo"]),
+            serde_json::from_str(r#"{"fields": ["/id", "/build/status"]}"#).unwrap(),
+        )
+        .await;
+        let (_, full_received) = connect(&server, "foo").await;
+
+        let msg = r#"{"id": 1, "build": {"status": "ok", "log": "..."}}"#;
+        server
+            .send(message::Broadcast {
+                id: Ulid::new(),
+                payload: msg.into(),
+                topic: "foo".to_owned(),
+                credential: Credential::Token(sign_token("broadcast", &["foo"])),
+                exclude: false,
+                retain: None,
+            })
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        let projected: Value =
+            serde_json::from_str(&projected_received.lock().unwrap()[0]).unwrap();
+        assert_eq!(
+            projected,
+            serde_json::json!({"id": 1, "build": {"status": "ok"}})
+        );
+        assert_eq!(*full_received.lock().unwrap(), vec![msg.to_owned()]);
+    }
+
+    /// Actor standing in for a backplane, recording what the server tells it
+    struct BackplaneCollector(Arc<Mutex<Vec<String>>>);
+
+    impl Actor for BackplaneCollector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<backplane::Outbound> for BackplaneCollector {
+        type Result = ();
+
+        fn handle(&mut self, msg: backplane::Outbound, _: &mut Context<Self>) {
+            let msg = match msg {
+                backplane::Outbound::Publish(delivery) => format!("publish {}", delivery.payload),
+                backplane::Outbound::Subscribe(topic) => format!("subscribe {}", topic),
+                backplane::Outbound::Unsubscribe(topic) => format!("unsubscribe {}", topic),
+                backplane::Outbound::Send { target, delivery } => {
+                    format!("send {:?} {}", target, delivery.payload)
+                }
+            };
+            self.0.lock().unwrap().push(msg);
+        }
+    }
+
+    #[actix::test]
+    async fn test_backplane_notified() {
+        let outbound = Arc::new(Mutex::new(Vec::new()));
+        let mut server = Server::new(include_bytes!("../scripts/public_key.pem"));
+        server.set_backplane(BackplaneCollector(outbound.clone()).start().recipient());
+        let server = server.start();
+
+        let (first, _) = connect(&server, "foo").await;
+        let (second, _) = connect(&server, "foo").await;
+        server
+            .send(message::Broadcast {
+                id: Ulid::new(),
+                payload: "hello".into(),
+                topic: "foo".to_owned(),
+                credential: Credential::Token(sign_token("broadcast", &["foo"])),
+                exclude: false,
+                retain: None,
+            })
+            .await
+            .unwrap();
+        for id in [first, second] {
+            server.send(message::Disconnect { id }).await.unwrap();
         }
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(
+            *outbound.lock().unwrap(),
+            vec!["subscribe foo", "publish hello", "unsubscribe foo"]
+        );
+    }
+
+    #[actix::test]
+    async fn test_relay_delivered_locally() {
+        let server = start_server();
+        let (_, received) = connect(&server, "foo").await;
+
+        server
+            .send(message::Relay {
+                delivery: Delivery::new(Some("foo"), "hello"),
+                target: None,
+            })
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(*received.lock().unwrap(), vec!["hello".to_owned()]);
+    }
+
+    #[actix::test]
+    async fn test_direct_message_relayed() {
+        let outbound = Arc::new(Mutex::new(Vec::new()));
+        let mut server = Server::new(include_bytes!("../scripts/public_key.pem"));
+        server.set_backplane(BackplaneCollector(outbound.clone()).start().recipient());
+        let server = server.start();
+        let (id, received) = connect_with_token(
+            &server,
+            "foo",
+            sign_subject_token("alice", "subscribe", &["foo"]),
+        )
+        .await;
+
+        for (target, name) in [
+            (SendTarget::Subject("alice".to_owned()), "alice".to_owned()),
+            (SendTarget::Session(id), id.to_string()),
+        ] {
+            server
+                .send(message::DirectMessage {
+                    msg: "hello".to_owned(),
+                    target,
+                    credential: Credential::Token(sign_send_token(&[&name])),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        server
+            .send(message::Relay {
+                delivery: Delivery::new(None, "relayed"),
+                target: Some(SendTarget::Subject("alice".to_owned())),
+            })
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        // A session of this instance isn't looked for on the others
+        assert_eq!(
+            *outbound.lock().unwrap(),
+            vec!["subscribe foo", "send Subject(\"alice\") hello"]
+        );
+        assert_eq!(*received.lock().unwrap(), vec!["hello", "hello", "relayed"]);
+    }
+
+    /// Actor standing in for a webhook, recording the events it would post
+    struct WebhookCollector(Arc<Mutex<Vec<String>>>);
+
+    impl Actor for WebhookCollector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<webhook::Event> for WebhookCollector {
+        type Result = ();
+
+        fn handle(&mut self, event: webhook::Event, _: &mut Context<Self>) {
+            let event = match event {
+                webhook::Event::TopicOccupied { topic } => format!("occupied {}", topic),
+                webhook::Event::TopicVacated { topic } => format!("vacated {}", topic),
+                webhook::Event::SessionConnected { .. } => "connected".to_owned(),
+                webhook::Event::SessionDisconnected { sub, .. } => {
+                    format!("disconnected {}", sub.unwrap_or_default())
+                }
+            };
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[actix::test]
+    async fn test_webhook_notified() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut server = Server::new(include_bytes!("../scripts/public_key.pem"));
+        server.set_webhook(WebhookCollector(events.clone()).start().recipient());
+        let server = server.start();
+
+        let (first, _) = connect(&server, "foo").await;
+        let (second, _) = connect(&server, "foo").await;
+        server
+            .send(message::UnsubscribeFromTopic {
+                id: first,
+                topic: "foo".to_owned(),
+            })
+            .await
+            .unwrap();
+        server
+            .send(message::Disconnect { id: second })
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "connected",
+                "occupied foo",
+                "connected",
+                "vacated foo",
+                "disconnected notiflux",
+            ]
+        );
+    }
+
+    #[actix::test]
+    async fn test_subscription_limits() {
+        let limiter = Arc::new(Limiter::new(
+            crate::config::LimitsConfig {
+                max_connections_per_sub: Some(2),
+                max_topics_per_session: Some(1),
+                max_subscribers_per_topic: Some(2),
+                ..Default::default()
+            },
+            Default::default(),
+        ));
+        let mut server = Server::new(include_bytes!("../scripts/public_key.pem"));
+        server.set_limiter(limiter.clone());
+        let server = server.start();
+        let subscribe = |id: Ulid, topic: &str, sub: &str| message::SubscribeToTopic {
+            id,
+            topic: topic.to_owned(),
+            credential: Credential::Token(sign_subject_token(sub, "subscribe", &[topic])),
+            options: message::SubscribeOptions::default(),
+        };
+
+        let (first, first_received) = connect_with_token(
+            &server,
+            "foo",
+            sign_subject_token("alice", "subscribe", &["foo"]),
+        )
+        .await;
+        // Resubscribing doesn't count as another topic
+        server.send(subscribe(first, "foo", "alice")).await.unwrap();
+        server.send(subscribe(first, "bar", "alice")).await.unwrap();
+        connect_with_token(
+            &server,
+            "foo",
+            sign_subject_token("alice", "subscribe", &["foo"]),
+        )
+        .await;
+        let (_, third_received) = connect_with_token(
+            &server,
+            "foo",
+            sign_subject_token("bob", "subscribe", &["foo"]),
+        )
+        .await;
+        let (_, fourth_received) = connect_with_token(
+            &server,
+            "baz",
+            sign_subject_token("alice", "subscribe", &["baz"]),
+        )
+        .await;
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(
+            *first_received.lock().unwrap(),
+            vec!["Unable to subscribe to bar: Subscribed to too many topics, the limit is 1"]
+        );
+        assert_eq!(
+            *third_received.lock().unwrap(),
+            vec!["Unable to subscribe to foo: Too many subscribers to the topic"]
+        );
+        assert_eq!(
+            *fourth_received.lock().unwrap(),
+            vec!["Unable to subscribe to baz: Too many connections for alice"]
+        );
+        assert_eq!(
+            server
+                .send(message::GetPresence {
+                    topic: "foo".to_owned(),
+                    auth: PresenceAuth::Authorized,
+                })
+                .await
+                .unwrap()
+                .unwrap()
+                .len(),
+            2
+        );
+        let metrics = limiter.metrics();
+        for limit in [
+            "connections_per_sub",
+            "topics_per_session",
+            "subscribers_per_topic",
+        ] {
+            let line = format!("notiflux_limit_rejections_total{{limit=\"{}\"}} 1\n", limit);
+            assert!(metrics.contains(&line), "{} in {}", line, metrics);
+        }
+    }
+
+    #[actix::test]
+    async fn test_broadcast_rate_limited_by_topic() {
+        let limiter = Arc::new(Limiter::new(
+            Default::default(),
+            crate::config::RateLimitsConfig {
+                per_topic: Some("1/m".parse().unwrap()),
+                ..Default::default()
+            },
+        ));
+        let mut server = Server::new(include_bytes!("../scripts/public_key.pem"));
+        server.set_limiter(limiter);
+        let server = server.start();
+        let (publisher, publisher_received) = connect(&server, "foo").await;
+
+        for msg in ["first", "second"] {
+            server
+                .send(message::Broadcast {
+                    id: publisher,
+                    payload: msg.into(),
+                    topic: "foo".to_owned(),
+                    credential: Credential::Token(sign_token("broadcast", &["foo"])),
+                    exclude: false,
+                    retain: None,
+                })
+                .await
+                .unwrap();
+        }
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        let received = publisher_received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0], "first");
+        let event: Value = serde_json::from_str(&received[1]).unwrap();
+        assert_eq!(event["error"], "rate_limited");
+        assert_eq!(event["message"], "Rate limit exceeded for topic foo");
+    }
+
+    #[actix::test]
+    async fn test_shutdown_closes_sessions() {
+        let server = start_server();
+        broadcast_retained(&server, "status", None).await;
+        let (_, first) = connect(&server, "foo").await;
+        let (_, second) = connect(&server, "bar").await;
+
+        let closed = server
+            .send(message::Shutdown {
+                reconnect_delay: Duration::from_secs(1),
+            })
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(closed, 2);
+        assert_eq!(
+            *first.lock().unwrap(),
+            vec!["status".to_owned(), "going away".to_owned()]
+        );
+        assert_eq!(*second.lock().unwrap(), vec!["going away".to_owned()]);
     }
 }
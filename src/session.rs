@@ -3,23 +3,131 @@ use actix_web_actors::ws;
 use std::time::{Duration, Instant};
 use ulid::Ulid;
 
+use crate::auth::Credential;
+use crate::limits::ConnectionGuard;
+use crate::message::{ErrorEvent, Payload};
 use crate::{message, server};
 
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+mod codec;
+
+pub use codec::Codec;
+use codec::{Command, Reply};
 
 #[derive(Debug)]
 pub struct WSSession {
     pub id: Ulid,
     pub heartbeat: Instant,
-    pub topics: Vec<String>,
+    /// How often the client is pinged, negotiated when it connected
+    pub heartbeat_interval: Duration,
+    /// How long the client can stay silent before it's disconnected
+    pub client_timeout: Duration,
     pub addr: Addr<server::Server>,
+    /// How commands are read and replies written, chosen by subprotocol
+    pub codec: Codec,
+    /// Deliver binary messages, as binary frames or in base64. Sessions that didn't ask for them
+    /// only get text messages, unless their codec has binary frames.
+    pub binary: bool,
+    /// Counts towards the connection limits until the session is dropped, and rate limits the
+    /// commands of the session by its address
+    pub(crate) connection: ConnectionGuard,
 }
 
 impl WSSession {
+    fn reply(&self, ctx: &mut ws::WebsocketContext<Self>, reply: Reply) {
+        match self.codec.encode(reply) {
+            Ok(Payload::Text(text)) => ctx.text(text),
+            Ok(Payload::Binary(bytes)) => ctx.binary(bytes),
+            Err(e) => log::error!("Unable to encode reply: {}", e),
+        }
+    }
+
+    fn error(&self, ctx: &mut ws::WebsocketContext<Self>, error: impl Into<ErrorEvent>) {
+        self.reply(ctx, Reply::Event(error.into().into()));
+    }
+
+    /// Carry out a command, however it was encoded
+    fn execute(&mut self, command: Command, ctx: &mut ws::WebsocketContext<Self>) {
+        match command {
+            // For browsers, which can't send WebSocket pings
+            Command::Ping { payload } => self.reply(ctx, Reply::Pong(payload)),
+            Command::Subscribe {
+                topic,
+                token,
+                options,
+            } => {
+                if let Err(e) = self.connection.limiter().validate_topic(&topic) {
+                    self.error(ctx, e);
+                    return;
+                }
+                self.addr.do_send(message::SubscribeToTopic {
+                    id: self.id,
+                    topic,
+                    credential: Credential::Token(token),
+                    options,
+                });
+            }
+            Command::Publish {
+                topic,
+                token,
+                message,
+                exclude,
+            } => {
+                let limiter = self.connection.limiter();
+                if let Err(e) = limiter
+                    .validate_topic(&topic)
+                    .and_then(|_| limiter.validate_message(&message))
+                {
+                    self.error(ctx, e);
+                    return;
+                }
+                // `exclude` skips this session in the fan-out, so a client doesn't receive its
+                // own messages back
+                self.addr.do_send(message::Broadcast {
+                    id: self.id,
+                    payload: message,
+                    topic,
+                    credential: Credential::Token(token),
+                    exclude,
+                    retain: None,
+                });
+            }
+            Command::Unsubscribe { topic } => {
+                self.addr
+                    .do_send(message::UnsubscribeFromTopic { id: self.id, topic });
+            }
+            Command::Presence { topic } => {
+                if let Err(e) = self.connection.limiter().validate_topic(&topic) {
+                    self.error(ctx, e);
+                    return;
+                }
+                self.addr
+                    .send(message::GetPresence {
+                        topic: topic.clone(),
+                        auth: message::PresenceAuth::Session(self.id),
+                    })
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(Ok(members)) => {
+                                let event = message::PresenceEvent::Presence { topic, members };
+                                act.reply(ctx, Reply::Event(event.into()));
+                            }
+                            Ok(Err(e)) => act.error(ctx, e),
+                            Err(_) => ctx.stop(),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            Command::UnsubscribeAll => {
+                self.addr.do_send(message::UnsubscribeAll { id: self.id });
+            }
+        }
+    }
+
     fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            if Instant::now().duration_since(act.heartbeat) > CLIENT_TIMEOUT {
+        ctx.run_interval(self.heartbeat_interval, |act, ctx| {
+            if Instant::now().duration_since(act.heartbeat) > act.client_timeout {
                 log::debug!("Websocket client heartbeat failed, disconnecting!");
 
                 act.addr.do_send(message::Disconnect { id: act.id });
@@ -68,13 +176,43 @@ impl Handler<message::Message> for WSSession {
     type Result = ();
 
     fn handle(&mut self, msg: message::Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        match msg {
+            message::Message::Delivery(delivery)
+                if delivery.payload.is_binary() && !self.binary =>
+            {
+                log::trace!(
+                    "Skipping binary message {} for a text only session",
+                    delivery.id
+                );
+            }
+            message::Message::Delivery(delivery) => self.reply(ctx, Reply::Delivery(delivery)),
+            message::Message::Event(event) => self.reply(ctx, Reply::Event(event)),
+            message::Message::GoingAway(reconnect_after) => {
+                let description = format!(
+                    r#"{{"reconnect_after_ms":{}}}"#,
+                    reconnect_after.as_millis()
+                );
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Away,
+                    description: Some(description),
+                }));
+                ctx.stop();
+            }
+        }
     }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WSSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         let msg = match msg {
+            Err(ws::ProtocolError::Overflow) => {
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Size,
+                    description: Some("Frame is too large".to_owned()),
+                }));
+                ctx.stop();
+                return;
+            }
             Err(_) => {
                 ctx.stop();
                 return;
@@ -83,61 +221,95 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WSSession {
         };
 
         log::trace!("Websocket message: {:?}", msg);
+        // Anything from the client shows it's still there, not only pongs
+        self.heartbeat = Instant::now();
 
-        match msg {
+        let command = match msg {
             ws::Message::Ping(msg) => {
-                self.heartbeat = Instant::now();
                 ctx.pong(&msg);
-            }
-            ws::Message::Pong(_) => {
-                self.heartbeat = Instant::now();
+                return;
             }
             ws::Message::Text(text) => {
-                let m = text.trim();
-                log::debug!("Text message from websocket: {}", m);
-
-                // We only care about messages that start with a slash
-                if !m.starts_with('/') {
-                    return;
-                }
-
-                let args: Vec<&str> = m.splitn(2, ' ').collect();
-                match args[..] {
-                    ["/subscribe", sub_args] => {
-                        let sub_args: Vec<&str> = sub_args.split(' ').collect();
-                        if let [topic, token] = sub_args[..] {
-                            self.addr.do_send(message::SubscribeToTopic {
-                                id: self.id,
-                                topic: topic.to_string(),
-                                token: token.to_string(),
-                            });
-                        } else {
-                            ctx.text("Invalid subscribe command, it should be: /subscribe <topic> <token>");
-                        }
-                    }
-                    ["/unsubscribe", topic] => {
-                        self.addr.do_send(message::UnsubscribeFromTopic {
-                            id: self.id,
-                            topic: topic.to_string(),
-                        });
-                    }
-                    ["/unsubscribe-all"] => {
-                        self.addr.do_send(message::UnsubscribeAll { id: self.id });
-                    }
-                    _ => {
-                        ctx.text("Unknown command");
-                    }
-                }
+                log::debug!("Text message from websocket: {}", text.trim());
+                self.codec.decode_text(&text)
             }
+            ws::Message::Binary(bytes) => self.codec.decode_binary(&bytes),
             ws::Message::Close(reason) => {
                 ctx.close(reason);
                 ctx.stop();
+                return;
             }
             ws::Message::Continuation(_) => {
                 ctx.stop();
+                return;
+            }
+            ws::Message::Pong(_) | ws::Message::Nop => return,
+        };
+
+        let Some(command) = command else {
+            return;
+        };
+        // Pings are answered even when rate limited, so a busy client isn't disconnected
+        if !matches!(command, Ok(Command::Ping { .. })) {
+            if let Err(limited) = self.connection.check_rate() {
+                self.error(ctx, ErrorEvent::rate_limited(&limited));
+                return;
             }
-            ws::Message::Binary(_) => (),
-            ws::Message::Nop => (),
+        }
+        match command {
+            Ok(command) => self.execute(command, ctx),
+            Err(error) => self.error(ctx, error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::test_utils::*;
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite;
+
+    #[actix::test]
+    async fn test_heartbeat_negotiated() {
+        let (addr, _) = serve(config());
+
+        let (mut stream, response) = tokio_tungstenite::connect_async(format!(
+            "ws://{}/notiflux/ws?heartbeat=60&timeout=3600",
+            addr
+        ))
+        .await
+        .unwrap();
+        let header = |name: &str| response.headers()[name].to_str().unwrap().to_owned();
+        assert_eq!(header("x-notiflux-heartbeat-interval"), "60");
+        assert_eq!(header("x-notiflux-client-timeout"), "300");
+
+        send(&mut stream, "/ping 42").await;
+        assert_eq!(
+            next(&mut stream).await,
+            tungstenite::Message::text("/pong 42")
+        );
+    }
+
+    #[actix::test]
+    async fn test_validation() {
+        let mut config = config();
+        config.limits.max_frame_size = Some(128);
+        let (addr, _) = serve(config);
+
+        let mut stream = connect(addr, "").await;
+        send(&mut stream, "/subscribe foo\u{7}bar token").await;
+        assert_eq!(
+            next(&mut stream).await,
+            tungstenite::Message::text("Topic can't contain '\\u{7}'")
+        );
+
+        send(&mut stream, &"x".repeat(129)).await;
+        match stream.next().await {
+            Some(Ok(tungstenite::Message::Close(Some(frame)))) => assert_eq!(
+                frame.code,
+                tungstenite::protocol::frame::coding::CloseCode::Size
+            ),
+            message => panic!("Expected a close frame, got {:?}", message),
         }
     }
 }
@@ -0,0 +1,521 @@
+//! How commands are read from the frames of a session, and replies written to them. The text
+//! protocol has slash commands, while the structured protocol has the same commands as objects
+//! tagged with `cmd`, in JSON, MessagePack or CBOR as chosen by subprotocol.
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::message::{Delivery, Encoding, ErrorEvent, Event, Payload, SubscribeOptions};
+
+const JSON_PROTOCOL: &str = "notiflux.json";
+const MSGPACK_PROTOCOL: &str = "notiflux.msgpack";
+const CBOR_PROTOCOL: &str = "notiflux.cbor";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    /// Slash commands in text frames, with messages delivered as is, or in a JSON envelope
+    Text { envelope: bool },
+    /// The structured protocol in text frames
+    Json,
+    /// The structured protocol in binary frames
+    MessagePack,
+    /// The structured protocol in binary frames
+    Cbor,
+}
+
+/// A command from a client, whichever codec it was read with
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Command {
+    Ping {
+        payload: Option<String>,
+    },
+    Subscribe {
+        topic: String,
+        token: String,
+        #[serde(default)]
+        options: SubscribeOptions,
+    },
+    Publish {
+        topic: String,
+        token: String,
+        message: Payload,
+        /// Leave this session out of the fan-out
+        #[serde(default)]
+        exclude: bool,
+    },
+    Unsubscribe {
+        topic: String,
+    },
+    UnsubscribeAll,
+    Presence {
+        topic: String,
+    },
+}
+
+/// Anything sent to a client
+#[derive(Debug)]
+pub enum Reply {
+    Pong(Option<String>),
+    Delivery(Delivery),
+    Event(Event),
+}
+
+/// Replies of the structured protocol that aren't events of their own, tagged the same way
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Frame {
+    Pong {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<String>,
+    },
+    Message {
+        topic: Option<String>,
+        id: String,
+        timestamp: u64,
+        payload: Payload,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        encoding: Option<Encoding>,
+    },
+}
+
+impl Codec {
+    /// The codec of the first subprotocol the client offered that is supported, or the text
+    /// protocol when there's none
+    pub fn negotiate(protocols: Option<&str>, envelope: bool) -> Codec {
+        protocols
+            .into_iter()
+            .flat_map(|protocols| protocols.split(','))
+            .find_map(|protocol| match protocol.trim() {
+                JSON_PROTOCOL => Some(Codec::Json),
+                MSGPACK_PROTOCOL => Some(Codec::MessagePack),
+                CBOR_PROTOCOL => Some(Codec::Cbor),
+                _ => None,
+            })
+            .unwrap_or(Codec::Text { envelope })
+    }
+
+    /// The subprotocol to accept in the handshake, none for the text protocol
+    pub fn protocols(&self) -> &'static [&'static str] {
+        match self {
+            Codec::Text { .. } => &[],
+            Codec::Json => &[JSON_PROTOCOL],
+            Codec::MessagePack => &[MSGPACK_PROTOCOL],
+            Codec::Cbor => &[CBOR_PROTOCOL],
+        }
+    }
+
+    /// Whether it uses binary frames, which carry binary messages as they are
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Codec::MessagePack | Codec::Cbor)
+    }
+
+    /// Read a command from a text frame, or nothing if the frame isn't a command
+    pub fn decode_text(&self, text: &str) -> Option<Result<Command, ErrorEvent>> {
+        match self {
+            Codec::Text { .. } => parse_command(text.trim()),
+            Codec::Json => Some(serde_json::from_str(text).map_err(invalid)),
+            Codec::MessagePack | Codec::Cbor => None,
+        }
+    }
+
+    /// Read a command from a binary frame, or nothing if the frame isn't a command
+    pub fn decode_binary(&self, bytes: &[u8]) -> Option<Result<Command, ErrorEvent>> {
+        match self {
+            Codec::MessagePack => Some(rmp_serde::from_slice(bytes).map_err(invalid)),
+            Codec::Cbor => Some(ciborium::from_reader(bytes).map_err(invalid)),
+            Codec::Text { .. } | Codec::Json => None,
+        }
+    }
+
+    /// Write a reply as the payload of a text or binary frame
+    pub fn encode(&self, reply: Reply) -> Result<Payload, String> {
+        match (self, reply) {
+            (Codec::Text { .. }, Reply::Pong(None)) => Ok(Payload::from("/pong")),
+            (Codec::Text { .. }, Reply::Pong(Some(payload))) => {
+                Ok(Payload::Text(format!("/pong {}", payload)))
+            }
+            (Codec::Text { envelope: true }, Reply::Delivery(delivery)) => {
+                serde_json::to_string(&delivery)
+                    .map(Payload::Text)
+                    .map_err(|e| e.to_string())
+            }
+            (Codec::Text { envelope: false }, Reply::Delivery(delivery)) => Ok(delivery.payload),
+            (Codec::Text { .. }, Reply::Event(event)) => Ok(Payload::Text(event.text())),
+            (codec, Reply::Pong(payload)) => codec.serialize(&Frame::Pong { payload }),
+            (codec, Reply::Delivery(delivery)) => codec.serialize(&codec.message(delivery)),
+            (codec, Reply::Event(event)) => codec.serialize(&event),
+        }
+    }
+
+    /// A delivery in the structured protocol, with binary payloads in base64 for JSON
+    fn message(&self, delivery: Delivery) -> Frame {
+        let (payload, encoding) = match delivery.payload {
+            Payload::Binary(bytes) if *self == Codec::Json => (
+                Payload::Text(BASE64_STANDARD.encode(bytes)),
+                Some(Encoding::Base64),
+            ),
+            payload => (payload, None),
+        };
+
+        Frame::Message {
+            topic: delivery.topic,
+            id: delivery.id.to_string(),
+            timestamp: delivery.timestamp,
+            payload,
+            encoding,
+        }
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Payload, String> {
+        match self {
+            Codec::MessagePack => rmp_serde::to_vec_named(value)
+                .map(Payload::Binary)
+                .map_err(|e| e.to_string()),
+            Codec::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(|e| e.to_string())?;
+                Ok(Payload::Binary(bytes))
+            }
+            Codec::Text { .. } | Codec::Json => serde_json::to_string(value)
+                .map(Payload::Text)
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+fn invalid(error: impl std::fmt::Display) -> ErrorEvent {
+    ErrorEvent::new("invalid", format!("Invalid command: {}", error))
+}
+
+/// Parse a slash command of the text protocol, anything else isn't a command
+fn parse_command(text: &str) -> Option<Result<Command, ErrorEvent>> {
+    if !text.starts_with('/') {
+        return None;
+    }
+
+    let command = match text.split_once(' ') {
+        None if text == "/ping" => Ok(Command::Ping { payload: None }),
+        None if text == "/unsubscribe-all" => Ok(Command::UnsubscribeAll),
+        Some(("/ping", payload)) => Ok(Command::Ping {
+            payload: Some(payload.to_owned()),
+        }),
+        Some(("/subscribe", args)) => parse_subscribe(args),
+        Some((cmd @ ("/publish" | "/publish-others"), args)) => {
+            match args.splitn(3, ' ').collect::<Vec<_>>()[..] {
+                [topic, token, message] => Ok(Command::Publish {
+                    topic: topic.to_owned(),
+                    token: token.to_owned(),
+                    message: Payload::from(message),
+                    exclude: cmd == "/publish-others",
+                }),
+                _ => Err(ErrorEvent::new(
+                    "invalid",
+                    format!(
+                        "Invalid publish command, it should be: {} <topic> <token> <message>",
+                        cmd
+                    ),
+                )),
+            }
+        }
+        Some(("/unsubscribe", topic)) => Ok(Command::Unsubscribe {
+            topic: topic.to_owned(),
+        }),
+        Some(("/presence", topic)) => Ok(Command::Presence {
+            topic: topic.to_owned(),
+        }),
+        _ => Err(ErrorEvent::new("invalid", "Unknown command")),
+    };
+    Some(command)
+}
+
+fn parse_subscribe(args: &str) -> Result<Command, ErrorEvent> {
+    let (topic, token, options) = match args.splitn(3, ' ').collect::<Vec<_>>()[..] {
+        [topic, token] => (topic, token, None),
+        [topic, token, options] => (topic, token, Some(options)),
+        _ => {
+            return Err(ErrorEvent::new(
+                "invalid",
+                "Invalid subscribe command, it should be: /subscribe <topic> <token> [options]",
+            ))
+        }
+    };
+    let options = options
+        .map(serde_json::from_str)
+        .transpose()
+        .map_err(|e| ErrorEvent::new("invalid", format!("Invalid subscribe options: {}", e)))?;
+
+    Ok(Command::Subscribe {
+        topic: topic.to_owned(),
+        token: token.to_owned(),
+        options: options.unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::test_utils::sign_token;
+    use crate::builder::test_utils::*;
+    use crate::message::PresenceEvent;
+    use futures_util::SinkExt;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Codec::negotiate(None, true), Codec::Text { envelope: true });
+        assert_eq!(
+            Codec::negotiate(Some("graphql-ws, notiflux.cbor, notiflux.json"), false),
+            Codec::Cbor
+        );
+        assert_eq!(
+            Codec::negotiate(Some("graphql-ws"), false),
+            Codec::Text { envelope: false }
+        );
+    }
+
+    #[test]
+    fn test_parse_text_commands() {
+        let text = Codec::Text { envelope: false };
+        let parse = |command: &str| text.decode_text(command).unwrap();
+
+        assert!(text.decode_text("hello").is_none());
+        assert_eq!(
+            parse("/ping 42").unwrap(),
+            Command::Ping {
+                payload: Some("42".to_owned())
+            }
+        );
+        assert_eq!(
+            parse("/publish-others foo token hello world").unwrap(),
+            Command::Publish {
+                topic: "foo".to_owned(),
+                token: "token".to_owned(),
+                message: Payload::from("hello world"),
+                exclude: true,
+            }
+        );
+        assert_eq!(
+            parse(" /unsubscribe-all ").unwrap(),
+            Command::UnsubscribeAll
+        );
+        assert_eq!(
+            parse("/subscribe foo").unwrap_err().message,
+            "Invalid subscribe command, it should be: /subscribe <topic> <token> [options]"
+        );
+        assert_eq!(parse("/subscribe").unwrap_err().message, "Unknown command");
+        // Binary frames aren't commands in the text protocol
+        assert!(text.decode_binary(b"/ping").is_none());
+    }
+
+    #[test]
+    fn test_structured_commands() {
+        let json =
+            r#"{"cmd": "subscribe", "topic": "foo", "token": "token", "options": {"meta": 1}}"#;
+        let Command::Subscribe { topic, options, .. } =
+            Codec::Json.decode_text(json).unwrap().unwrap()
+        else {
+            panic!("Expected a subscribe command");
+        };
+        assert_eq!(topic, "foo");
+        assert_eq!(options.meta, Some(serde_json::json!(1)));
+
+        #[derive(Serialize)]
+        struct Publish<'a> {
+            cmd: &'a str,
+            topic: &'a str,
+            token: &'a str,
+            message: Payload,
+        }
+        let publish = Publish {
+            cmd: "publish",
+            topic: "foo",
+            token: "token",
+            message: Payload::Binary(vec![0, 159]),
+        };
+        let expected = Command::Publish {
+            topic: "foo".to_owned(),
+            token: "token".to_owned(),
+            message: Payload::Binary(vec![0, 159]),
+            exclude: false,
+        };
+
+        let bytes = rmp_serde::to_vec_named(&publish).unwrap();
+        assert_eq!(
+            Codec::MessagePack.decode_binary(&bytes).unwrap().unwrap(),
+            expected
+        );
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&publish, &mut bytes).unwrap();
+        assert_eq!(
+            Codec::Cbor.decode_binary(&bytes).unwrap().unwrap(),
+            expected
+        );
+
+        let error = Codec::MessagePack
+            .decode_binary(b"\x81\xa3cmd\xa4nope")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.error, "invalid");
+        assert!(error.message.starts_with("Invalid command: "));
+    }
+
+    #[test]
+    fn test_encode_replies() {
+        let delivery = Delivery::new(Some("foo"), Payload::Binary(vec![0, 159]));
+
+        #[derive(Deserialize)]
+        struct Message {
+            event: String,
+            topic: String,
+            payload: Payload,
+        }
+        let Payload::Binary(bytes) = Codec::MessagePack
+            .encode(Reply::Delivery(delivery.clone()))
+            .unwrap()
+        else {
+            panic!("Expected a binary frame");
+        };
+        let message: Message = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(message.event, "message");
+        assert_eq!(message.topic, "foo");
+        assert_eq!(message.payload, Payload::Binary(vec![0, 159]));
+
+        let Payload::Text(text) = Codec::Json.encode(Reply::Delivery(delivery)).unwrap() else {
+            panic!("Expected a text frame");
+        };
+        let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(message["payload"], "AJ8=");
+        assert_eq!(message["encoding"], "base64");
+
+        let event = Event::from(PresenceEvent::Presence {
+            topic: "foo".to_owned(),
+            members: Vec::new(),
+        });
+        let Payload::Binary(bytes) = Codec::Cbor.encode(Reply::Event(event)).unwrap() else {
+            panic!("Expected a binary frame");
+        };
+        let event: serde_json::Value = ciborium::from_reader(&bytes[..]).unwrap();
+        assert_eq!(event["event"], "presence");
+        assert_eq!(event["topic"], "foo");
+
+        let error = Event::from(ErrorEvent::new("invalid", "Unknown command"));
+        assert_eq!(
+            Codec::Text { envelope: true }.encode(Reply::Event(error.clone())),
+            Ok(Payload::from("Unknown command"))
+        );
+        let Payload::Text(text) = Codec::Json.encode(Reply::Event(error)).unwrap() else {
+            panic!("Expected a text frame");
+        };
+        assert_eq!(
+            text,
+            r#"{"event":"error","error":"invalid","message":"Unknown command"}"#
+        );
+        assert_eq!(
+            Codec::Text { envelope: false }.encode(Reply::Pong(Some("42".to_owned()))),
+            Ok(Payload::from("/pong 42"))
+        );
+    }
+
+    #[test]
+    fn test_binary_delivery_as_bytes() {
+        let delivery = Delivery::new(Some("foo"), Payload::Binary(vec![0, 159]));
+
+        let Payload::Binary(bytes) = Codec::Cbor
+            .encode(Reply::Delivery(delivery.clone()))
+            .unwrap()
+        else {
+            panic!("Expected a binary frame");
+        };
+        let message: ciborium::Value = ciborium::from_reader(&bytes[..]).unwrap();
+        let payload = message
+            .as_map()
+            .unwrap()
+            .iter()
+            .find(|(key, _)| key.as_text() == Some("payload"))
+            .map(|(_, value)| value);
+        assert_eq!(payload, Some(&ciborium::Value::Bytes(vec![0, 159])));
+
+        // A MessagePack bin 8 of two bytes, rather than an array of numbers
+        let Payload::Binary(bytes) = Codec::MessagePack
+            .encode(Reply::Delivery(delivery))
+            .unwrap()
+        else {
+            panic!("Expected a binary frame");
+        };
+        assert!(bytes.windows(4).any(|w| w == [0xc4, 2, 0, 159]));
+    }
+
+    #[actix::test]
+    async fn test_msgpack_subprotocol() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let (addr, handle) = serve(config());
+
+        let mut request = format!("ws://{}/notiflux/ws", addr)
+            .into_client_request()
+            .unwrap();
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            "graphql-ws, notiflux.msgpack".parse().unwrap(),
+        );
+        let (mut stream, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(
+            response.headers()["Sec-WebSocket-Protocol"],
+            "notiflux.msgpack"
+        );
+
+        #[derive(Serialize)]
+        struct Subscribe {
+            cmd: &'static str,
+            topic: &'static str,
+            token: String,
+        }
+        // Unlike the text command, the topic can have whitespace
+        let subscribe = Subscribe {
+            cmd: "subscribe",
+            topic: "foo bar",
+            token: sign_token("subscribe", &["foo bar"]),
+        };
+        stream
+            .send(tungstenite::Message::binary(
+                rmp_serde::to_vec_named(&subscribe).unwrap(),
+            ))
+            .await
+            .unwrap();
+        actix::clock::sleep(Duration::from_millis(50)).await;
+
+        handle.publish_binary("foo bar", &[0, 159]);
+        handle.publish("foo bar", "hello");
+
+        #[derive(Deserialize)]
+        struct Frame {
+            event: String,
+            topic: Option<String>,
+            payload: Payload,
+        }
+        let mut frames = Vec::new();
+        for _ in 0..2 {
+            let tungstenite::Message::Binary(bytes) = next(&mut stream).await else {
+                panic!("Expected a binary frame");
+            };
+            frames.push(rmp_serde::from_slice::<Frame>(&bytes).unwrap());
+        }
+        assert_eq!(frames[0].event, "message");
+        assert_eq!(frames[0].topic.as_deref(), Some("foo bar"));
+        assert_eq!(frames[0].payload, Payload::Binary(vec![0, 159]));
+        assert_eq!(frames[1].payload, Payload::from("hello"));
+
+        // Errors are replies like any other, in the same encoding
+        stream
+            .send(tungstenite::Message::binary(vec![0xc1]))
+            .await
+            .unwrap();
+        let tungstenite::Message::Binary(bytes) = next(&mut stream).await else {
+            panic!("Expected a binary frame");
+        };
+        let error: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(error["event"], "error");
+        assert_eq!(error["error"], "invalid");
+    }
+}
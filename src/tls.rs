@@ -0,0 +1,365 @@
+//! TLS termination with rustls. The certificate is looked up on every handshake, so that it can
+//! be swapped for a renewed one without restarting, which leaves established connections alone.
+//!
+//! Clients can optionally present a certificate signed by a configured CA. Its subject
+//! alternative names are attached to the connection as a [`ClientIdentity`], for the handlers
+//! to authorise with.
+use actix::prelude::*;
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::RootCertStore;
+use std::any::Any;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::config::TlsConfig;
+use crate::{NotifluxError, NotifluxErrorType};
+
+/// How often the certificate files are checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Serves the current certificate, which can be reloaded from disk
+#[derive(Debug)]
+pub struct CertResolver {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn new(config: TlsConfig) -> Result<CertResolver, NotifluxError> {
+        let provider = Arc::new(ring::default_provider());
+        let key = load_certified_key(&config, &provider)?;
+
+        Ok(CertResolver {
+            config,
+            provider,
+            key: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    /// Load the certificate files again, keeping the current certificate if they're invalid
+    pub fn reload(&self) -> Result<(), NotifluxError> {
+        let key = load_certified_key(&self.config, &self.provider)?;
+        *self.key.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+
+    /// The last time either of the certificate files changed
+    fn modified(&self) -> Option<SystemTime> {
+        [&self.config.cert_path, &self.config.key_path]
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .max()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(
+    config: &TlsConfig,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, NotifluxError> {
+    let certs = CertificateDer::pem_file_iter(&config.cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| tls_error(&config.cert_path, e))?;
+    if certs.is_empty() {
+        return Err(tls_error(&config.cert_path, "no certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key_path)
+        .map_err(|e| tls_error(&config.key_path, e))?;
+    let key = provider
+        .key_provider
+        .load_private_key(key)
+        .map_err(|e| tls_error(&config.key_path, e))?;
+
+    // Renewals can be caught halfway, with only one of the files replaced
+    let certified_key = CertifiedKey::new(certs, key);
+    certified_key
+        .keys_match()
+        .map_err(|e| tls_error(&config.key_path, e))?;
+
+    Ok(certified_key)
+}
+
+fn tls_error(path: &Path, error: impl std::fmt::Display) -> NotifluxError {
+    NotifluxError {
+        message: Some(format!("Unable to load {}: {}", path.display(), error)),
+        error_type: NotifluxErrorType::ConfigError,
+    }
+}
+
+fn config_error(error: impl std::fmt::Display) -> NotifluxError {
+    NotifluxError {
+        message: Some(format!("Unable to configure TLS: {}", error)),
+        error_type: NotifluxErrorType::ConfigError,
+    }
+}
+
+/// Build the rustls config that `HttpServer::bind_rustls_0_23` serves with
+pub fn server_config(resolver: Arc<CertResolver>) -> Result<rustls::ServerConfig, NotifluxError> {
+    let builder = rustls::ServerConfig::builder_with_provider(resolver.provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(config_error)?;
+
+    let builder = match &resolver.config.client_auth {
+        Some(client_auth) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(&client_auth.ca_path)
+                .map_err(|e| tls_error(&client_auth.ca_path, e))?
+            {
+                let cert = cert.map_err(|e| tls_error(&client_auth.ca_path, e))?;
+                roots
+                    .add(cert)
+                    .map_err(|e| tls_error(&client_auth.ca_path, e))?;
+            }
+            // Clients without a certificate are still let in, to authenticate with tokens
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(roots),
+                resolver.provider.clone(),
+            )
+            .allow_unauthenticated()
+            .build()
+            .map_err(config_error)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(builder.with_cert_resolver(resolver))
+}
+
+/// The subject alternative names of the verified client certificate of a connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIdentity {
+    pub names: Vec<String>,
+}
+
+/// Attach the [`ClientIdentity`] to connections made with a client certificate, which handlers
+/// get through `HttpRequest::conn_data`
+pub fn on_connect(conn: &dyn Any, extensions: &mut Extensions) {
+    let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    let (_, session) = stream.get_ref();
+    if let Some(cert) = session.peer_certificates().and_then(|certs| certs.first()) {
+        extensions.insert(ClientIdentity {
+            names: subject_alt_names(cert),
+        });
+    }
+}
+
+/// DNS names, URIs, emails and IP addresses from the subject alternative names extension
+fn subject_alt_names(cert: &CertificateDer) -> Vec<String> {
+    use x509_parser::extensions::GeneralName;
+
+    let Ok((_, cert)) = x509_parser::parse_x509_certificate(cert) else {
+        return Vec::new();
+    };
+    let Ok(Some(san)) = cert.subject_alternative_name() else {
+        return Vec::new();
+    };
+
+    san.value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(name) | GeneralName::URI(name) | GeneralName::RFC822Name(name) => {
+                Some(name.to_string())
+            }
+            GeneralName::IPAddress(bytes) => match bytes.len() {
+                4 => <[u8; 4]>::try_from(*bytes)
+                    .ok()
+                    .map(|ip| IpAddr::from(ip).to_string()),
+                16 => <[u8; 16]>::try_from(*bytes)
+                    .ok()
+                    .map(|ip| IpAddr::from(ip).to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Reload;
+
+/// Reloads the certificate when its files change or the process receives SIGHUP
+pub struct CertReloader {
+    resolver: Arc<CertResolver>,
+    modified: Option<SystemTime>,
+}
+
+impl CertReloader {
+    pub fn new(resolver: Arc<CertResolver>) -> CertReloader {
+        CertReloader {
+            modified: resolver.modified(),
+            resolver,
+        }
+    }
+
+    fn reload(&mut self) {
+        self.modified = self.resolver.modified();
+        match self.resolver.reload() {
+            Ok(()) => log::info!("Reloaded TLS certificate"),
+            Err(e) => log::error!("Keeping the current TLS certificate: {}", e),
+        }
+    }
+}
+
+impl Actor for CertReloader {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(CHECK_INTERVAL, |act, _| {
+            if act.resolver.modified() != act.modified {
+                act.reload();
+            }
+        });
+
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            match signal(SignalKind::hangup()) {
+                Ok(mut hangup) => {
+                    let addr = ctx.address();
+                    actix::spawn(async move {
+                        while hangup.recv().await.is_some() {
+                            addr.do_send(Reload);
+                        }
+                    });
+                }
+                Err(e) => log::error!("Unable to listen for SIGHUP: {}", e),
+            }
+        }
+    }
+}
+
+impl Handler<Reload> for CertReloader {
+    type Result = ();
+
+    fn handle(&mut self, _: Reload, _: &mut Context<Self>) {
+        self.reload();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClientAuthConfig;
+    use std::path::PathBuf;
+
+    const CERT: &[u8] = include_bytes!("../scripts/tls/server.pem");
+    const KEY: &[u8] = include_bytes!("../scripts/tls/server-key.pem");
+    const RENEWED_CERT: &[u8] = include_bytes!("../scripts/tls/server-renewed.pem");
+    const RENEWED_KEY: &[u8] = include_bytes!("../scripts/tls/server-renewed-key.pem");
+
+    fn write_files(dir: &Path, cert: &[u8], key: &[u8]) -> TlsConfig {
+        std::fs::write(dir.join("cert.pem"), cert).unwrap();
+        std::fs::write(dir.join("key.pem"), key).unwrap();
+        TlsConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+            client_auth: None,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("notiflux-{}-{}", name, ulid::Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn current_cert(resolver: &CertResolver) -> Vec<u8> {
+        resolver.key.read().unwrap().cert[0].to_vec()
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = temp_dir("reload");
+        let config = write_files(&dir, CERT, KEY);
+        let resolver = CertResolver::new(config).unwrap();
+        let original = current_cert(&resolver);
+
+        write_files(&dir, RENEWED_CERT, RENEWED_KEY);
+        resolver.reload().unwrap();
+        let renewed = current_cert(&resolver);
+        assert_ne!(renewed, original);
+
+        // A broken file is reported, and the renewed certificate is kept
+        std::fs::write(dir.join("key.pem"), "garbage").unwrap();
+        let err = resolver.reload().unwrap_err();
+        assert_eq!(err.error_type, NotifluxErrorType::ConfigError);
+        assert_eq!(current_cert(&resolver), renewed);
+
+        // So is a key that doesn't belong to the certificate
+        std::fs::write(dir.join("key.pem"), KEY).unwrap();
+        assert!(resolver.reload().is_err());
+        assert_eq!(current_cert(&resolver), renewed);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let err = CertResolver::new(TlsConfig {
+            cert_path: PathBuf::from("/nonexistent/cert.pem"),
+            key_path: PathBuf::from("/nonexistent/key.pem"),
+            client_auth: None,
+        })
+        .unwrap_err();
+
+        assert_eq!(err.error_type, NotifluxErrorType::ConfigError);
+        assert!(err.message().contains("/nonexistent/cert.pem"));
+    }
+
+    #[test]
+    fn test_server_config() {
+        let dir = temp_dir("config");
+        let resolver = Arc::new(CertResolver::new(write_files(&dir, CERT, KEY)).unwrap());
+
+        assert!(server_config(resolver).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_subject_alt_names() {
+        let cert =
+            CertificateDer::from_pem_slice(include_bytes!("../scripts/tls/client.pem")).unwrap();
+
+        assert_eq!(
+            subject_alt_names(&cert),
+            vec!["ci.internal", "spiffe://mesh/ns/builds/sa/ci"]
+        );
+    }
+
+    #[test]
+    fn test_server_config_with_client_auth() {
+        let dir = temp_dir("client-auth");
+        let mut config = write_files(&dir, CERT, KEY);
+        config.client_auth = Some(ClientAuthConfig {
+            ca_path: PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/scripts/tls/ca.pem")),
+            topics_path: PathBuf::new(),
+        });
+        let resolver = Arc::new(CertResolver::new(config).unwrap());
+
+        assert!(server_config(resolver).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
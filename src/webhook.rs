@@ -0,0 +1,470 @@
+//! Webhooks that tell a backend when topics gain their first subscriber or lose their last one,
+//! and optionally when sessions connect and disconnect.
+//!
+//! Events are posted one at a time, in the order they happened, so that a backend never sees a
+//! topic vacated before it was occupied. Failed deliveries are retried with an exponential
+//! backoff, and every request is signed with an HMAC of the body when a secret is configured.
+//!
+//! Events wait in a bounded queue while the backend is slow or down. Once it's full, topic events
+//! take the place of queued session events, which are dropped, and are otherwise kept aside with
+//! at most one per topic: a topic occupied and then vacated again leaves the backend where it
+//! was, so the two cancel out. Topic events are never dropped, so the backend always ends up
+//! knowing which topics are occupied.
+use actix::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::WebhookConfig;
+
+pub const SIGNATURE_HEADER: &str = "X-Notiflux-Signature";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_QUEUED_EVENTS: usize = 10_000;
+
+#[derive(Message, Debug, Clone, Serialize)]
+#[rtype(result = "()")]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The first session subscribed to the topic
+    TopicOccupied {
+        topic: String,
+    },
+    /// The last session left the topic
+    TopicVacated {
+        topic: String,
+    },
+    SessionConnected {
+        session: String,
+    },
+    SessionDisconnected {
+        session: String,
+        sub: Option<String>,
+    },
+}
+
+impl Event {
+    fn is_session_event(&self) -> bool {
+        matches!(
+            self,
+            Event::SessionConnected { .. } | Event::SessionDisconnected { .. }
+        )
+    }
+
+    fn topic(&self) -> Option<&str> {
+        match self {
+            Event::TopicOccupied { topic } | Event::TopicVacated { topic } => Some(topic),
+            Event::SessionConnected { .. } | Event::SessionDisconnected { .. } => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    /// Milliseconds since the epoch when the event happened
+    timestamp: u64,
+}
+
+pub struct Webhook {
+    config: WebhookConfig,
+    client: reqwest::Client,
+    initial_backoff: Duration,
+    /// Events waiting for the one being delivered, with when they happened
+    queue: VecDeque<(Event, u64)>,
+    max_queued: usize,
+    /// Topic events that didn't fit in the queue, which follow every queued event for their topic
+    overflow: BTreeMap<String, (Event, u64)>,
+    delivering: bool,
+    /// Session events dropped since the queue last had room
+    dropped: usize,
+}
+
+impl Webhook {
+    pub fn new(config: WebhookConfig) -> Webhook {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Unable to build webhook HTTP client");
+
+        Webhook {
+            config,
+            client,
+            initial_backoff: INITIAL_BACKOFF,
+            queue: VecDeque::new(),
+            max_queued: MAX_QUEUED_EVENTS,
+            overflow: BTreeMap::new(),
+            delivering: false,
+            dropped: 0,
+        }
+    }
+
+    fn enqueue(&mut self, event: Event, timestamp: u64) {
+        // Nothing jumps ahead of the overflow, which drains into the queue whenever it has room
+        self.fill_queue();
+        if self.queue.len() < self.max_queued && self.overflow.is_empty() {
+            if self.dropped > 0 {
+                log::warn!(
+                    "Webhook queue has room again, {} session events dropped",
+                    self.dropped
+                );
+                self.dropped = 0;
+            }
+            self.queue.push_back((event, timestamp));
+            return;
+        }
+
+        let Some(topic) = event.topic().map(str::to_owned) else {
+            self.drop_session_event();
+            return;
+        };
+        // Topic events alternate, so the last one waiting for the topic is the opposite
+        if self.overflow.remove(&topic).is_some() {
+            return;
+        }
+        if let Some(index) = self
+            .queue
+            .iter()
+            .rposition(|(queued, _)| queued.topic() == Some(topic.as_str()))
+        {
+            self.queue.remove(index);
+            return;
+        }
+        if let Some(index) = self
+            .queue
+            .iter()
+            .position(|(queued, _)| queued.is_session_event())
+        {
+            self.queue.remove(index);
+            self.drop_session_event();
+            if self.overflow.is_empty() {
+                self.queue.push_back((event, timestamp));
+                return;
+            }
+            self.fill_queue();
+        }
+        self.overflow.insert(topic, (event, timestamp));
+    }
+
+    fn drop_session_event(&mut self) {
+        if self.dropped == 0 {
+            log::warn!(
+                "Webhook queue is full with {} events, dropping session events",
+                self.queue.len()
+            );
+        }
+        self.dropped += 1;
+    }
+
+    /// Move topic events from the overflow to the queue while it has room
+    fn fill_queue(&mut self) {
+        while self.queue.len() < self.max_queued {
+            let Some((_, queued)) = self.overflow.pop_first() else {
+                return;
+            };
+            self.queue.push_back(queued);
+        }
+    }
+
+    /// Deliver the next queued event, unless one is being delivered already
+    fn deliver_next(&mut self, ctx: &mut Context<Self>) {
+        if self.delivering {
+            return;
+        }
+        let Some((event, timestamp)) = self.queue.pop_front() else {
+            return;
+        };
+        self.fill_queue();
+
+        let body = match serde_json::to_string(&Payload {
+            event: &event,
+            timestamp,
+        }) {
+            Ok(body) => body,
+            Err(e) => {
+                log::error!("Unable to serialize webhook event: {}", e);
+                return self.deliver_next(ctx);
+            }
+        };
+        let signature = self.config.secret.as_deref().map(|s| sign(s, &body));
+
+        self.delivering = true;
+        ctx.spawn(
+            Webhook::deliver(
+                self.client.clone(),
+                self.config.url.clone(),
+                body,
+                signature,
+                self.config.max_attempts,
+                self.initial_backoff,
+            )
+            .into_actor(self)
+            .map(|_, act, ctx| {
+                act.delivering = false;
+                act.deliver_next(ctx);
+            }),
+        );
+    }
+
+    /// Post the body, retrying until it's accepted or the attempts run out
+    async fn deliver(
+        client: reqwest::Client,
+        url: String,
+        body: String,
+        signature: Option<String>,
+        max_attempts: u32,
+        mut backoff: Duration,
+    ) {
+        for attempt in 1..=max_attempts {
+            let mut request = client
+                .post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            match request.send().await {
+                Ok(response) if response.status().is_success() => return,
+                Ok(response) => log::warn!(
+                    "Webhook returned {} (attempt {}/{})",
+                    response.status(),
+                    attempt,
+                    max_attempts
+                ),
+                Err(e) => log::warn!(
+                    "Unable to call webhook: {} (attempt {}/{})",
+                    e,
+                    attempt,
+                    max_attempts
+                ),
+            }
+
+            if attempt < max_attempts {
+                actix::clock::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        log::error!("Giving up on webhook event: {}", body);
+    }
+}
+
+impl Actor for Webhook {
+    type Context = Context<Self>;
+}
+
+impl Handler<Event> for Webhook {
+    type Result = ();
+
+    fn handle(&mut self, event: Event, ctx: &mut Context<Self>) {
+        if event.is_session_event() && !self.config.session_events {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.enqueue(event, timestamp);
+        self.deliver_next(ctx);
+    }
+}
+
+/// The value of the signature header, `sha256=` followed by the hex encoded HMAC of the body
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Received {
+        /// Number of requests to fail before accepting them
+        failures: AtomicUsize,
+        requests: Mutex<Vec<(Option<String>, String)>>,
+    }
+
+    async fn record(
+        req: HttpRequest,
+        body: String,
+        received: web::Data<Arc<Received>>,
+    ) -> HttpResponse {
+        let signature = req
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned);
+        received.requests.lock().unwrap().push((signature, body));
+
+        let failures = received.failures.load(Ordering::SeqCst);
+        if failures > 0 {
+            received.failures.store(failures - 1, Ordering::SeqCst);
+            return HttpResponse::InternalServerError().finish();
+        }
+        HttpResponse::Ok().finish()
+    }
+
+    fn start_receiver(received: Arc<Received>) -> String {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(received.clone()))
+                .route("/", web::post().to(record))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix::spawn(server.run());
+        format!("http://{}/", addr)
+    }
+
+    fn webhook(url: String, session_events: bool) -> Webhook {
+        let mut webhook = Webhook::new(WebhookConfig {
+            url,
+            secret: Some("secret".to_owned()),
+            session_events,
+            max_attempts: 3,
+        });
+        webhook.initial_backoff = Duration::from_millis(10);
+        webhook
+    }
+
+    fn start_webhook(url: String, session_events: bool) -> Addr<Webhook> {
+        webhook(url, session_events).start()
+    }
+
+    fn events(received: &Received) -> Vec<String> {
+        received
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, body)| {
+                let value: serde_json::Value = serde_json::from_str(body).unwrap();
+                format!("{} {}", value["event"], value["topic"])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("secret", "body"),
+            "sha256=dc46983557fea127b43af721467eb9b3fde2338fe3e14f51952aa8478c13d355"
+        );
+    }
+
+    #[actix::test]
+    async fn test_events_delivered_in_order_and_signed() {
+        let received = Arc::new(Received::default());
+        received.failures.store(2, Ordering::SeqCst);
+        let webhook = start_webhook(start_receiver(received.clone()), false);
+
+        webhook.do_send(Event::SessionConnected {
+            session: "session".to_owned(),
+        });
+        webhook.do_send(Event::TopicOccupied {
+            topic: "foo".to_owned(),
+        });
+        webhook.do_send(Event::TopicVacated {
+            topic: "foo".to_owned(),
+        });
+        actix::clock::sleep(Duration::from_millis(300)).await;
+
+        let events = events(&received);
+        // The first event is retried until the receiver accepts it, and the session event is
+        // skipped as session events aren't enabled
+        assert_eq!(
+            events,
+            vec![
+                r#""topic_occupied" "foo""#,
+                r#""topic_occupied" "foo""#,
+                r#""topic_occupied" "foo""#,
+                r#""topic_vacated" "foo""#,
+            ]
+        );
+        for (signature, body) in received.requests.lock().unwrap().iter() {
+            assert_eq!(signature.as_deref(), Some(sign("secret", body).as_str()));
+        }
+    }
+
+    #[actix::test]
+    async fn test_session_events() {
+        let received = Arc::new(Received::default());
+        let webhook = start_webhook(start_receiver(received.clone()), true);
+
+        webhook.do_send(Event::SessionDisconnected {
+            session: "session".to_owned(),
+            sub: Some("user".to_owned()),
+        });
+        actix::clock::sleep(Duration::from_millis(100)).await;
+
+        let requests = received.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let value: serde_json::Value = serde_json::from_str(&requests[0].1).unwrap();
+        assert_eq!(value["event"], "session_disconnected");
+        assert_eq!(value["session"], "session");
+        assert_eq!(value["sub"], "user");
+        assert!(value["timestamp"].as_u64().unwrap() > 0);
+    }
+
+    #[actix::test]
+    async fn test_queue_bounded() {
+        let received = Arc::new(Received::default());
+        // Keeps the first event in flight while the others are queued
+        received.failures.store(2, Ordering::SeqCst);
+        let mut webhook = webhook(start_receiver(received.clone()), true);
+        webhook.max_queued = 2;
+        let webhook = webhook.start();
+
+        let occupied = |topic: &str| Event::TopicOccupied {
+            topic: topic.to_owned(),
+        };
+        let vacated = |topic: &str| Event::TopicVacated {
+            topic: topic.to_owned(),
+        };
+        let connected = || Event::SessionConnected {
+            session: "session".to_owned(),
+        };
+        webhook.do_send(occupied("foo"));
+        webhook.do_send(connected());
+        webhook.do_send(occupied("bar"));
+        // The queue is full, so the session event makes room
+        webhook.do_send(occupied("baz"));
+        // Kept aside, as there's no session event left to drop
+        webhook.do_send(occupied("qux"));
+        // Cancels out the queued event for bar
+        webhook.do_send(vacated("bar"));
+        // Qux takes the room left by bar, and quux is kept aside in turn
+        webhook.do_send(occupied("quux"));
+        webhook.do_send(occupied("zed"));
+        // Cancels out the one kept aside for quux
+        webhook.do_send(vacated("quux"));
+        // Dropped, as the queue is full of topic events
+        webhook.do_send(connected());
+        actix::clock::sleep(Duration::from_millis(300)).await;
+
+        assert_eq!(
+            events(&received),
+            vec![
+                r#""topic_occupied" "foo""#,
+                r#""topic_occupied" "foo""#,
+                r#""topic_occupied" "foo""#,
+                r#""topic_occupied" "baz""#,
+                r#""topic_occupied" "qux""#,
+                r#""topic_occupied" "zed""#,
+            ]
+        );
+    }
+}